@@ -0,0 +1,66 @@
+/* the private key of a peer known only by its public key, like the ones imported from the
+ * [Peer] sections of other configurations, is NULL instead of an empty string.
+ * SQLite can't drop NOT NULL from a column, so the table is rebuilt */
+CREATE TABLE `new_peers` (
+  `vpn_name` TEXT NOT NULL,
+  `name` TEXT NOT NULL,
+  `index_in_vpn` INT,
+  `privkey` TEXT CHECK (`privkey` <> ''),
+  `pubkey` TEXT NOT NULL,
+  `address_v4` TEXT NOT NULL,
+  `address_v6` TEXT NOT NULL,
+  `endpoint` TEXT,
+  `dns` TEXT,
+  `status` TEXT NOT NULL,
+  PRIMARY KEY (`vpn_name`, `name`)
+  FOREIGN KEY (`vpn_name`) REFERENCES `vpns` (`name`) ON UPDATE CASCADE ON DELETE RESTRICT
+  FOREIGN KEY (`status`) REFERENCES `peer_statuses`(`status`) ON UPDATE CASCADE ON DELETE RESTRICT
+) WITHOUT ROWID;
+INSERT INTO `new_peers`
+  SELECT `vpn_name`, `name`, `index_in_vpn`, NULLIF(`privkey`, ''), `pubkey`, `address_v4`, `address_v6`,
+    `endpoint`, `dns`, `status`
+  FROM `peers`;
+/* dropping `peers` deletes the rows that reference it, they are put back afterwards */
+CREATE TEMP TABLE `saved_allowed_ips` AS SELECT * FROM `allowed_ips`;
+CREATE TEMP TABLE `saved_preshared_keys` AS SELECT * FROM `preshared_keys`;
+DROP TABLE `peers`;
+ALTER TABLE `new_peers` RENAME TO `peers`;
+CREATE INDEX `peers_vpn_names_idx` ON `peers`(`vpn_name`);
+CREATE UNIQUE INDEX `peers_index_in_vpn` ON `peers`(`vpn_name`, `index_in_vpn`);
+CREATE INDEX `peers_statuses_idx` ON `peers`(`status`);
+
+/* when creating a peer, assign its id */
+CREATE TRIGGER assign_a_peer_the_next_index AFTER INSERT ON `peers`
+BEGIN
+  UPDATE peers SET index_in_vpn = (SELECT MAX(index_in_vpn)+1 FROM `peers` GROUP BY `vpn_name` HAVING vpn_name = new.vpn_name)
+    WHERE vpn_name = new.vpn_name AND name = new.name;
+END;
+
+/* Copy the IPv4 and IPv6 addresses of a peer into its allowed addresses */
+CREATE TRIGGER after_insert_on_peers_add_allowed_ips AFTER INSERT ON `peers`
+BEGIN
+  INSERT INTO allowed_ips VALUES(new.vpn_name, new.name, new.address_v4);
+  INSERT INTO allowed_ips VALUES(new.vpn_name, new.name, new.address_v6);
+END;
+
+/* Update IPv4/IPv6 allowed_ips of a peer when they are updated in the peer table */
+CREATE TRIGGER after_update_on_peers_update_allowed_ips AFTER UPDATE ON `peers`
+  WHEN new.address_v4 <> old.address_v4
+    OR new.address_v6 <> old.address_v6
+BEGIN
+  UPDATE allowed_ips
+    SET address = new.address_v4
+    WHERE peer_vpn = new.vpn_name
+      AND peer_name = new.name
+      AND address = old.address_v4;
+  UPDATE allowed_ips
+    SET address = new.address_v6
+    WHERE peer_vpn = new.vpn_name
+      AND peer_name = new.name
+      AND address = old.address_v6;
+END;
+
+INSERT INTO `allowed_ips` SELECT * FROM `saved_allowed_ips`;
+INSERT INTO `preshared_keys` SELECT * FROM `saved_preshared_keys`;
+DROP TABLE `saved_allowed_ips`;
+DROP TABLE `saved_preshared_keys`;
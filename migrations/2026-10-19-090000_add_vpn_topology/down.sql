@@ -0,0 +1,6 @@
+DROP TRIGGER before_insert_check_if_pair_is_present_for_edges;
+DROP INDEX edges_peers2_idx;
+DROP INDEX edges_peers1_idx;
+DROP TABLE edges;
+ALTER TABLE `peers` DROP COLUMN `hub`;
+ALTER TABLE `vpns` DROP COLUMN `topology`;
@@ -0,0 +1,28 @@
+/* which peers have a [Peer] section for which: every other peer (mesh), hubs only (hub)
+ * or the peers listed in `edges` (custom).
+ * ADD COLUMN can't have a REFERENCES clause with a default, so a CHECK is used instead of a table */
+ALTER TABLE `vpns` ADD COLUMN `topology` TEXT NOT NULL DEFAULT 'mesh'
+  CHECK (`topology` IN ('mesh', 'hub', 'custom'));
+
+/* hub peers, for the hub topology */
+ALTER TABLE `peers` ADD COLUMN `hub` BOOLEAN NOT NULL DEFAULT 0;
+
+/* which peers talk to each other, for the custom topology */
+CREATE TABLE `edges` (
+  `vpn` TEXT NOT NULL,
+  `peer1` TEXT NOT NULL,
+  `peer2` TEXT NOT NULL,
+  PRIMARY KEY (`vpn`, `peer1`, `peer2`)
+  FOREIGN KEY (`vpn`, `peer1`) REFERENCES `peers` (`vpn_name`, `name`) ON UPDATE CASCADE ON DELETE CASCADE
+  FOREIGN KEY (`vpn`, `peer2`) REFERENCES `peers` (`vpn_name`, `name`) ON UPDATE CASCADE ON DELETE CASCADE
+) WITHOUT ROWID;
+CREATE INDEX `edges_peers1_idx` ON `edges`(`vpn`, `peer1`);
+CREATE INDEX `edges_peers2_idx` ON `edges`(`vpn`, `peer2`);
+
+/* edges are not directed, so avoid having peer1, peer2 and peer2, peer1 */
+CREATE TRIGGER before_insert_check_if_pair_is_present_for_edges BEFORE INSERT ON `edges`
+BEGIN
+  SELECT RAISE(FAIL, "peers pair are already linked")
+  FROM edges
+  WHERE vpn = new.vpn AND peer2 = new.peer1 AND peer1 = new.peer2;
+END;
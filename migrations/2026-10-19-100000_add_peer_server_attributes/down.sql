@@ -0,0 +1,9 @@
+ALTER TABLE `peers` ADD COLUMN `endpoint` TEXT;
+UPDATE `peers` SET `endpoint` = CASE WHEN instr(`endpoint_host`, ':') > 0
+    THEN '[' || `endpoint_host` || ']:' || `endpoint_port`
+    ELSE `endpoint_host` || ':' || `endpoint_port` END
+  WHERE `endpoint_host` IS NOT NULL;
+ALTER TABLE `peers` DROP COLUMN `persistent_keepalive`;
+ALTER TABLE `peers` DROP COLUMN `endpoint_port`;
+ALTER TABLE `peers` DROP COLUMN `endpoint_host`;
+ALTER TABLE `peers` DROP COLUMN `listen_port`;
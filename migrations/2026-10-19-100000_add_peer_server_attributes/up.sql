@@ -0,0 +1,23 @@
+/* port the peer listens on, set for servers */
+ALTER TABLE `peers` ADD COLUMN `listen_port` INTEGER CHECK (`listen_port` BETWEEN 1 AND 65535);
+/* public address of the peer, replaces the free form `endpoint` */
+ALTER TABLE `peers` ADD COLUMN `endpoint_host` TEXT;
+ALTER TABLE `peers` ADD COLUMN `endpoint_port` INTEGER CHECK (`endpoint_port` BETWEEN 1 AND 65535);
+/* keepalive interval the other peers should use towards this one */
+ALTER TABLE `peers` ADD COLUMN `persistent_keepalive` INTEGER
+  CHECK (`persistent_keepalive` BETWEEN 1 AND 65535);
+
+/* split host:port and [ipv6]:port endpoints, anything else can't be used and is dropped */
+UPDATE `peers` SET
+  `endpoint_host` = CASE WHEN `endpoint` LIKE '[%]:%'
+    THEN substr(`endpoint`, 2, instr(`endpoint`, ']:') - 2)
+    ELSE substr(`endpoint`, 1, instr(`endpoint`, ':') - 1) END,
+  `endpoint_port` = CAST(CASE WHEN `endpoint` LIKE '[%]:%'
+    THEN substr(`endpoint`, instr(`endpoint`, ']:') + 2)
+    ELSE substr(`endpoint`, instr(`endpoint`, ':') + 1) END AS INTEGER)
+  WHERE `endpoint` LIKE '%_:_%'
+    AND CAST(CASE WHEN `endpoint` LIKE '[%]:%'
+      THEN substr(`endpoint`, instr(`endpoint`, ']:') + 2)
+      ELSE substr(`endpoint`, instr(`endpoint`, ':') + 1) END AS INTEGER) BETWEEN 1 AND 65535;
+
+ALTER TABLE `peers` DROP COLUMN `endpoint`;
@@ -0,0 +1,14 @@
+ALTER TABLE `vpns` DROP COLUMN `post_down`;
+ALTER TABLE `vpns` DROP COLUMN `pre_down`;
+ALTER TABLE `vpns` DROP COLUMN `post_up`;
+ALTER TABLE `vpns` DROP COLUMN `pre_up`;
+ALTER TABLE `vpns` DROP COLUMN `fwmark`;
+ALTER TABLE `vpns` DROP COLUMN `route_table`;
+ALTER TABLE `vpns` DROP COLUMN `mtu`;
+ALTER TABLE `peers` DROP COLUMN `post_down`;
+ALTER TABLE `peers` DROP COLUMN `pre_down`;
+ALTER TABLE `peers` DROP COLUMN `post_up`;
+ALTER TABLE `peers` DROP COLUMN `pre_up`;
+ALTER TABLE `peers` DROP COLUMN `fwmark`;
+ALTER TABLE `peers` DROP COLUMN `route_table`;
+ALTER TABLE `peers` DROP COLUMN `mtu`;
@@ -0,0 +1,19 @@
+/* wg-quick interface settings, set on the VPN and overridden by the peers where not NULL.
+ * MTU can't go below 1280 as the VPNs always carry IPv6 */
+ALTER TABLE `vpns` ADD COLUMN `mtu` INTEGER CHECK (`mtu` BETWEEN 1280 AND 65535);
+/* off, auto, or a routing table number or name */
+ALTER TABLE `vpns` ADD COLUMN `route_table` TEXT CHECK (`route_table` <> '');
+ALTER TABLE `vpns` ADD COLUMN `fwmark` BIGINT CHECK (`fwmark` BETWEEN 1 AND 4294967295);
+/* one command per line */
+ALTER TABLE `vpns` ADD COLUMN `pre_up` TEXT;
+ALTER TABLE `vpns` ADD COLUMN `post_up` TEXT;
+ALTER TABLE `vpns` ADD COLUMN `pre_down` TEXT;
+ALTER TABLE `vpns` ADD COLUMN `post_down` TEXT;
+
+ALTER TABLE `peers` ADD COLUMN `mtu` INTEGER CHECK (`mtu` BETWEEN 1280 AND 65535);
+ALTER TABLE `peers` ADD COLUMN `route_table` TEXT CHECK (`route_table` <> '');
+ALTER TABLE `peers` ADD COLUMN `fwmark` BIGINT CHECK (`fwmark` BETWEEN 1 AND 4294967295);
+ALTER TABLE `peers` ADD COLUMN `pre_up` TEXT;
+ALTER TABLE `peers` ADD COLUMN `post_up` TEXT;
+ALTER TABLE `peers` ADD COLUMN `pre_down` TEXT;
+ALTER TABLE `peers` ADD COLUMN `post_down` TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE `peers` DROP COLUMN `exclude_lan`;
+ALTER TABLE `peers` DROP COLUMN `tunnel`;
@@ -0,0 +1,6 @@
+/* split: only the VPN and the networks behind the other peers go through the tunnel.
+ * full: the default routes go through the gateway peer, except the RFC1918 ranges when
+ * exclude_lan is set */
+ALTER TABLE `peers` ADD COLUMN `tunnel` TEXT NOT NULL DEFAULT 'split'
+  CHECK (`tunnel` IN ('split', 'full'));
+ALTER TABLE `peers` ADD COLUMN `exclude_lan` BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE `peers` DROP COLUMN `firewall`;
+ALTER TABLE `peers` DROP COLUMN `isolated`;
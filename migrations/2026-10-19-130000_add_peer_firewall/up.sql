@@ -0,0 +1,5 @@
+/* isolated peers can't reach the other peers through a server, only the server itself and
+ * the networks it routes */
+ALTER TABLE `peers` ADD COLUMN `isolated` BOOLEAN NOT NULL DEFAULT 0;
+/* firewall rules added to the PostUp/PostDown hooks of a server, NULL for none */
+ALTER TABLE `peers` ADD COLUMN `firewall` TEXT CHECK (`firewall` IN ('nftables', 'iptables'));
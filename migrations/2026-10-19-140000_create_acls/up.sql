@@ -0,0 +1,21 @@
+/* peer to peer access rules, enforced by the firewall of the servers and evaluated in `id`
+ * order, the first matching rule wins. A NULL source or destination matches every peer,
+ * a NULL protocol every protocol */
+CREATE TABLE `acls` (
+  `id` INTEGER PRIMARY KEY,
+  `vpn` TEXT NOT NULL,
+  `src_peer` TEXT,
+  `src_tag` TEXT,
+  `dst_peer` TEXT,
+  `dst_tag` TEXT,
+  `protocol` TEXT CHECK (`protocol` IN ('tcp', 'udp', 'icmp')),
+  `port` INTEGER CHECK (`port` BETWEEN 1 AND 65535),
+  `action` TEXT NOT NULL CHECK (`action` IN ('allow', 'deny')),
+  FOREIGN KEY (`vpn`) REFERENCES `vpns` (`name`) ON UPDATE CASCADE ON DELETE CASCADE
+  FOREIGN KEY (`vpn`, `src_peer`) REFERENCES `peers` (`vpn_name`, `name`) ON UPDATE CASCADE ON DELETE CASCADE
+  FOREIGN KEY (`vpn`, `dst_peer`) REFERENCES `peers` (`vpn_name`, `name`) ON UPDATE CASCADE ON DELETE CASCADE
+  CHECK (`src_peer` IS NULL OR `src_tag` IS NULL)
+  CHECK (`dst_peer` IS NULL OR `dst_tag` IS NULL)
+  CHECK (`port` IS NULL OR `protocol` IN ('tcp', 'udp'))
+);
+CREATE INDEX `acls_vpn_idx` ON `acls`(`vpn`);
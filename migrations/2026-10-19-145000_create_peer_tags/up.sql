@@ -0,0 +1,9 @@
+/* free form labels on peers, like `contractors` or `dept=eng` */
+CREATE TABLE `peer_tags` (
+  `vpn` TEXT NOT NULL,
+  `peer` TEXT NOT NULL,
+  `tag` TEXT NOT NULL CHECK (`tag` <> ''),
+  PRIMARY KEY (`vpn`, `peer`, `tag`)
+  FOREIGN KEY (`vpn`, `peer`) REFERENCES `peers` (`vpn_name`, `name`) ON UPDATE CASCADE ON DELETE CASCADE
+) WITHOUT ROWID;
+CREATE INDEX `peer_tags_tag_idx` ON `peer_tags`(`vpn`, `tag`);
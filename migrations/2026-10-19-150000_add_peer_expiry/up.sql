@@ -0,0 +1,3 @@
+/* UTC time after which the peer is treated as disabled, as `YYYY-MM-DD HH:MM:SS` so that it
+ * sorts and compares like SQLite's datetime(). NULL never expires */
+ALTER TABLE `peers` ADD COLUMN `expires_at` TEXT CHECK (`expires_at` IS NULL OR datetime(`expires_at`) IS `expires_at`);
@@ -0,0 +1,13 @@
+/* public keys replaced by `rotate-keys`. Until `grace_until` the exports of the other peers
+ * can still list them next to the current key. Times are UTC, like datetime() */
+CREATE TABLE `key_history` (
+  `id` INTEGER PRIMARY KEY,
+  `vpn` TEXT NOT NULL,
+  `peer` TEXT NOT NULL,
+  `pubkey` TEXT NOT NULL,
+  `retired_at` TEXT NOT NULL,
+  `grace_until` TEXT NOT NULL,
+  FOREIGN KEY (`vpn`, `peer`) REFERENCES `peers` (`vpn_name`, `name`) ON UPDATE CASCADE ON DELETE CASCADE
+  CHECK (`grace_until` >= `retired_at`)
+);
+CREATE INDEX `key_history_peer_idx` ON `key_history`(`vpn`, `peer`);
@@ -0,0 +1,2 @@
+ALTER TABLE `preshared_keys` DROP COLUMN `created_at`;
+ALTER TABLE `peers` DROP COLUMN `key_created_at`;
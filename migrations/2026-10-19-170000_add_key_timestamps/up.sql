@@ -0,0 +1,4 @@
+/* when the current keys were generated, UTC like datetime(). NULL for keys of unknown age,
+ * like the imported ones */
+ALTER TABLE `peers` ADD COLUMN `key_created_at` TEXT CHECK (`key_created_at` IS NULL OR datetime(`key_created_at`) IS `key_created_at`);
+ALTER TABLE `preshared_keys` ADD COLUMN `created_at` TEXT CHECK (`created_at` IS NULL OR datetime(`created_at`) IS `created_at`);
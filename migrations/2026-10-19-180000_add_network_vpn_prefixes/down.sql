@@ -0,0 +1,8 @@
+DROP TRIGGER assign_a_vpn_the_lowest_free_index;
+CREATE TRIGGER assign_a_vpn_the_next_index AFTER INSERT ON `vpns`
+BEGIN
+  UPDATE vpns SET index_in_network = (SELECT MAX(index_in_network)+1 FROM `vpns` GROUP BY network_name HAVING network_name = new.network_name)
+    WHERE name = new.name;
+END;
+ALTER TABLE `networks` DROP COLUMN `vpn_prefix_v6`;
+ALTER TABLE `networks` DROP COLUMN `vpn_prefix_v4`;
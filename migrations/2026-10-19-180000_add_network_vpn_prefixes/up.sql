@@ -0,0 +1,34 @@
+/* prefix lengths of the subnets assigned to the new VPNs of a network */
+ALTER TABLE `networks` ADD COLUMN `vpn_prefix_v4` INTEGER NOT NULL DEFAULT 24
+  CHECK (`vpn_prefix_v4` BETWEEN 0 AND 32);
+ALTER TABLE `networks` ADD COLUMN `vpn_prefix_v6` INTEGER NOT NULL DEFAULT 64
+  CHECK (`vpn_prefix_v6` BETWEEN 0 AND 128);
+
+/* MAX(index_in_network)+1 never reused the indexes of removed VPNs, and as MAX() of NULLs is
+ * NULL the first VPN of a network, and all the ones after it, got no index at all.
+ * New VPNs without an index now get the lowest free one */
+DROP TRIGGER assign_a_vpn_the_next_index;
+CREATE TRIGGER assign_a_vpn_the_lowest_free_index AFTER INSERT ON `vpns`
+  WHEN new.index_in_network IS NULL
+BEGIN
+  UPDATE vpns SET index_in_network = (
+    SELECT MIN(candidate) FROM (
+      SELECT 0 AS candidate
+      UNION SELECT index_in_network + 1 FROM vpns
+        WHERE network_name = new.network_name AND index_in_network IS NOT NULL
+    )
+    WHERE candidate NOT IN (
+      SELECT index_in_network FROM vpns
+        WHERE network_name = new.network_name AND index_in_network IS NOT NULL
+    ))
+    WHERE name = new.name;
+END;
+
+/* number the VPNs of the networks where none got an index, in name order */
+CREATE TEMP TABLE unnumbered AS
+  SELECT network_name FROM vpns GROUP BY network_name HAVING COUNT(index_in_network) = 0;
+UPDATE vpns SET index_in_network = (
+  SELECT COUNT(*) FROM vpns AS other
+    WHERE other.network_name = vpns.network_name AND other.name < vpns.name)
+  WHERE network_name IN (SELECT network_name FROM unnumbered);
+DROP TABLE unnumbered;
@@ -0,0 +1,1191 @@
+//! Typed functions over a database connection, for tools that reuse vpnutils without the
+//! REPL. The commands that change the database are thin wrappers around them
+use crate::error::{constraint, Constraint};
+use crate::expiry;
+use crate::export;
+use crate::import;
+use crate::keys;
+use crate::schema::{acls, edges, key_history, networks, peer_tags, peers, preshared_keys, vpns};
+use crate::types::{
+    AclProtocol, FirewallFormat, Ipv4Address, Ipv4Network, Ipv6Address, Ipv6Network, PeerStatus,
+    Topology, TunnelMode,
+};
+
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use diesel::sqlite::SqliteConnection;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub use crate::endpoint::{Endpoint, EndpointError};
+pub use crate::error::CommandError;
+pub use crate::export::{File, Settings};
+pub use crate::models::{
+    Acl, AclChanges, Network, NetworkChanges, NewAcl, Peer, PeerChanges, PeerSettings, Vpn,
+    VpnChanges, VpnSettings,
+};
+
+pub type Result<T> = std::result::Result<T, CommandError>;
+
+fn overlap(a: IpNet, b: IpNet) -> bool {
+    a.contains(&b) || b.contains(&a)
+}
+
+/// the subnet of a failed UNIQUE constraint on the `address_v4` or `address_v6` column of
+/// `table`, if it is one of those
+fn subnet_of(columns: &str, table: &str, v4: Ipv4Net, v6: Ipv6Net) -> Option<IpNet> {
+    match columns.strip_prefix(table)?.strip_prefix('.')? {
+        "address_v4" => Some(IpNet::V4(v4)),
+        "address_v6" => Some(IpNet::V6(v6)),
+        _ => None,
+    }
+}
+
+/// the error for a write to the networks table that failed a constraint
+fn network_constraint(err: DieselError, name: &str, v4: Ipv4Net, v6: Ipv6Net) -> CommandError {
+    match constraint(&err) {
+        Some(Constraint::Unique(c)) if c == "networks.name" => {
+            CommandError::NetworkExists(name.into())
+        }
+        Some(Constraint::Unique(c)) => match subnet_of(&c, "networks", v4, v6) {
+            Some(subnet) => CommandError::SubnetOverlap {
+                subnet,
+                other: String::from("another network"),
+            },
+            None => err.into(),
+        },
+        Some(Constraint::ForeignKey) => CommandError::NetworkHasVpns(name.into()),
+        None => err.into(),
+    }
+}
+
+/// the error for a write to the vpns table that failed a constraint
+fn vpn_constraint(
+    err: DieselError,
+    network: &str,
+    name: &str,
+    v4: Ipv4Net,
+    v6: Ipv6Net,
+) -> CommandError {
+    match constraint(&err) {
+        Some(Constraint::Unique(c)) if c == "vpns.name" => CommandError::VpnExists(name.into()),
+        Some(Constraint::Unique(c)) => match subnet_of(&c, "vpns", v4, v6) {
+            Some(subnet) => CommandError::SubnetOverlap {
+                subnet,
+                other: String::from("another VPN"),
+            },
+            None => err.into(),
+        },
+        Some(Constraint::ForeignKey) => CommandError::NetworkNotFound(network.into()),
+        None => err.into(),
+    }
+}
+
+/// the error for a write to the peers table that failed a constraint
+fn peer_constraint(err: DieselError, vpn: &str, name: &str) -> CommandError {
+    match constraint(&err) {
+        Some(Constraint::Unique(c)) if c == "peers.vpn_name, peers.name" => {
+            CommandError::PeerExists {
+                vpn: vpn.into(),
+                name: name.into(),
+            }
+        }
+        Some(Constraint::ForeignKey) => CommandError::VpnNotFound(vpn.into()),
+        Some(Constraint::Unique(_)) | None => err.into(),
+    }
+}
+
+pub fn get_network(conn: &SqliteConnection, name: &str) -> Result<Network> {
+    networks::table
+        .find(name)
+        .first::<Network>(conn)
+        .optional()?
+        .ok_or_else(|| CommandError::NetworkNotFound(name.into()))
+}
+
+pub fn list_networks(conn: &SqliteConnection) -> Result<Vec<Network>> {
+    Ok(networks::table.order(networks::name).load(conn)?)
+}
+
+/// fail if the subnets overlap those of the networks other than `name`
+fn check_network_subnets(
+    conn: &SqliteConnection,
+    name: &str,
+    v4: &Ipv4Net,
+    v6: &Ipv6Net,
+) -> Result<()> {
+    for other in list_networks(conn)?.iter().filter(|n| n.name != name) {
+        let subnet = match (
+            overlap(IpNet::V4(*v4), IpNet::V4(*other.address_v4)),
+            overlap(IpNet::V6(*v6), IpNet::V6(*other.address_v6)),
+        ) {
+            (true, _) => IpNet::V4(*v4),
+            (_, true) => IpNet::V6(*v6),
+            _ => continue,
+        };
+        return Err(CommandError::SubnetOverlap {
+            subnet,
+            other: format!("network {}", other.name),
+        });
+    }
+    Ok(())
+}
+
+/// the prefix lengths of the VPN subnets of a network when not given, the same as the
+/// defaults of the networks table
+const DEFAULT_VPN_PREFIX_V4: i32 = 24;
+const DEFAULT_VPN_PREFIX_V6: i32 = 64;
+
+/// add a network, the VPNs get their subnets from it
+pub fn create_network(
+    conn: &SqliteConnection,
+    name: &str,
+    v4: Ipv4Net,
+    v6: Ipv6Net,
+    vpn_prefix_v4: Option<u8>,
+    vpn_prefix_v6: Option<u8>,
+) -> Result<Network> {
+    conn.transaction(|| {
+        // only the network part matters
+        let (v4, v6) = (v4.trunc(), v6.trunc());
+        check_network_subnets(conn, name, &v4, &v6)?;
+        // the default prefix lengths may not fit in small networks either
+        let vpn_prefix_v4 = vpn_prefix(
+            IpNet::V4(v4),
+            vpn_prefix_v4.map_or(DEFAULT_VPN_PREFIX_V4, i32::from),
+        )?;
+        let vpn_prefix_v6 = vpn_prefix(
+            IpNet::V6(v6),
+            vpn_prefix_v6.map_or(DEFAULT_VPN_PREFIX_V6, i32::from),
+        )?;
+        diesel::insert_into(networks::table)
+            .values(&crate::models::NewNetwork {
+                name,
+                address_v4: Ipv4Network(v4),
+                address_v6: Ipv6Network(v6),
+                vpn_prefix_v4: Some(i32::from(vpn_prefix_v4)),
+                vpn_prefix_v6: Some(i32::from(vpn_prefix_v6)),
+            })
+            .execute(conn)
+            .map_err(|e| network_constraint(e, name, v4, v6))?;
+        get_network(conn, name)
+    })
+}
+
+/// change the name, the subnets or the VPN prefix lengths of a network. The subnets must still
+/// contain those of its VPNs
+pub fn update_network(
+    conn: &SqliteConnection,
+    name: &str,
+    mut changes: NetworkChanges,
+) -> Result<Network> {
+    conn.transaction(|| {
+        let current = get_network(conn, name)?;
+        let v4 = changes
+            .address_v4
+            .map_or(*current.address_v4, |a| a.trunc());
+        let v6 = changes
+            .address_v6
+            .map_or(*current.address_v6, |a| a.trunc());
+        check_network_subnets(conn, name, &v4, &v6)?;
+        vpn_prefix(
+            IpNet::V4(v4),
+            changes.vpn_prefix_v4.unwrap_or(current.vpn_prefix_v4),
+        )?;
+        vpn_prefix(
+            IpNet::V6(v6),
+            changes.vpn_prefix_v6.unwrap_or(current.vpn_prefix_v6),
+        )?;
+        for vpn in list_vpns(conn, Some(name))? {
+            let outside = match (v4.contains(&*vpn.address_v4), v6.contains(&*vpn.address_v6)) {
+                (false, _) => IpNet::V4(*vpn.address_v4),
+                (_, false) => IpNet::V6(*vpn.address_v6),
+                _ => continue,
+            };
+            return Err(CommandError::SubnetOutOfRange {
+                subnet: outside,
+                network: name.into(),
+            });
+        }
+        changes.address_v4 = changes.address_v4.map(|_| Ipv4Network(v4));
+        changes.address_v6 = changes.address_v6.map(|_| Ipv6Network(v6));
+        let new_name = changes.name.as_deref().unwrap_or(name);
+        diesel::update(networks::table.find(name))
+            .set(&changes)
+            .execute(conn)
+            .map_err(|e| network_constraint(e, new_name, v4, v6))?;
+        get_network(conn, new_name)
+    })
+}
+
+/// remove a network without VPNs
+pub fn remove_network(conn: &SqliteConnection, name: &str) -> Result<()> {
+    conn.transaction(|| {
+        let network = get_network(conn, name)?;
+        diesel::delete(networks::table.find(name))
+            .execute(conn)
+            .map_err(|e| network_constraint(e, name, *network.address_v4, *network.address_v6))?;
+        Ok(())
+    })
+}
+
+pub fn get_vpn(conn: &SqliteConnection, name: &str) -> Result<Vpn> {
+    vpns::table
+        .find(name)
+        .first::<Vpn>(conn)
+        .optional()?
+        .ok_or_else(|| CommandError::VpnNotFound(name.into()))
+}
+
+/// the VPNs of `network`, or all of them
+pub fn list_vpns(conn: &SqliteConnection, network: Option<&str>) -> Result<Vec<Vpn>> {
+    let mut query = vpns::table.order(vpns::name).into_boxed();
+    if let Some(network) = network {
+        get_network(conn, network)?;
+        query = query.filter(vpns::network_name.eq(network));
+    }
+    Ok(query.load(conn)?)
+}
+
+/// fail if the subnets of a VPN are outside of `network` or overlap those of the other VPNs
+/// in it
+fn check_vpn_subnets(
+    conn: &SqliteConnection,
+    network: &Network,
+    name: &str,
+    v4: &Ipv4Net,
+    v6: &Ipv6Net,
+) -> Result<()> {
+    for (subnet, inside) in [
+        (IpNet::V4(*v4), network.address_v4.contains(v4)),
+        (IpNet::V6(*v6), network.address_v6.contains(v6)),
+    ] {
+        if !inside {
+            return Err(CommandError::SubnetOutOfRange {
+                subnet,
+                network: network.name.clone(),
+            });
+        }
+    }
+    for other in list_vpns(conn, Some(&network.name))?
+        .iter()
+        .filter(|v| v.name != name)
+    {
+        let subnet = match (
+            overlap(IpNet::V4(*v4), IpNet::V4(*other.address_v4)),
+            overlap(IpNet::V6(*v6), IpNet::V6(*other.address_v6)),
+        ) {
+            (true, _) => IpNet::V4(*v4),
+            (_, true) => IpNet::V6(*v6),
+            _ => continue,
+        };
+        return Err(CommandError::SubnetOverlap {
+            subnet,
+            other: format!("VPN {}", other.name),
+        });
+    }
+    Ok(())
+}
+
+/// the first and last address of a subnet, as integers
+fn range(net: &IpNet) -> (u128, u128) {
+    match net {
+        IpNet::V4(n) => (
+            u32::from(n.network()).into(),
+            u32::from(n.broadcast()).into(),
+        ),
+        IpNet::V6(n) => (n.network().into(), n.broadcast().into()),
+    }
+}
+
+/// the number of addresses in a subnet, u128::MAX for ::/0
+fn size(net: &IpNet) -> u128 {
+    let (first, last) = range(net);
+    (last - first).saturating_add(1)
+}
+
+/// the first subnet of `network` with `prefix_len` that doesn't overlap the `used` ones.
+/// Candidates jump past the used subnets, so that large networks with few VPNs are quick
+fn first_fit(network: IpNet, prefix_len: u8, used: &[IpNet]) -> Option<IpNet> {
+    if prefix_len < network.prefix_len() {
+        return None;
+    }
+    let (_, last) = range(&network);
+    let (mut candidate, end) = range(&IpNet::new(network.network(), prefix_len).ok()?);
+    // subnets are aligned to their size
+    let mask = end - candidate;
+    let used: Vec<(u128, u128)> = used.iter().map(range).collect();
+    loop {
+        let end = candidate.checked_add(mask).filter(|end| *end <= last)?;
+        match used
+            .iter()
+            .filter(|(first, last)| *first <= end && *last >= candidate)
+            .map(|(_, last)| *last)
+            .max()
+        {
+            Some(last) => candidate = last.checked_add(1)?.checked_add(mask)? & !mask,
+            None => break,
+        }
+    }
+    let address = match network {
+        IpNet::V4(_) => IpAddr::V4(Ipv4Addr::from(u32::try_from(candidate).ok()?)),
+        IpNet::V6(_) => IpAddr::V6(Ipv6Addr::from(candidate)),
+    };
+    IpNet::new(address, prefix_len).ok()
+}
+
+/// a prefix length for the VPNs of a network, which must fit in its `subnet`
+fn vpn_prefix(subnet: IpNet, prefix_len: i32) -> Result<u8> {
+    u8::try_from(prefix_len)
+        .ok()
+        .filter(|p| (subnet.prefix_len()..=subnet.max_prefix_len()).contains(p))
+        .ok_or(CommandError::InvalidPrefixLength { subnet, prefix_len })
+}
+
+/// the first subnets of `network` not used by its VPNs, with the prefix lengths of the network
+fn free_vpn_subnets(conn: &SqliteConnection, network: &Network) -> Result<(Ipv4Net, Ipv6Net)> {
+    let vpns = list_vpns(conn, Some(&network.name))?;
+    let free = |subnet: IpNet, prefix_len: i32, used: Vec<IpNet>| {
+        let prefix_len = vpn_prefix(subnet, prefix_len)?;
+        first_fit(subnet, prefix_len, &used).ok_or_else(|| CommandError::SubnetExhausted {
+            network: network.name.clone(),
+            subnet,
+            prefix_len,
+            vpns: vpns.len(),
+            used: used
+                .iter()
+                .filter(|u| subnet.contains(*u))
+                .map(size)
+                .fold(0, u128::saturating_add),
+            total: size(&subnet),
+        })
+    };
+    let v4 = free(
+        IpNet::V4(*network.address_v4),
+        network.vpn_prefix_v4,
+        vpns.iter().map(|v| IpNet::V4(*v.address_v4)).collect(),
+    )?;
+    let v6 = free(
+        IpNet::V6(*network.address_v6),
+        network.vpn_prefix_v6,
+        vpns.iter().map(|v| IpNet::V6(*v.address_v6)).collect(),
+    )?;
+    match (v4, v6) {
+        (IpNet::V4(v4), IpNet::V6(v6)) => Ok((v4, v6)),
+        _ => unreachable!("first_fit keeps the address family"),
+    }
+}
+
+/// add a VPN to `network`, the subnets not given are the first free ones in the network
+pub fn add_vpn(
+    conn: &SqliteConnection,
+    network: &str,
+    name: &str,
+    v4: Option<Ipv4Net>,
+    v6: Option<Ipv6Net>,
+) -> Result<Vpn> {
+    conn.transaction(|| {
+        let network = get_network(conn, network)?;
+        let (v4, v6) = match (v4, v6) {
+            (Some(v4), Some(v6)) => (v4.trunc(), v6.trunc()),
+            _ => {
+                let (free_v4, free_v6) = free_vpn_subnets(conn, &network)?;
+                (
+                    v4.map_or(free_v4, |n| n.trunc()),
+                    v6.map_or(free_v6, |n| n.trunc()),
+                )
+            }
+        };
+        check_vpn_subnets(conn, &network, name, &v4, &v6)?;
+        diesel::insert_into(vpns::table)
+            .values(&crate::models::NewVpn {
+                name,
+                network_name: &network.name,
+                address_v4: Ipv4Network(v4),
+                address_v6: Ipv6Network(v6),
+            })
+            .execute(conn)
+            .map_err(|e| vpn_constraint(e, &network.name, name, v4, v6))?;
+        get_vpn(conn, name)
+    })
+}
+
+/// change the name or the subnets of a VPN, which must still contain the addresses of its
+/// peers
+pub fn update_vpn(conn: &SqliteConnection, name: &str, mut changes: VpnChanges) -> Result<Vpn> {
+    conn.transaction(|| {
+        let current = get_vpn(conn, name)?;
+        let network = get_network(conn, &current.network_name)?;
+        let v4 = changes
+            .address_v4
+            .map_or(*current.address_v4, |a| a.trunc());
+        let v6 = changes
+            .address_v6
+            .map_or(*current.address_v6, |a| a.trunc());
+        check_vpn_subnets(conn, &network, name, &v4, &v6)?;
+        for peer in list_peers(conn, name, &[])? {
+            let outside = match (
+                v4.contains(&*peer.address_v4),
+                v6.contains(&*peer.address_v6),
+            ) {
+                (false, _) => IpAddr::V4(*peer.address_v4),
+                (_, false) => IpAddr::V6(*peer.address_v6),
+                _ => continue,
+            };
+            return Err(CommandError::AddressOutOfRange {
+                address: outside,
+                vpn: name.into(),
+            });
+        }
+        changes.address_v4 = changes.address_v4.map(|_| Ipv4Network(v4));
+        changes.address_v6 = changes.address_v6.map(|_| Ipv6Network(v6));
+        let new_name = changes.name.as_deref().unwrap_or(name);
+        diesel::update(vpns::table.find(name))
+            .set(&changes)
+            .execute(conn)
+            .map_err(|e| vpn_constraint(e, &network.name, new_name, v4, v6))?;
+        get_vpn(conn, new_name)
+    })
+}
+
+/// remove a VPN without peers
+pub fn remove_vpn(conn: &SqliteConnection, name: &str) -> Result<()> {
+    conn.transaction(|| {
+        get_vpn(conn, name)?;
+        diesel::delete(vpns::table.find(name))
+            .execute(conn)
+            .map_err(|e| match constraint(&e) {
+                Some(Constraint::ForeignKey) => CommandError::VpnHasPeers(name.into()),
+                _ => e.into(),
+            })?;
+        Ok(())
+    })
+}
+
+/// set which peers of a VPN have a [Peer] section for which
+pub fn set_vpn_topology(conn: &SqliteConnection, name: &str, topology: Topology) -> Result<Vpn> {
+    conn.transaction(|| {
+        get_vpn(conn, name)?;
+        diesel::update(vpns::table.find(name))
+            .set(vpns::topology.eq(topology))
+            .execute(conn)?;
+        get_vpn(conn, name)
+    })
+}
+
+/// fail for the interface settings wg-quick would choke on
+fn check_settings(mtu: Option<i32>, route_table: Option<&str>) -> Result<()> {
+    if let Some(mtu) = mtu.filter(|m| *m < 1280) {
+        return Err(CommandError::MtuTooSmall(mtu));
+    }
+    if route_table == Some("") {
+        return Err(CommandError::EmptyRouteTable);
+    }
+    Ok(())
+}
+
+/// change the wg-quick interface settings of a VPN, those of its peers override them
+pub fn update_vpn_settings(
+    conn: &SqliteConnection,
+    name: &str,
+    settings: &VpnSettings,
+) -> Result<Vpn> {
+    check_settings(
+        settings.mtu.flatten(),
+        settings.route_table.as_ref().and_then(|t| t.as_deref()),
+    )?;
+    conn.transaction(|| {
+        get_vpn(conn, name)?;
+        diesel::update(vpns::table.find(name))
+            .set(settings)
+            .execute(conn)?;
+        get_vpn(conn, name)
+    })
+}
+
+pub fn get_peer(conn: &SqliteConnection, vpn: &str, name: &str) -> Result<Peer> {
+    peers::table
+        .find((vpn, name))
+        .first::<Peer>(conn)
+        .optional()?
+        .ok_or_else(|| CommandError::PeerNotFound {
+            vpn: vpn.into(),
+            name: name.into(),
+        })
+}
+
+/// the peers of `vpn` with all the `tags`, or all its peers without tags
+pub fn list_peers(conn: &SqliteConnection, vpn: &str, tags: &[String]) -> Result<Vec<Peer>> {
+    get_vpn(conn, vpn)?;
+    let peers = peers::table
+        .filter(peers::vpn_name.eq(vpn))
+        .order(peers::name)
+        .load::<Peer>(conn)?;
+    if tags.is_empty() {
+        return Ok(peers);
+    }
+    let wanted: HashSet<&String> = tags.iter().collect();
+    let tagged = peer_tags::table
+        .filter(peer_tags::vpn.eq(vpn))
+        .filter(peer_tags::tag.eq_any(tags))
+        .select(peer_tags::peer)
+        .load::<String>(conn)?;
+    Ok(peers
+        .into_iter()
+        .filter(|p| tagged.iter().filter(|t| **t == p.name).count() == wanted.len())
+        .collect())
+}
+
+/// fail if the addresses are outside of the subnets of `vpn` or used by a peer other than
+/// `name`
+fn check_peer_addresses(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    v4: Option<Ipv4Addr>,
+    v6: Option<Ipv6Addr>,
+) -> Result<()> {
+    let subnets = import::Subnets::load(conn, vpn)?;
+    let addresses = [
+        v4.map(|a| (subnets.v4.contains(&a), IpAddr::V4(a))),
+        v6.map(|a| (subnets.v6.contains(&a), IpAddr::V6(a))),
+    ];
+    let others = peers::table
+        .filter(peers::vpn_name.eq(vpn))
+        .filter(peers::name.ne(name))
+        .load::<Peer>(conn)?;
+    for (inside, address) in addresses.iter().flatten() {
+        if !inside {
+            return Err(CommandError::AddressOutOfRange {
+                address: *address,
+                vpn: vpn.into(),
+            });
+        }
+        let taken = others.iter().find(|p| {
+            *address == IpAddr::V4(*p.address_v4) || *address == IpAddr::V6(*p.address_v6)
+        });
+        if let Some(other) = taken {
+            return Err(CommandError::AddressInUse {
+                address: *address,
+                peer: other.name.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// fail if `key` is the public key of a peer of `vpn` other than `name`
+fn check_public_key(conn: &SqliteConnection, vpn: &str, name: &str, key: &str) -> Result<()> {
+    let other = peers::table
+        .filter(peers::vpn_name.eq(vpn))
+        .filter(peers::name.ne(name))
+        .filter(peers::pubkey.eq(key))
+        .select(peers::name)
+        .first::<String>(conn)
+        .optional()?;
+    match other {
+        Some(peer) => Err(CommandError::DuplicatePublicKey {
+            vpn: vpn.into(),
+            peer,
+            key: key.into(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// the optional settings of a new peer. Without keys a new key pair is generated, without
+/// addresses the first free ones of the VPN are assigned
+#[derive(Default, Debug)]
+pub struct PeerOptions {
+    pub endpoint: Option<Endpoint>,
+    pub dns: Option<String>,
+    pub status: PeerStatus,
+    pub public_key: Option<String>,
+    pub private_key: Option<String>,
+    pub address_v4: Option<Ipv4Addr>,
+    pub address_v6: Option<Ipv6Addr>,
+    pub expires_at: Option<String>,
+}
+
+/// the private key, if known, and the public key given for a peer, checking that they match
+fn given_keys(
+    private_key: Option<&str>,
+    public_key: Option<&str>,
+) -> Result<Option<(Option<String>, String)>> {
+    match (private_key, public_key) {
+        (Some(private_key), public_key) => {
+            let derived = keys::public_key(private_key)?;
+            if public_key.is_some_and(|p| p.trim() != derived) {
+                return Err(CommandError::KeyMismatch);
+            }
+            Ok(Some((Some(private_key.trim().to_string()), derived)))
+        }
+        (None, Some(public_key)) => {
+            keys::validate(public_key)?;
+            Ok(Some((None, public_key.trim().to_string())))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// the private key, if known, public key and creation time of the keys of a new peer
+fn new_peer_keys(options: &PeerOptions) -> Result<(Option<String>, String, Option<String>)> {
+    let given = given_keys(
+        options.private_key.as_deref(),
+        options.public_key.as_deref(),
+    )?;
+    match given {
+        // keys made elsewhere are of unknown age
+        Some((private_key, public_key)) => Ok((private_key, public_key, None)),
+        None => {
+            let private_key = keys::generate_private_key();
+            let public_key = keys::public_key(&private_key)?;
+            Ok((Some(private_key), public_key, Some(crate::expiry::now())))
+        }
+    }
+}
+
+/// replace the keys of a peer in `changes` with the given ones, if any. With a public key
+/// alone the private key becomes unknown, the old one is of no use anymore
+pub fn set_peer_keys(
+    changes: &mut PeerChanges,
+    private_key: Option<&str>,
+    public_key: Option<&str>,
+) -> Result<()> {
+    if let Some((private_key, public_key)) = given_keys(private_key, public_key)? {
+        changes.private_key = Some(private_key);
+        changes.public_key = Some(public_key);
+        // keys made elsewhere are of unknown age
+        changes.key_created_at = Some(None);
+    }
+    Ok(())
+}
+
+/// add a peer to `vpn`
+pub fn add_peer(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    options: &PeerOptions,
+) -> Result<Peer> {
+    let (private_key, public_key, key_created_at) = new_peer_keys(options)?;
+    conn.transaction(|| {
+        get_vpn(conn, vpn)?;
+        check_public_key(conn, vpn, name, &public_key)?;
+        check_peer_addresses(conn, vpn, name, options.address_v4, options.address_v6)?;
+        let subnets = import::Subnets::load(conn, vpn)?;
+        let used: HashSet<IpAddr> = list_peers(conn, vpn, &[])?
+            .iter()
+            .flat_map(|p| vec![IpAddr::V4(*p.address_v4), IpAddr::V6(*p.address_v6)])
+            .collect();
+        let exhausted = |subnet| CommandError::AddressesExhausted {
+            vpn: vpn.into(),
+            subnet,
+        };
+        let address_v4 = match options.address_v4 {
+            Some(a) => a,
+            None => import::first_free_v4(&subnets.v4, &used)
+                .ok_or_else(|| exhausted(IpNet::V4(subnets.v4)))?,
+        };
+        let address_v6 = match options.address_v6 {
+            Some(a) => a,
+            None => import::first_free_v6(&subnets.v6, &used)
+                .ok_or_else(|| exhausted(IpNet::V6(subnets.v6)))?,
+        };
+        diesel::insert_into(peers::table)
+            .values(&crate::models::NewPeer {
+                vpn_name: vpn,
+                name,
+                private_key: private_key.as_deref(),
+                public_key: &public_key,
+                address_v4: Ipv4Address(address_v4),
+                address_v6: Ipv6Address(address_v6),
+                dns: options.dns.clone(),
+                status: options.status,
+                listen_port: None,
+                endpoint_host: options.endpoint.as_ref().map(|e| e.host.as_str()),
+                endpoint_port: options.endpoint.as_ref().map(|e| i32::from(e.port)),
+                persistent_keepalive: None,
+                key_created_at,
+                expires_at: options.expires_at.clone(),
+            })
+            .execute(conn)
+            .map_err(|e| peer_constraint(e, vpn, name))?;
+        get_peer(conn, vpn, name)
+    })
+}
+
+/// apply `changes` to a peer, checking the new addresses
+pub fn update_peer(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    changes: &PeerChanges,
+) -> Result<Peer> {
+    conn.transaction(|| {
+        get_peer(conn, vpn, name)?;
+        check_peer_addresses(
+            conn,
+            vpn,
+            name,
+            changes.address_v4.map(|a| *a),
+            changes.address_v6.map(|a| *a),
+        )?;
+        if let Some(key) = changes.public_key.as_deref() {
+            check_public_key(conn, vpn, name, key)?;
+        }
+        let new_name = changes.name.as_deref().unwrap_or(name);
+        diesel::update(peers::table.find((vpn, name)))
+            .set(changes)
+            .execute(conn)
+            .map_err(|e| peer_constraint(e, vpn, new_name))?;
+        get_peer(conn, vpn, new_name)
+    })
+}
+
+/// remove a peer, with its allowed IPs, preshared keys, links, tags and access rules
+pub fn remove_peer(conn: &SqliteConnection, vpn: &str, name: &str) -> Result<()> {
+    conn.transaction(|| {
+        get_peer(conn, vpn, name)?;
+        diesel::delete(peers::table.find((vpn, name))).execute(conn)?;
+        Ok(())
+    })
+}
+
+/// add `tags` to a peer, the ones it already has are left alone
+pub fn add_peer_tags(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    tags: &[String],
+) -> Result<()> {
+    if let Some(tag) = tags
+        .iter()
+        .find(|t| t.is_empty() || t.contains(char::is_whitespace))
+    {
+        return Err(CommandError::InvalidTag(tag.clone()));
+    }
+    conn.transaction(|| {
+        // the foreign key would only say that a constraint failed
+        get_peer(conn, vpn, name)?;
+        for tag in tags {
+            diesel::insert_or_ignore_into(peer_tags::table)
+                .values((
+                    peer_tags::vpn.eq(vpn),
+                    peer_tags::peer.eq(name),
+                    peer_tags::tag.eq(tag),
+                ))
+                .execute(conn)?;
+        }
+        Ok(())
+    })
+}
+
+/// remove `tags` from a peer and return how many it had
+pub fn remove_peer_tags(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    tags: &[String],
+) -> Result<usize> {
+    get_peer(conn, vpn, name)?;
+    Ok(diesel::delete(
+        peer_tags::table
+            .filter(peer_tags::vpn.eq(vpn))
+            .filter(peer_tags::peer.eq(name))
+            .filter(peer_tags::tag.eq_any(tags)),
+    )
+    .execute(conn)?)
+}
+
+/// give a peer a new key pair. The old public key stays in the history, and the exports can
+/// list it for `grace` seconds, until the peer confirms the new one
+pub fn rotate_peer_keys(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    grace: i64,
+) -> Result<Peer> {
+    conn.transaction(|| {
+        let old = get_peer(conn, vpn, name)?;
+        let private_key = keys::generate_private_key();
+        let public_key = keys::public_key(&private_key)?;
+        diesel::insert_into(key_history::table)
+            .values((
+                key_history::vpn.eq(vpn),
+                key_history::peer.eq(name),
+                key_history::pubkey.eq(old.public_key),
+                key_history::retired_at.eq(expiry::now()),
+                key_history::grace_until.eq(expiry::after(grace)),
+            ))
+            .execute(conn)?;
+        diesel::update(peers::table.find((vpn, name)))
+            .set(&PeerChanges {
+                private_key: Some(Some(private_key)),
+                public_key: Some(public_key),
+                key_created_at: Some(Some(expiry::now())),
+                ..Default::default()
+            })
+            .execute(conn)?;
+        get_peer(conn, vpn, name)
+    })
+}
+
+/// end the grace period of the old keys of a peer, now that it runs its new ones, and return
+/// how many were still in it
+pub fn confirm_peer_keys(conn: &SqliteConnection, vpn: &str, name: &str) -> Result<usize> {
+    get_peer(conn, vpn, name)?;
+    Ok(diesel::update(
+        key_history::table
+            .filter(key_history::vpn.eq(vpn))
+            .filter(key_history::peer.eq(name))
+            .filter(key_history::grace_until.gt(expiry::now())),
+    )
+    .set(key_history::grace_until.eq(expiry::now()))
+    .execute(conn)?)
+}
+
+/// the preshared keys replaced by `rotate_vpn_keys`, with the peers it gave new keys
+#[derive(Debug)]
+pub struct VpnRotation {
+    pub peers: Vec<Peer>,
+    pub preshared_keys: usize,
+}
+
+/// give every peer of a VPN a new key pair like `rotate_peer_keys`, and replace all its
+/// preshared keys, e.g. after a suspected compromise
+pub fn rotate_vpn_keys(conn: &SqliteConnection, vpn: &str, grace: i64) -> Result<VpnRotation> {
+    conn.transaction(|| {
+        let peers = list_peers(conn, vpn, &[])?
+            .iter()
+            .map(|p| rotate_peer_keys(conn, vpn, &p.name, grace))
+            .collect::<Result<Vec<_>>>()?;
+        let pairs = preshared_keys::table
+            .filter(preshared_keys::vpn.eq(vpn))
+            .load::<crate::models::PresharedKey>(conn)?;
+        for pair in &pairs {
+            diesel::update(preshared_keys::table.find((vpn, &pair.peer1, &pair.peer2)))
+                .set((
+                    preshared_keys::key.eq(keys::generate_preshared_key()),
+                    preshared_keys::created_at.eq(expiry::now()),
+                ))
+                .execute(conn)?;
+        }
+        Ok(VpnRotation {
+            peers,
+            preshared_keys: pairs.len(),
+        })
+    })
+}
+
+/// keep the firewall rules of a peer in its PostUp and PostDown hooks, generated on export in
+/// the `format` given, or stop with `None`
+pub fn set_peer_firewall(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    format: Option<FirewallFormat>,
+) -> Result<Peer> {
+    conn.transaction(|| {
+        get_peer(conn, vpn, name)?;
+        if let Some(format) = format {
+            // fail now rather than on every export
+            export::firewall::hooks(&export::Node::load(conn, vpn, name)?, format)?;
+        }
+        diesel::update(peers::table.find((vpn, name)))
+            .set(peers::firewall.eq(format))
+            .execute(conn)?;
+        get_peer(conn, vpn, name)
+    })
+}
+
+/// prevent a peer from reaching the other peers through the servers, or let it again
+pub fn set_peer_isolated(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    isolated: bool,
+) -> Result<Peer> {
+    conn.transaction(|| {
+        get_peer(conn, vpn, name)?;
+        diesel::update(peers::table.find((vpn, name)))
+            .set(peers::isolated.eq(isolated))
+            .execute(conn)?;
+        get_peer(conn, vpn, name)
+    })
+}
+
+/// make a peer a hub or a spoke, for VPNs with the hub topology
+pub fn set_peer_hub(conn: &SqliteConnection, vpn: &str, name: &str, hub: bool) -> Result<Peer> {
+    conn.transaction(|| {
+        get_peer(conn, vpn, name)?;
+        diesel::update(peers::table.find((vpn, name)))
+            .set(peers::hub.eq(hub))
+            .execute(conn)?;
+        get_peer(conn, vpn, name)
+    })
+}
+
+/// set how the other peers reach a peer acting as a server or relay, the settings not given
+/// are left as they are
+pub fn update_peer_server(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    listen_port: Option<u16>,
+    endpoint: Option<&Endpoint>,
+    keepalive: Option<u16>,
+) -> Result<Peer> {
+    if listen_port == Some(0) || keepalive == Some(0) {
+        return Err(CommandError::ZeroPortOrKeepalive);
+    }
+    update_server_columns(
+        conn,
+        vpn,
+        name,
+        &crate::models::PeerServer {
+            listen_port: listen_port.map(|p| Some(i32::from(p))),
+            endpoint_host: endpoint.map(|e| Some(e.host.clone())),
+            endpoint_port: endpoint.map(|e| Some(i32::from(e.port))),
+            persistent_keepalive: keepalive.map(|k| Some(i32::from(k))),
+        },
+    )
+}
+
+/// clear the listen port, endpoint and keepalive of a peer
+pub fn clear_peer_server(conn: &SqliteConnection, vpn: &str, name: &str) -> Result<Peer> {
+    update_server_columns(
+        conn,
+        vpn,
+        name,
+        &crate::models::PeerServer {
+            listen_port: Some(None),
+            endpoint_host: Some(None),
+            endpoint_port: Some(None),
+            persistent_keepalive: Some(None),
+        },
+    )
+}
+
+fn update_server_columns(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    changes: &crate::models::PeerServer,
+) -> Result<Peer> {
+    conn.transaction(|| {
+        let peer = get_peer(conn, vpn, name)?;
+        // diesel refuses an update without columns
+        if changes.listen_port.is_none()
+            && changes.endpoint_host.is_none()
+            && changes.persistent_keepalive.is_none()
+        {
+            return Ok(peer);
+        }
+        diesel::update(peers::table.find((vpn, name)))
+            .set(changes)
+            .execute(conn)?;
+        get_peer(conn, vpn, name)
+    })
+}
+
+/// choose which traffic of a peer goes through the VPN. Full tunnels can keep the RFC1918
+/// ranges on the local network with `exclude_lan`
+pub fn set_peer_tunnel(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    mode: TunnelMode,
+    exclude_lan: bool,
+) -> Result<Peer> {
+    if exclude_lan && mode == TunnelMode::Split {
+        return Err(CommandError::SplitTunnelExcludesLan);
+    }
+    conn.transaction(|| {
+        get_peer(conn, vpn, name)?;
+        diesel::update(peers::table.find((vpn, name)))
+            .set((peers::tunnel.eq(mode), peers::exclude_lan.eq(exclude_lan)))
+            .execute(conn)?;
+        get_peer(conn, vpn, name)
+    })
+}
+
+/// the wg-quick interface settings of a peer, including those it inherits from its VPN
+pub fn peer_settings(conn: &SqliteConnection, vpn: &str, name: &str) -> Result<Settings> {
+    get_peer(conn, vpn, name)?;
+    Ok(export::Node::load(conn, vpn, name)?.settings)
+}
+
+/// override the wg-quick interface settings of the VPN for a peer, cleared settings fall back
+/// to the VPN ones
+pub fn update_peer_settings(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    settings: &PeerSettings,
+) -> Result<Peer> {
+    check_settings(
+        settings.mtu.flatten(),
+        settings.route_table.as_ref().and_then(|t| t.as_deref()),
+    )?;
+    conn.transaction(|| {
+        get_peer(conn, vpn, name)?;
+        diesel::update(peers::table.find((vpn, name)))
+            .set(settings)
+            .execute(conn)?;
+        get_peer(conn, vpn, name)
+    })
+}
+
+/// true if two peers are linked, in either order
+fn linked(conn: &SqliteConnection, vpn: &str, peer1: &str, peer2: &str) -> Result<bool> {
+    let count: i64 = edges::table
+        .filter(edges::vpn.eq(vpn))
+        .filter(
+            (edges::peer1.eq(peer1).and(edges::peer2.eq(peer2)))
+                .or(edges::peer1.eq(peer2).and(edges::peer2.eq(peer1))),
+        )
+        .count()
+        .get_result(conn)?;
+    Ok(count > 0)
+}
+
+/// link two peers, for VPNs with the custom topology
+pub fn link_peers(conn: &SqliteConnection, vpn: &str, peer1: &str, peer2: &str) -> Result<()> {
+    if peer1 == peer2 {
+        return Err(CommandError::SelfLink(peer1.into()));
+    }
+    conn.transaction(|| {
+        get_peer(conn, vpn, peer1)?;
+        get_peer(conn, vpn, peer2)?;
+        if linked(conn, vpn, peer1, peer2)? {
+            return Err(CommandError::AlreadyLinked {
+                peer1: peer1.into(),
+                peer2: peer2.into(),
+            });
+        }
+        diesel::insert_into(edges::table)
+            .values((
+                edges::vpn.eq(vpn),
+                edges::peer1.eq(peer1),
+                edges::peer2.eq(peer2),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// remove the link between two peers, in whichever order they were linked
+pub fn unlink_peers(conn: &SqliteConnection, vpn: &str, peer1: &str, peer2: &str) -> Result<()> {
+    let deleted = diesel::delete(
+        edges::table.filter(edges::vpn.eq(vpn)).filter(
+            (edges::peer1.eq(peer1).and(edges::peer2.eq(peer2)))
+                .or(edges::peer1.eq(peer2).and(edges::peer2.eq(peer1))),
+        ),
+    )
+    .execute(conn)?;
+    if deleted == 0 {
+        return Err(CommandError::NotLinked {
+            peer1: peer1.into(),
+            peer2: peer2.into(),
+        });
+    }
+    Ok(())
+}
+
+/// the peers `vpn export` writes: the active ones with all the `tags`, or all of them, leaving
+/// out those that expired
+pub fn exported_peers(conn: &SqliteConnection, vpn: &str, tags: &[String]) -> Result<Vec<Peer>> {
+    let now = expiry::now();
+    Ok(list_peers(conn, vpn, tags)?
+        .into_iter()
+        .filter(|p| p.status == PeerStatus::Active && !p.expired(&now))
+        .collect())
+}
+
+/// the wg-quick configuration of a peer
+pub fn render_peer_config(conn: &SqliteConnection, vpn: &str, name: &str) -> Result<String> {
+    get_peer(conn, vpn, name)?;
+    let node = export::Node::load(conn, vpn, name)?;
+    Ok(export::wgquick::config(&node)?.to_string())
+}
+
+/// the forwarding and NAT rules of a server peer
+pub fn render_peer_firewall(
+    conn: &SqliteConnection,
+    vpn: &str,
+    name: &str,
+    format: FirewallFormat,
+) -> Result<Vec<File>> {
+    get_peer(conn, vpn, name)?;
+    let node = export::Node::load(conn, vpn, name)?;
+    Ok(export::firewall::files(&node, format)?)
+}
+
+pub fn get_acl(conn: &SqliteConnection, id: i32) -> Result<Acl> {
+    acls::table
+        .find(id)
+        .first::<Acl>(conn)
+        .optional()?
+        .ok_or(CommandError::AclNotFound(id))
+}
+
+/// the access rules of a VPN, in the order they are checked
+pub fn list_acls(conn: &SqliteConnection, vpn: &str) -> Result<Vec<Acl>> {
+    get_vpn(conn, vpn)?;
+    Ok(acls::table
+        .filter(acls::vpn.eq(vpn))
+        .order(acls::id)
+        .load(conn)?)
+}
+
+/// fail for a port without a protocol that has ports
+fn check_acl_port(protocol: Option<AclProtocol>, port: Option<i32>) -> Result<()> {
+    match (protocol, port) {
+        (Some(p), Some(_)) if p.has_ports() => Ok(()),
+        (_, Some(_)) => Err(CommandError::PortWithoutProtocol),
+        (_, None) => Ok(()),
+    }
+}
+
+/// add an access rule to a VPN, checked after its existing ones
+pub fn add_acl(conn: &SqliteConnection, acl: &NewAcl) -> Result<Acl> {
+    check_acl_port(acl.protocol, acl.port)?;
+    conn.transaction(|| {
+        // the foreign keys would only say that a constraint failed
+        get_vpn(conn, &acl.vpn)?;
+        for peer in acl.src_peer.iter().chain(acl.dst_peer.iter()) {
+            get_peer(conn, &acl.vpn, peer)?;
+        }
+        diesel::insert_into(acls::table).values(acl).execute(conn)?;
+        Ok(acls::table.order(acls::id.desc()).first(conn)?)
+    })
+}
+
+/// change the action, protocol or port of an access rule
+pub fn update_acl(conn: &SqliteConnection, id: i32, changes: &AclChanges) -> Result<Acl> {
+    conn.transaction(|| {
+        let rule = get_acl(conn, id)?;
+        check_acl_port(
+            changes.protocol.unwrap_or(rule.protocol),
+            changes.port.unwrap_or(rule.port),
+        )?;
+        // diesel refuses an update without columns
+        if changes.action.is_none() && changes.protocol.is_none() && changes.port.is_none() {
+            return Ok(rule);
+        }
+        diesel::update(acls::table.find(id))
+            .set(changes)
+            .execute(conn)?;
+        get_acl(conn, id)
+    })
+}
+
+pub fn remove_acl(conn: &SqliteConnection, id: i32) -> Result<()> {
+    match diesel::delete(acls::table.find(id)).execute(conn)? {
+        0 => Err(CommandError::AclNotFound(id)),
+        _ => Ok(()),
+    }
+}
@@ -2,11 +2,24 @@ use clap::Parser;
 
 /// Manage wireguard secrets and peers
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "The database password is read from DATABASE_PASSWORD, or asked for when it \
+                  is not set. Like DATABASE_PATH, it can also come from a .env file."
+)]
 pub struct Cli {
     /// encrypted database file
     #[clap(short, long, parse(from_os_str), env = "DATABASE_PATH")]
     pub database_path: std::path::PathBuf,
+    /// save the database after running a command given on the commandline
+    #[clap(long)]
+    pub save: bool,
+    /// run a single command instead of starting the shell, exiting non-zero if it fails
+    #[clap(subcommand)]
+    pub command: Option<crate::commands::Commands>,
 }
 
 #[derive(Parser, Debug)]
@@ -0,0 +1,492 @@
+//! Audit of the keys stored in the database, and consistency checks SQL constraints miss
+use crate::endpoint::Endpoint;
+use crate::expiry;
+use crate::keys;
+use crate::models;
+use crate::schema::{allowed_ips, networks, peers, preshared_keys, vpns};
+use crate::types::PeerStatus;
+use crate::wgconf::parse_net;
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// a problem with a row of the database
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    /// `network`, `vpn`, `vpn/peer` or `vpn/peer1+peer2` for preshared keys
+    pub subject: String,
+    pub message: String,
+    /// command that would solve the problem, `<...>` for values to choose
+    pub fix: Option<String>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.subject, self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n  fix: {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+fn finding(severity: Severity, subject: String, message: String, fix: Option<String>) -> Finding {
+    Finding {
+        severity,
+        subject,
+        message,
+        fix,
+    }
+}
+
+/// the age of a key against the policy, `None` if it is recent enough
+fn check_age(
+    created_at: &Option<String>,
+    oldest: &str,
+    max_age: &str,
+) -> Option<(Severity, String)> {
+    match created_at {
+        None => Some((
+            Severity::Warning,
+            String::from("created at an unknown time, rotate it to track its age"),
+        )),
+        Some(at) if at.as_str() < oldest => Some((
+            Severity::Error,
+            format!("created on {}, older than {}", at, max_age),
+        )),
+        Some(_) => None,
+    }
+}
+
+/// keys of the peers and preshared keys of `vpn`, or of all the VPNs, older than `max_age`
+/// seconds (written as `max_age_label`), malformed, weak, duplicated or not matching their
+/// private key
+pub fn keys(
+    conn: &SqliteConnection,
+    vpn: Option<&str>,
+    max_age: i64,
+    max_age_label: &str,
+) -> QueryResult<Vec<Finding>> {
+    let oldest = expiry::after(-max_age);
+    let mut query = peers::table.into_boxed();
+    if let Some(vpn) = vpn {
+        query = query.filter(peers::vpn_name.eq(vpn));
+    }
+    let all = query
+        .order((peers::vpn_name, peers::name))
+        .load::<models::Peer>(conn)?;
+    let mut psk_query = preshared_keys::table.into_boxed();
+    if let Some(vpn) = vpn {
+        psk_query = psk_query.filter(preshared_keys::vpn.eq(vpn));
+    }
+    let psks = psk_query
+        .order((
+            preshared_keys::vpn,
+            preshared_keys::peer1,
+            preshared_keys::peer2,
+        ))
+        .load::<models::PresharedKey>(conn)?;
+
+    let mut findings = vec![];
+    let mut by_key: BTreeMap<&str, Vec<&models::Peer>> = BTreeMap::new();
+    for peer in &all {
+        let subject = format!("{}/{}", peer.vpn_name, peer.name);
+        let rotate = Some(format!("peer rotate-keys {} {}", peer.vpn_name, peer.name));
+        by_key
+            .entry(peer.public_key.as_str())
+            .or_default()
+            .push(peer);
+        let mut problems = vec![];
+        match keys::is_low_order(&peer.public_key) {
+            Err(e) => problems.push(e.to_string()),
+            Ok(true) => problems.push(String::from("weak public key, a low order point")),
+            Ok(false) => {}
+        }
+        if let Some(private_key) = &peer.private_key {
+            match keys::public_key(private_key) {
+                Err(e) => problems.push(e.to_string()),
+                Ok(derived) if derived != peer.public_key.trim() => problems.push(String::from(
+                    "the public key does not match the private key",
+                )),
+                Ok(_) => {}
+            }
+        }
+        for problem in problems {
+            findings.push(finding(
+                Severity::Error,
+                subject.clone(),
+                problem,
+                rotate.clone(),
+            ));
+        }
+        if let Some((severity, age)) = check_age(&peer.key_created_at, &oldest, max_age_label) {
+            findings.push(finding(
+                severity,
+                subject,
+                format!("key pair {}", age),
+                rotate,
+            ));
+        }
+    }
+    for (_, owners) in by_key.iter().filter(|(_, o)| o.len() > 1) {
+        for owner in owners {
+            let others: Vec<String> = owners
+                .iter()
+                .filter(|o| *o != owner)
+                .map(|o| format!("{}/{}", o.vpn_name, o.name))
+                .collect();
+            findings.push(finding(
+                Severity::Error,
+                format!("{}/{}", owner.vpn_name, owner.name),
+                format!("same public key as {}", others.join(", ")),
+                Some(format!(
+                    "peer rotate-keys {} {}",
+                    owner.vpn_name, owner.name
+                )),
+            ));
+        }
+    }
+
+    let mut by_psk: BTreeMap<&str, Vec<&models::PresharedKey>> = BTreeMap::new();
+    for psk in &psks {
+        let subject = format!("{}/{}+{}", psk.vpn, psk.peer1, psk.peer2);
+        let rotate = Some(format!("vpn rotate-keys {}", psk.vpn));
+        by_psk.entry(psk.key.as_str()).or_default().push(psk);
+        if let Err(e) = keys::validate(&psk.key) {
+            findings.push(finding(
+                Severity::Error,
+                subject.clone(),
+                e.to_string(),
+                rotate.clone(),
+            ));
+        }
+        if let Some((severity, age)) = check_age(&psk.created_at, &oldest, max_age_label) {
+            findings.push(finding(
+                severity,
+                subject,
+                format!("preshared key {}", age),
+                rotate,
+            ));
+        }
+    }
+    for (_, pairs) in by_psk.iter().filter(|(_, p)| p.len() > 1) {
+        for pair in pairs {
+            findings.push(finding(
+                Severity::Error,
+                format!("{}/{}+{}", pair.vpn, pair.peer1, pair.peer2),
+                format!("preshared key shared by {} pairs of peers", pairs.len()),
+                Some(format!("vpn rotate-keys {}", pair.vpn)),
+            ));
+        }
+    }
+    Ok(findings)
+}
+
+// the typed address columns of the models can't load the invalid rows `check` reports
+
+#[derive(Queryable)]
+struct RawNetwork {
+    name: String,
+    address_v4: String,
+    address_v6: String,
+}
+
+#[derive(Queryable)]
+struct RawVpn {
+    name: String,
+    network_name: String,
+    address_v4: String,
+    address_v6: String,
+}
+
+#[derive(Queryable)]
+struct RawPeer {
+    vpn_name: String,
+    name: String,
+    address_v4: String,
+    address_v6: String,
+    status: PeerStatus,
+    endpoint_host: Option<String>,
+    endpoint_port: Option<i32>,
+}
+
+fn overlap(a: &IpNet, b: &IpNet) -> bool {
+    a.contains(b) || b.contains(a)
+}
+
+/// every row of the database against the rules SQL can't express: addresses that parse, VPN
+/// subnets inside their network and apart from each other, peer addresses inside their VPN,
+/// allowed IPs of active peers that don't collide, well formed endpoints and preshared keys
+/// of active peers
+pub fn check(conn: &SqliteConnection) -> QueryResult<Vec<Finding>> {
+    let mut findings = vec![];
+    let mut error = |subject: String, message: String, fix: Option<String>| {
+        findings.push(finding(Severity::Error, subject, message, fix))
+    };
+
+    let mut network_subnets = BTreeMap::new();
+    for network in networks::table
+        .select((networks::name, networks::address_v4, networks::address_v6))
+        .order(networks::name)
+        .load::<RawNetwork>(conn)?
+    {
+        let v4 = network.address_v4.parse::<Ipv4Net>();
+        let v6 = network.address_v6.parse::<Ipv6Net>();
+        if v4.is_err() {
+            error(
+                network.name.clone(),
+                format!("invalid ipv4 network `{}`", network.address_v4),
+                Some(format!("network update {} -4 <network>", network.name)),
+            );
+        }
+        if v6.is_err() {
+            error(
+                network.name.clone(),
+                format!("invalid ipv6 network `{}`", network.address_v6),
+                Some(format!("network update {} -6 <network>", network.name)),
+            );
+        }
+        network_subnets.insert(network.name, (v4.ok(), v6.ok()));
+    }
+
+    let vpns = vpns::table
+        .select((
+            vpns::name,
+            vpns::network_name,
+            vpns::address_v4,
+            vpns::address_v6,
+        ))
+        .order(vpns::name)
+        .load::<RawVpn>(conn)?;
+    let mut vpn_subnets: BTreeMap<&str, (Option<Ipv4Net>, Option<Ipv6Net>)> = BTreeMap::new();
+    for vpn in &vpns {
+        let v4 = vpn.address_v4.parse::<Ipv4Net>().ok();
+        let v6 = vpn.address_v6.parse::<Ipv6Net>().ok();
+        let (net_v4, net_v6) = network_subnets
+            .get(&vpn.network_name)
+            .copied()
+            .unwrap_or((None, None));
+        match (v4, net_v4) {
+            (None, _) => error(
+                vpn.name.clone(),
+                format!("invalid ipv4 subnet `{}`", vpn.address_v4),
+                Some(format!("vpn update {} -4 <subnet>", vpn.name)),
+            ),
+            (Some(subnet), Some(net)) if !net.contains(&subnet) => error(
+                vpn.name.clone(),
+                format!(
+                    "ipv4 subnet {} is outside of network {} ({})",
+                    subnet, vpn.network_name, net
+                ),
+                Some(format!("vpn update {} -4 <subnet of {}>", vpn.name, net)),
+            ),
+            _ => {}
+        }
+        match (v6, net_v6) {
+            (None, _) => error(
+                vpn.name.clone(),
+                format!("invalid ipv6 subnet `{}`", vpn.address_v6),
+                Some(format!("vpn update {} -6 <subnet>", vpn.name)),
+            ),
+            (Some(subnet), Some(net)) if !net.contains(&subnet) => error(
+                vpn.name.clone(),
+                format!(
+                    "ipv6 subnet {} is outside of network {} ({})",
+                    subnet, vpn.network_name, net
+                ),
+                Some(format!("vpn update {} -6 <subnet of {}>", vpn.name, net)),
+            ),
+            _ => {}
+        }
+        for (other, (other_v4, other_v6)) in &vpn_subnets {
+            let nets = [
+                (v4.map(IpNet::V4), other_v4.map(IpNet::V4), "-4"),
+                (v6.map(IpNet::V6), other_v6.map(IpNet::V6), "-6"),
+            ];
+            for (a, b, flag) in nets.iter() {
+                if let (Some(a), Some(b)) = (a, b) {
+                    if overlap(a, b) {
+                        error(
+                            vpn.name.clone(),
+                            format!("subnet {} overlaps {} of VPN {}", a, b, other),
+                            Some(format!("vpn update {} {} <subnet>", vpn.name, flag)),
+                        );
+                    }
+                }
+            }
+        }
+        vpn_subnets.insert(&vpn.name, (v4, v6));
+    }
+
+    let peers = peers::table
+        .select((
+            peers::vpn_name,
+            peers::name,
+            peers::address_v4,
+            peers::address_v6,
+            peers::status,
+            peers::endpoint_host,
+            peers::endpoint_port,
+        ))
+        .order((peers::vpn_name, peers::name))
+        .load::<RawPeer>(conn)?;
+    // the networks routed to each active peer. The allowed IPs include the addresses of the
+    // peers, copied there by a trigger
+    let mut routed: BTreeMap<(&str, &str), Vec<IpNet>> = BTreeMap::new();
+    for peer in &peers {
+        let subject = format!("{}/{}", peer.vpn_name, peer.name);
+        let (subnet_v4, subnet_v6) = vpn_subnets
+            .get(peer.vpn_name.as_str())
+            .copied()
+            .unwrap_or((None, None));
+        if peer.status == PeerStatus::Active {
+            routed.insert((peer.vpn_name.as_str(), peer.name.as_str()), vec![]);
+        }
+        match peer.address_v4.parse::<Ipv4Addr>() {
+            Err(_) => error(
+                subject.clone(),
+                format!("invalid ipv4 address `{}`", peer.address_v4),
+                Some(format!(
+                    "peer update {} {} -4 <address>",
+                    peer.vpn_name, peer.name
+                )),
+            ),
+            Ok(a) if subnet_v4.is_some_and(|s| !s.contains(&a)) => error(
+                subject.clone(),
+                format!("address {} is outside of the VPN subnet", a),
+                Some(format!(
+                    "peer update {} {} -4 <address>",
+                    peer.vpn_name, peer.name
+                )),
+            ),
+            Ok(_) => {}
+        }
+        match peer.address_v6.parse::<Ipv6Addr>() {
+            Err(_) => error(
+                subject.clone(),
+                format!("invalid ipv6 address `{}`", peer.address_v6),
+                Some(format!(
+                    "peer update {} {} -6 <address>",
+                    peer.vpn_name, peer.name
+                )),
+            ),
+            Ok(a) if subnet_v6.is_some_and(|s| !s.contains(&a)) => error(
+                subject.clone(),
+                format!("address {} is outside of the VPN subnet", a),
+                Some(format!(
+                    "peer update {} {} -6 <address>",
+                    peer.vpn_name, peer.name
+                )),
+            ),
+            Ok(_) => {}
+        }
+        let server = format!("peer server {} {} -e <host:port>", peer.vpn_name, peer.name);
+        match (&peer.endpoint_host, peer.endpoint_port) {
+            (None, None) => {}
+            (Some(host), Some(port)) => {
+                let valid = u16::try_from(port)
+                    .ok()
+                    .and_then(|p| Endpoint::new(host, p).ok());
+                if valid.is_none() {
+                    error(
+                        subject.clone(),
+                        format!("invalid endpoint {}:{}", host, port),
+                        Some(server),
+                    );
+                }
+            }
+            _ => error(
+                subject.clone(),
+                String::from("endpoint with only one of host and port"),
+                Some(server),
+            ),
+        }
+    }
+
+    let ips = allowed_ips::table
+        .order((allowed_ips::peer_vpn, allowed_ips::peer_name))
+        .load::<models::AllowedIp>(conn)?;
+    for ip in &ips {
+        let nets = routed.get_mut(&(ip.peer_vpn.as_str(), ip.peer_name.as_str()));
+        match (parse_net(&ip.address), nets) {
+            (Some(net), Some(nets)) => nets.push(net),
+            // already reported as an invalid address of the peer
+            (None, _)
+                if peers.iter().any(|p| {
+                    p.vpn_name == ip.peer_vpn
+                        && p.name == ip.peer_name
+                        && (p.address_v4 == ip.address || p.address_v6 == ip.address)
+                }) => {}
+            (None, _) => error(
+                format!("{}/{}", ip.peer_vpn, ip.peer_name),
+                format!("invalid allowed IP `{}`", ip.address),
+                None,
+            ),
+            (Some(_), None) => {}
+        }
+    }
+    let routed: Vec<_> = routed.into_iter().collect();
+    for (i, ((vpn, peer), nets)) in routed.iter().enumerate() {
+        for ((other_vpn, other), other_nets) in &routed[i + 1..] {
+            if vpn != other_vpn {
+                continue;
+            }
+            for net in nets {
+                for other_net in other_nets.iter().filter(|o| overlap(net, o)) {
+                    error(
+                        format!("{}/{}", vpn, peer),
+                        format!("{} collides with {} of peer {}", net, other_net, other),
+                        Some(format!("peer update {} {} --status disabled", vpn, other)),
+                    );
+                }
+            }
+        }
+    }
+
+    let inactive: BTreeMap<(&str, &str), PeerStatus> = peers
+        .iter()
+        .filter(|p| p.status != PeerStatus::Active)
+        .map(|p| ((p.vpn_name.as_str(), p.name.as_str()), p.status))
+        .collect();
+    for psk in preshared_keys::table
+        .order((
+            preshared_keys::vpn,
+            preshared_keys::peer1,
+            preshared_keys::peer2,
+        ))
+        .load::<models::PresharedKey>(conn)?
+    {
+        for peer in [&psk.peer1, &psk.peer2].iter() {
+            if let Some(status) = inactive.get(&(psk.vpn.as_str(), peer.as_str())) {
+                findings.push(finding(
+                    Severity::Warning,
+                    format!("{}/{}+{}", psk.vpn, psk.peer1, psk.peer2),
+                    format!("preshared key of peer {}, which is {}", peer, status),
+                    Some(format!("peer update {} {} --status active", psk.vpn, peer)),
+                ));
+            }
+        }
+    }
+    Ok(findings)
+}
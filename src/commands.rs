@@ -1,10 +1,25 @@
+use crate::api;
+use crate::audit;
+use crate::drift;
+use crate::endpoint::Endpoint;
+use crate::expiry;
+use crate::export;
+use crate::import;
 use crate::models;
 use crate::schema;
+use crate::types::{
+    AclAction, AclProtocol, FirewallFormat, Ipv4Address, Ipv4Network, Ipv6Address, Ipv6Network,
+    PeerStatus, Topology, TunnelMode,
+};
+use crate::wgconf;
+use crate::wgdump;
 
-use anyhow::Result;
-use clap::{ArgEnum, Subcommand};
+use anyhow::{Context, Result};
+use clap::{ArgEnum, Args, Subcommand};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
-use diesel::RunQueryDsl;
+use std::io::Read;
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
@@ -23,6 +38,19 @@ pub enum Commands {
         #[clap(subcommand)]
         command: Peer,
     },
+    /// Manage the peer to peer access rules of VPNs
+    Acl {
+        #[clap(subcommand)]
+        command: Acl,
+    },
+    /// Check every row of the database for problems the database itself doesn't catch, and
+    /// suggest how to fix them. Fails if any error is found
+    Check,
+    /// Check the database against security policies
+    Audit {
+        #[clap(subcommand)]
+        command: Audit,
+    },
     /// Save the database
     Save,
     /// Quit the application
@@ -44,6 +72,27 @@ impl Commands {
             Commands::Network { command } => command.dispatch(conn),
             Commands::Vpn { command } => command.dispatch(conn),
             Commands::Peer { command } => command.dispatch(conn),
+            Commands::Acl { command } => command.dispatch(conn),
+            Commands::Audit { command } => command.dispatch(conn),
+            Commands::Check => {
+                let findings = audit::check(&conn)?;
+                for finding in &findings {
+                    println!("{}", finding);
+                }
+                let errors = findings
+                    .iter()
+                    .filter(|f| f.severity == audit::Severity::Error)
+                    .count();
+                if errors > 0 {
+                    return Err(anyhow::anyhow!(
+                        "{} errors and {} warnings found",
+                        errors,
+                        findings.len() - errors
+                    ));
+                }
+                println!("{} warnings found", findings.len());
+                Ok(true)
+            }
         }
     }
 }
@@ -62,6 +111,12 @@ pub enum Network {
         /// Ipv6 Network from where to create subnets for vpns
         #[clap(long, short = '6')]
         ipv6: ipnet::Ipv6Net,
+        /// Prefix length of the ipv4 subnets assigned to new vpns [default: 24]
+        #[clap(long)]
+        vpn_prefix_v4: Option<u8>,
+        /// Prefix length of the ipv6 subnets assigned to new vpns [default: 64]
+        #[clap(long)]
+        vpn_prefix_v6: Option<u8>,
     },
     /// Remove a network
     Remove { name: String },
@@ -78,18 +133,74 @@ pub enum Network {
         /// Ipv6 Network from where to create subnets for vpns
         #[clap(long, short = '6')]
         ipv6: Option<ipnet::Ipv6Net>,
+        /// Prefix length of the ipv4 subnets assigned to new vpns
+        #[clap(long)]
+        vpn_prefix_v4: Option<u8>,
+        /// Prefix length of the ipv6 subnets assigned to new vpns
+        #[clap(long)]
+        vpn_prefix_v6: Option<u8>,
     },
 }
 
 impl Network {
     fn dispatch(&self, conn: SqliteConnection) -> Result<bool> {
-        use schema::networks::dsl::*;
         match self {
             Network::List {} => {
-                networks.load::<models::Network>(&conn)?;
+                for network in api::list_networks(&conn)? {
+                    println!(
+                        "{}: {}, {} (vpns /{}, /{})",
+                        network.name,
+                        network.address_v4,
+                        network.address_v6,
+                        network.vpn_prefix_v4,
+                        network.vpn_prefix_v6
+                    );
+                }
+                Ok(true)
+            }
+            Network::Add {
+                name,
+                ipv4,
+                ipv6,
+                vpn_prefix_v4,
+                vpn_prefix_v6,
+            } => {
+                api::create_network(&conn, name, *ipv4, *ipv6, *vpn_prefix_v4, *vpn_prefix_v6)?;
+                Ok(true)
+            }
+            Network::Remove { name } => {
+                api::remove_network(&conn, name)?;
+                Ok(true)
+            }
+            Network::Update {
+                name,
+                new_name,
+                ipv4,
+                ipv6,
+                vpn_prefix_v4,
+                vpn_prefix_v6,
+            } => {
+                if new_name.is_none()
+                    && ipv4.is_none()
+                    && ipv6.is_none()
+                    && vpn_prefix_v4.is_none()
+                    && vpn_prefix_v6.is_none()
+                {
+                    return Err(anyhow::anyhow!("nothing to update"));
+                }
+                api::update_network(
+                    &conn,
+                    name,
+                    api::NetworkChanges {
+                        name: new_name.clone(),
+                        address_v4: ipv4.map(Ipv4Network),
+                        address_v6: ipv6.map(Ipv6Network),
+                        vpn_prefix_v4: vpn_prefix_v4.map(i32::from),
+                        vpn_prefix_v6: vpn_prefix_v6.map(i32::from),
+                    },
+                )?;
                 Ok(true)
             }
-            _ => Err(anyhow::anyhow!("not implemented")),
         }
     }
 }
@@ -101,8 +212,8 @@ pub enum Vpn {
         /// Restrict to a specific network
         network: Option<String>,
     },
-    /// Add a new VPN. A new ipv4 (/24) and ipv6 (/16) subnet will be assigned automatically if
-    /// not set
+    /// Add a new VPN. The first free ipv4 and ipv6 subnets with the prefix lengths of the network
+    /// will be assigned automatically if not set
     Add {
         /// name of (existing) network
         network: String,
@@ -131,18 +242,671 @@ pub enum Vpn {
         #[clap(long, short = '6')]
         ipv6: Option<ipnet::Ipv6Net>,
     },
+    /// Import wg-quick configuration files into an existing VPN. Peers are matched by public key
+    /// across files and with the peers already in the VPN
+    ImportConf {
+        /// name of (existing) vpn
+        vpn: String,
+        /// wg-quick configuration files, e.g. /etc/wireguard/wg0.conf
+        #[clap(required = true, parse(from_os_str))]
+        files: Vec<std::path::PathBuf>,
+    },
+    /// Compare the output of `wg show <iface> dump` with the peers of a VPN, and optionally add
+    /// the peers that are not in the database yet. Their endpoints are not imported
+    ImportDump {
+        /// name of (existing) vpn
+        vpn: String,
+        /// file containing the dump, or `-` to read it from stdin
+        #[clap(default_value = "-", parse(from_os_str))]
+        file: std::path::PathBuf,
+        /// interface to use when the dump comes from `wg show all dump`
+        #[clap(short, long)]
+        interface: Option<String>,
+        /// add the missing peers without asking
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Set which peers of a VPN talk to each other
+    Topology {
+        /// name of (existing) vpn
+        vpn: String,
+        /// mesh: every peer with every other. hub: spokes only talk to the hub peers.
+        /// custom: only peers linked with `peer link`
+        #[clap(arg_enum)]
+        topology: Topology,
+    },
+    /// Show or change the wg-quick interface settings of the peers of a VPN
+    Settings {
+        /// name of (existing) vpn
+        vpn: String,
+        #[clap(flatten)]
+        settings: InterfaceSettings,
+    },
+    /// Export the configuration of all the active peers of a VPN, one directory per peer
+    Export {
+        /// name of (existing) vpn
+        vpn: String,
+        /// output format
+        #[clap(short, long, default_value_t = ExportFormat::WgQuick, arg_enum)]
+        format: ExportFormat,
+        /// template file, or name of a bundled template, for the template format
+        #[clap(short, long, required_if_eq("format", "template"))]
+        template: Option<String>,
+        /// directory where to create the peer directories
+        #[clap(short, long, parse(from_os_str))]
+        output_dir: std::path::PathBuf,
+        /// only the peers with all these tags
+        #[clap(long)]
+        tag: Vec<String>,
+        /// list the peers rotated in their grace period with their old key, until they confirm
+        /// the new one
+        #[clap(long)]
+        retired_keys: bool,
+    },
+    /// Give every peer of a VPN a new key pair and replace all its preshared keys, e.g. after
+    /// a suspected compromise
+    RotateKeys {
+        /// name of (existing) vpn
+        vpn: String,
+        /// how long the exports can still list the old public keys, e.g. 1d
+        #[clap(short, long, parse(try_from_str = expiry::parse_duration))]
+        grace: Option<i64>,
+    },
+}
+
+/// write the files in `dir`, or print them
+fn output_files(files: &[export::File], dir: Option<&std::path::Path>) -> Result<()> {
+    match dir {
+        Some(dir) => export::write(files, dir)?,
+        // a single file is printed as is, so that it can be redirected
+        None if files.len() == 1 => print!("{}", files[0].contents),
+        None => {
+            for file in files {
+                println!("==> {} <==", file.name);
+                println!("{}", file.contents);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_import_summary(summary: &import::Summary) {
+    for warning in &summary.warnings {
+        println!("Warning: {}", warning);
+    }
+    println!(
+        "Added {} peers ({}), updated {}, {} allowed ips, {} preshared keys",
+        summary.added.len(),
+        summary.added.join(", "),
+        summary.updated.len(),
+        summary.allowed_ips,
+        summary.preshared_keys
+    );
 }
 
 impl Vpn {
-    fn dispatch(&self, _conn: SqliteConnection) -> Result<bool> {
-        Err(anyhow::anyhow!("not implemented"))
+    fn dispatch(&self, conn: SqliteConnection) -> Result<bool> {
+        match self {
+            Vpn::ImportConf { vpn, files } => {
+                let sources = files
+                    .iter()
+                    .map(|path| {
+                        let content = std::fs::read_to_string(path)
+                            .with_context(|| format!("cannot read {}", path.display()))?;
+                        let config = wgconf::Config::parse(&content)
+                            .with_context(|| format!("cannot parse {}", path.display()))?;
+                        let name = path
+                            .file_stem()
+                            .map(|s| s.to_string_lossy().into_owned())
+                            .unwrap_or_else(|| path.display().to_string());
+                        Ok(import::Source { name, config })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let summary = import::import_configs(&conn, vpn, &sources)?;
+                print_import_summary(&summary);
+                Ok(true)
+            }
+            Vpn::Topology { vpn, topology } => {
+                api::set_vpn_topology(&conn, vpn, *topology)?;
+                Ok(true)
+            }
+            Vpn::Settings { vpn, settings } => {
+                if settings.is_empty() {
+                    let current = api::get_vpn(&conn, vpn)?;
+                    print_settings(&[
+                        ("MTU", current.mtu.map(|v| v.to_string())),
+                        ("Table", current.route_table),
+                        ("FwMark", current.fwmark.map(|v| v.to_string())),
+                        ("PreUp", current.pre_up),
+                        ("PostUp", current.post_up),
+                        ("PreDown", current.pre_down),
+                        ("PostDown", current.post_down),
+                    ]);
+                    return Ok(true);
+                }
+                api::update_vpn_settings(&conn, vpn, &settings.changes())?;
+                Ok(true)
+            }
+            Vpn::Export {
+                vpn,
+                format,
+                template,
+                output_dir,
+                tag,
+                retired_keys,
+            } => {
+                for peer in api::exported_peers(&conn, vpn, tag)? {
+                    let mut node = export::Node::load(&conn, vpn, &peer.name)?;
+                    if *retired_keys {
+                        node.add_retired_keys(&conn)?;
+                    }
+                    export::write(
+                        &format.render(&node, template.as_deref())?,
+                        &output_dir.join(&peer.name),
+                    )?;
+                }
+                Ok(true)
+            }
+            Vpn::RotateKeys { vpn, grace } => {
+                let rotation = api::rotate_vpn_keys(&conn, vpn, grace.unwrap_or(0))?;
+                println!(
+                    "Rotated the keys of {} peers and {} preshared keys, export all the peers again",
+                    rotation.peers.len(),
+                    rotation.preshared_keys
+                );
+                Ok(true)
+            }
+            Vpn::ImportDump {
+                vpn,
+                file,
+                interface,
+                yes,
+            } => {
+                let content = match file.to_str() {
+                    Some("-") => {
+                        let mut buffer = String::new();
+                        std::io::stdin().read_to_string(&mut buffer)?;
+                        buffer
+                    }
+                    _ => std::fs::read_to_string(file)
+                        .with_context(|| format!("cannot read {}", file.display()))?,
+                };
+                let dump = wgdump::Dump::parse(&content).context("cannot parse dump")?;
+                let iface = dump.interface(interface.as_deref())?;
+                let result = import::reconcile(&conn, vpn, &iface.public_key, &iface.peers)?;
+                for name in &result.missing {
+                    println!("Peer {} is not configured on the interface", name);
+                }
+                for key in &result.unknown {
+                    println!("Public key {} is not in the database", key);
+                }
+                if result.unknown.is_empty() {
+                    println!("All the public keys on the interface are in the database");
+                    return Ok(true);
+                }
+                let confirmed = *yes
+                    || Confirm::with_theme(&ColorfulTheme::default())
+                        .with_prompt(format!(
+                            "Add {} missing peers to {}?",
+                            result.unknown.len(),
+                            vpn
+                        ))
+                        .interact()?;
+                if !confirmed {
+                    return Ok(true);
+                }
+                let name = iface.name.clone().unwrap_or_else(|| String::from("dump"));
+                let interface_key = iface.public_key.clone();
+                let private_key_known = iface.private_key.is_some();
+                let mut config = iface.into_config();
+                config
+                    .peers
+                    .retain(|p| result.unknown.contains(&p.public_key));
+                for peer in &mut config.peers {
+                    // the endpoint of a peer in a dump is the address it was last seen from,
+                    // clients would be pinned to a stale NAT address
+                    peer.endpoint = None;
+                }
+                // without its private key the interface is only known by its public key, like
+                // its peers
+                if !private_key_known && result.unknown.contains(&interface_key) {
+                    config.peers.push(wgconf::Peer {
+                        public_key: interface_key,
+                        ..Default::default()
+                    });
+                }
+                let summary =
+                    import::import_configs(&conn, vpn, &[import::Source { name, config }])?;
+                print_import_summary(&summary);
+                Ok(true)
+            }
+            Vpn::List { network } => {
+                for vpn in api::list_vpns(&conn, network.as_deref())? {
+                    println!(
+                        "{}: {}, {}, {}, {}",
+                        vpn.name, vpn.network_name, vpn.address_v4, vpn.address_v6, vpn.topology
+                    );
+                }
+                Ok(true)
+            }
+            Vpn::Add {
+                network,
+                name,
+                ipv4,
+                ipv6,
+            } => {
+                let vpn = api::add_vpn(&conn, network, name, *ipv4, *ipv6)?;
+                println!(
+                    "Added VPN {} ({}, {})",
+                    vpn.name, vpn.address_v4, vpn.address_v6
+                );
+                Ok(true)
+            }
+            Vpn::Remove { name } => {
+                api::remove_vpn(&conn, name)?;
+                Ok(true)
+            }
+            Vpn::Update {
+                name,
+                new_name,
+                ipv4,
+                ipv6,
+            } => {
+                if new_name.is_none() && ipv4.is_none() && ipv6.is_none() {
+                    return Err(anyhow::anyhow!("nothing to update"));
+                }
+                api::update_vpn(
+                    &conn,
+                    name,
+                    api::VpnChanges {
+                        name: new_name.clone(),
+                        address_v4: ipv4.map(Ipv4Network),
+                        address_v6: ipv6.map(Ipv6Network),
+                    },
+                )?;
+                Ok(true)
+            }
+        }
     }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
-pub enum PeerStatus {
-    Active,
-    Disabled,
+pub enum ExportFormat {
+    /// wg-quick configuration file
+    WgQuick,
+    /// systemd-networkd .netdev and .network files
+    Networkd,
+    /// NetworkManager keyfile, to install in /etc/NetworkManager/system-connections
+    Networkmanager,
+    /// OpenWrt `uci` commands
+    OpenwrtUci,
+    /// OpenWrt /etc/config/network fragment
+    OpenwrtConfig,
+    /// MikroTik RouterOS script
+    Mikrotik,
+    /// user supplied tera template, see --template
+    Template,
+}
+
+impl ExportFormat {
+    fn render(&self, node: &export::Node, template: Option<&str>) -> Result<Vec<export::File>> {
+        match self {
+            ExportFormat::WgQuick => export::wgquick::files(node),
+            ExportFormat::Networkd => export::networkd::files(node),
+            ExportFormat::Networkmanager => export::networkmanager::files(node),
+            ExportFormat::OpenwrtUci => export::openwrt::uci_files(node),
+            ExportFormat::OpenwrtConfig => export::openwrt::config_files(node),
+            ExportFormat::Mikrotik => export::mikrotik::files(node),
+            ExportFormat::Template => match template {
+                Some(template) => export::template::files(node, template),
+                None => Err(anyhow::anyhow!("the template format needs --template")),
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
+pub enum Setting {
+    Mtu,
+    Table,
+    Fwmark,
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+}
+
+/// wg-quick interface settings. Hooks are run in the order they are given
+#[derive(Args, Debug)]
+pub struct InterfaceSettings {
+    /// MTU of the interface
+    #[clap(long)]
+    mtu: Option<u16>,
+    /// routing table for the routes: off, auto, or a table number or name
+    #[clap(long)]
+    table: Option<String>,
+    /// firewall mark for the outgoing packets, in decimal or 0x hexadecimal
+    #[clap(long, parse(try_from_str = parse_fwmark))]
+    fwmark: Option<u32>,
+    /// command to run before bringing the interface up, replaces the existing ones
+    #[clap(long)]
+    pre_up: Vec<String>,
+    /// command to run after bringing the interface up, replaces the existing ones
+    #[clap(long)]
+    post_up: Vec<String>,
+    /// command to run before bringing the interface down, replaces the existing ones
+    #[clap(long)]
+    pre_down: Vec<String>,
+    /// command to run after bringing the interface down, replaces the existing ones
+    #[clap(long)]
+    post_down: Vec<String>,
+    /// clear a setting. Peers fall back to the setting of their VPN
+    #[clap(long, arg_enum)]
+    unset: Vec<Setting>,
+}
+
+fn parse_fwmark(value: &str) -> Result<u32> {
+    match wgconf::parse_fwmark(value) {
+        Some(0) => Err(anyhow::anyhow!("use --unset fwmark to remove the mark")),
+        Some(mark) => Ok(mark),
+        None => Err(anyhow::anyhow!("invalid fwmark {}", value)),
+    }
+}
+
+fn print_settings(settings: &[(&str, Option<String>)]) {
+    for (key, value) in settings {
+        for line in value.iter().flat_map(|v| v.lines()) {
+            println!("{} = {}", key, line);
+        }
+    }
+}
+
+impl InterfaceSettings {
+    fn is_empty(&self) -> bool {
+        self.mtu.is_none()
+            && self.table.is_none()
+            && self.fwmark.is_none()
+            && self.pre_up.is_empty()
+            && self.post_up.is_empty()
+            && self.pre_down.is_empty()
+            && self.post_down.is_empty()
+            && self.unset.is_empty()
+    }
+
+    fn changes(&self) -> api::VpnSettings {
+        let hooks = |hooks: &[String]| match hooks.is_empty() {
+            true => None,
+            false => Some(Some(hooks.join("\n"))),
+        };
+        let mut changes = api::VpnSettings {
+            mtu: self.mtu.map(|m| Some(i32::from(m))),
+            route_table: self.table.clone().map(Some),
+            fwmark: self.fwmark.map(|m| Some(i64::from(m))),
+            pre_up: hooks(&self.pre_up),
+            post_up: hooks(&self.post_up),
+            pre_down: hooks(&self.pre_down),
+            post_down: hooks(&self.post_down),
+        };
+        for setting in &self.unset {
+            match setting {
+                Setting::Mtu => changes.mtu = Some(None),
+                Setting::Table => changes.route_table = Some(None),
+                Setting::Fwmark => changes.fwmark = Some(None),
+                Setting::PreUp => changes.pre_up = Some(None),
+                Setting::PostUp => changes.post_up = Some(None),
+                Setting::PreDown => changes.pre_down = Some(None),
+                Setting::PostDown => changes.post_down = Some(None),
+            }
+        }
+        changes
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Audit {
+    /// List keys older than the rotation policy, weak, duplicated or not matching their
+    /// private key. Fails if any is found
+    Keys {
+        /// restrict to a specific vpn
+        vpn: Option<String>,
+        /// maximum age of keys, e.g. 180d
+        #[clap(short, long, default_value = "180d")]
+        max_age: String,
+        /// also fail for keys of unknown age
+        #[clap(long)]
+        strict: bool,
+    },
+}
+
+impl Audit {
+    fn dispatch(&self, conn: SqliteConnection) -> Result<bool> {
+        match self {
+            Audit::Keys {
+                vpn,
+                max_age,
+                strict,
+            } => {
+                let seconds = expiry::parse_duration(max_age)?;
+                let findings = audit::keys(&conn, vpn.as_deref(), seconds, max_age)?;
+                for finding in &findings {
+                    println!("{}", finding);
+                }
+                let failing = findings
+                    .iter()
+                    .filter(|f| *strict || f.severity == audit::Severity::Error)
+                    .count();
+                if failing > 0 {
+                    return Err(anyhow::anyhow!("{} key problems found", failing));
+                }
+                println!("No key problems found");
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Acl {
+    /// List the access rules of a VPN, in the order they are checked
+    List {
+        /// name of (existing) vpn
+        vpn: String,
+    },
+    /// Add an access rule, checked after the existing ones. The first matching rule wins
+    Add {
+        /// name of (existing) vpn
+        vpn: String,
+        #[clap(arg_enum)]
+        action: AclAction,
+        /// source peer, any peer if neither this nor --from-tag are set
+        #[clap(long, conflicts_with = "from-tag")]
+        from: Option<String>,
+        /// peers with this tag as the source
+        #[clap(long)]
+        from_tag: Option<String>,
+        /// destination peer, any peer if neither this nor --to-tag are set
+        #[clap(long, conflicts_with = "to-tag")]
+        to: Option<String>,
+        /// peers with this tag as the destination
+        #[clap(long)]
+        to_tag: Option<String>,
+        /// any protocol if not set
+        #[clap(short, long, arg_enum)]
+        protocol: Option<AclProtocol>,
+        /// destination port, for tcp and udp
+        #[clap(long, requires = "protocol")]
+        port: Option<u16>,
+    },
+    /// Change the action, protocol or port of an access rule
+    Update {
+        id: i32,
+        #[clap(short, long, arg_enum)]
+        action: Option<AclAction>,
+        #[clap(short, long, arg_enum, conflicts_with = "any-protocol")]
+        protocol: Option<AclProtocol>,
+        /// destination port, for tcp and udp
+        #[clap(long, conflicts_with = "any-port")]
+        port: Option<u16>,
+        /// match any protocol and port
+        #[clap(long)]
+        any_protocol: bool,
+        /// match any port
+        #[clap(long)]
+        any_port: bool,
+    },
+    /// Remove an access rule
+    Remove { id: i32 },
+}
+
+fn describe_selector(peer: &Option<String>, tag: &Option<String>) -> String {
+    match (peer, tag) {
+        (Some(peer), _) => format!("peer {}", peer),
+        (None, Some(tag)) => format!("tag {}", tag),
+        (None, None) => String::from("any peer"),
+    }
+}
+
+impl Acl {
+    fn dispatch(&self, conn: SqliteConnection) -> Result<bool> {
+        match self {
+            Acl::List { vpn } => {
+                for rule in api::list_acls(&conn, vpn)? {
+                    let protocol = match (rule.protocol, rule.port) {
+                        (Some(p), Some(port)) => format!("{}/{}", p, port),
+                        (Some(p), None) => p.to_string(),
+                        _ => String::from("any"),
+                    };
+                    println!(
+                        "{}: {} {} from {} to {}",
+                        rule.id,
+                        rule.action,
+                        protocol,
+                        describe_selector(&rule.src_peer, &rule.src_tag),
+                        describe_selector(&rule.dst_peer, &rule.dst_tag)
+                    );
+                }
+                Ok(true)
+            }
+            Acl::Add {
+                vpn,
+                action,
+                from,
+                from_tag,
+                to,
+                to_tag,
+                protocol,
+                port,
+            } => {
+                api::add_acl(
+                    &conn,
+                    &api::NewAcl {
+                        vpn: vpn.clone(),
+                        src_peer: from.clone(),
+                        src_tag: from_tag.clone(),
+                        dst_peer: to.clone(),
+                        dst_tag: to_tag.clone(),
+                        protocol: *protocol,
+                        port: port.map(i32::from),
+                        action: *action,
+                    },
+                )?;
+                Ok(true)
+            }
+            Acl::Update {
+                id,
+                action,
+                protocol,
+                port,
+                any_protocol,
+                any_port,
+            } => {
+                let protocol = match any_protocol {
+                    true => Some(None),
+                    false => protocol.map(Some),
+                };
+                // the port goes with the protocol
+                let port = match *any_port || *any_protocol {
+                    true => Some(None),
+                    false => port.map(|p| Some(i32::from(p))),
+                };
+                api::update_acl(
+                    &conn,
+                    *id,
+                    &api::AclChanges {
+                        action: *action,
+                        protocol,
+                        port,
+                    },
+                )?;
+                Ok(true)
+            }
+            Acl::Remove { id } => {
+                api::remove_acl(&conn, *id)?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Tag {
+    /// Add tags to a peer
+    Add {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// free form labels, matched as whole strings: `dept=eng` is one tag, not a key and
+        /// a value
+        #[clap(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from a peer
+    Remove {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        #[clap(required = true)]
+        tags: Vec<String>,
+    },
+}
+
+impl Tag {
+    fn dispatch(&self, conn: &SqliteConnection) -> Result<bool> {
+        match self {
+            Tag::Add { vpn, name, tags } => {
+                api::add_peer_tags(conn, vpn, name, tags)?;
+                Ok(true)
+            }
+            Tag::Remove { vpn, name, tags } => {
+                if api::remove_peer_tags(conn, vpn, name, tags)? == 0 {
+                    return Err(anyhow::anyhow!("Peer {} has none of these tags", name));
+                }
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// the expiry set by `--expires`, `--ttl` or `--no-expiry`, if any
+fn expiry_change(
+    expires: &Option<String>,
+    ttl: &Option<String>,
+    no_expiry: bool,
+) -> Option<Option<String>> {
+    match no_expiry {
+        true => Some(None),
+        false => expires.clone().or_else(|| ttl.clone()).map(Some),
+    }
+}
+
+/// names of the peers of `vpn` with all the `tags`, or all its peers without tags
+fn select_peers(conn: &SqliteConnection, vpn: &str, tags: &[String]) -> Result<Vec<String>> {
+    Ok(api::list_peers(conn, vpn, tags)?
+        .into_iter()
+        .map(|p| p.name)
+        .collect())
 }
 
 #[derive(Subcommand, Debug)]
@@ -151,6 +915,9 @@ pub enum Peer {
     List {
         /// name of the vpn
         vpn: String,
+        /// only the peers with all these tags
+        #[clap(short, long)]
+        tag: Vec<String>,
     },
     /// Add a new peer in a VPN
     Add {
@@ -158,15 +925,21 @@ pub enum Peer {
         vpn: String,
         /// new peer name
         name: String,
-        /// peer endpoint
+        /// peer endpoint, as host:port or [ipv6]:port
         #[clap(short, long)]
-        endpoint: Option<String>,
+        endpoint: Option<Endpoint>,
         /// dns for the peer
         #[clap(short, long)]
         dns: Option<String>,
         /// initial status of the peer
         #[clap(short, long, default_value_t = PeerStatus::Active, arg_enum)]
         status: PeerStatus,
+        /// treat the peer as disabled from this day (YYYY-MM-DD, UTC) on
+        #[clap(long, parse(try_from_str = expiry::parse_date))]
+        expires: Option<String>,
+        /// treat the peer as disabled after this time from now, e.g. 90d, 12h or 2w
+        #[clap(long, parse(try_from_str = expiry::parse_ttl), conflicts_with = "expires")]
+        ttl: Option<String>,
         /// set the public key for the peer
         #[clap(short, long)]
         pubkey: Option<String>,
@@ -187,23 +960,37 @@ pub enum Peer {
         /// new peer name
         name: String,
     },
+    /// Update a peer, or the status and dns of all the peers with some tags
     Update {
         /// vpn the new peer is part of
         vpn: String,
         /// peer name
-        name: String,
+        #[clap(required_unless_present = "tag")]
+        name: Option<String>,
+        /// update all the peers with all these tags instead of a single one
+        #[clap(short, long, conflicts_with = "name")]
+        tag: Vec<String>,
         /// new name for the peer
         #[clap(short, long)]
         new_name: Option<String>,
-        /// peer endpoint
+        /// peer endpoint, as host:port or [ipv6]:port
         #[clap(short, long)]
-        endpoint: Option<String>,
+        endpoint: Option<Endpoint>,
         /// dns for the peer
         #[clap(short, long)]
         dns: Option<String>,
         /// status of the peer
         #[clap(short, long, arg_enum)]
         status: Option<PeerStatus>,
+        /// treat the peer as disabled from this day (YYYY-MM-DD, UTC) on
+        #[clap(long, parse(try_from_str = expiry::parse_date))]
+        expires: Option<String>,
+        /// treat the peer as disabled after this time from now, e.g. 90d, 12h or 2w
+        #[clap(long, parse(try_from_str = expiry::parse_ttl), conflicts_with = "expires")]
+        ttl: Option<String>,
+        /// remove the expiry date
+        #[clap(long, conflicts_with_all = &["expires", "ttl"])]
+        no_expiry: bool,
         /// set the public key for the peer
         #[clap(short, long)]
         pubkey: Option<String>,
@@ -217,10 +1004,521 @@ pub enum Peer {
         #[clap(long, short = '6')]
         ipv6: Option<std::net::Ipv6Addr>,
     },
+    /// Compare the configuration of a peer with the one running on the node. Fails if they differ
+    Diff {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// output of `wg showconf <iface>` or `wg show <iface> dump` taken on the node
+        #[clap(short, long, parse(from_os_str))]
+        live: std::path::PathBuf,
+    },
+    /// Export the configuration of a peer
+    Export {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// output format
+        #[clap(short, long, default_value_t = ExportFormat::WgQuick, arg_enum)]
+        format: ExportFormat,
+        /// template file, or name of a bundled template, for the template format
+        #[clap(short, long, required_if_eq("format", "template"))]
+        template: Option<String>,
+        /// write the files in this directory instead of printing them
+        #[clap(short, long, parse(from_os_str))]
+        output_dir: Option<std::path::PathBuf>,
+        /// list the peers rotated in their grace period with their old key, until they confirm
+        /// the new one
+        #[clap(long)]
+        retired_keys: bool,
+    },
+    /// Give a peer a new key pair, keeping its old public key in the history
+    ///
+    /// Until the grace period ends, exports with --retired-keys list the peer with its old key
+    /// instead of the new one, not with both: WireGuard routes AllowedIPs to a single peer, so
+    /// two [Peer] sections with the same addresses can't both work. Deploy the new configuration
+    /// of the peer, run `peer confirm-keys`, then export the other peers again.
+    RotateKeys {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// how long the exports of the other peers can still list the old public key, e.g. 7d
+        #[clap(short, long, parse(try_from_str = expiry::parse_duration))]
+        grace: Option<i64>,
+    },
+    /// Tell the exports that a rotated peer runs its new keys, ending its grace period
+    ConfirmKeys {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+    },
+    /// Generate the forwarding and NAT rules of a server peer
+    Firewall {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        #[clap(short, long, default_value_t = FirewallFormat::Nftables, arg_enum)]
+        format: FirewallFormat,
+        /// write the files in this directory instead of printing them
+        #[clap(short, long, parse(from_os_str), conflicts_with_all = &["hooks", "unset"])]
+        output_dir: Option<std::path::PathBuf>,
+        /// add the rules to the PostUp and PostDown hooks of the peer, kept up to date on export
+        #[clap(long)]
+        hooks: bool,
+        /// remove the rules from the hooks
+        #[clap(long, conflicts_with = "hooks")]
+        unset: bool,
+    },
+    /// Prevent a peer from reaching the other peers through the servers
+    Isolate {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// let the peer reach the other peers again
+        #[clap(long)]
+        unset: bool,
+    },
+    /// Make a peer a hub, for VPNs with the hub topology
+    Hub {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// make the peer a spoke again
+        #[clap(long)]
+        unset: bool,
+    },
+    /// Set how the other peers connect to a peer acting as a server or relay
+    Server {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// port the peer listens on
+        #[clap(short, long)]
+        listen_port: Option<u16>,
+        /// public endpoint of the peer, as host:port or [ipv6]:port
+        #[clap(short, long)]
+        endpoint: Option<Endpoint>,
+        /// PersistentKeepalive interval, in seconds, the other peers use towards this one
+        #[clap(short, long)]
+        keepalive: Option<u16>,
+        /// clear the listen port, endpoint and keepalive
+        #[clap(long, conflicts_with_all = &["listen-port", "endpoint", "keepalive"])]
+        unset: bool,
+    },
+    /// Choose which traffic of a peer goes through the VPN
+    Tunnel {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// split: only the VPN and the networks behind the other peers.
+        /// full: everything, through the hub or the first peer with an endpoint
+        #[clap(arg_enum)]
+        mode: TunnelMode,
+        /// keep the RFC1918 ranges on the local network, for full tunnels
+        #[clap(long)]
+        exclude_lan: bool,
+    },
+    /// Show the wg-quick interface settings of a peer, or override those of its VPN
+    Settings {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        #[clap(flatten)]
+        settings: InterfaceSettings,
+    },
+    /// Link two peers, for VPNs with the custom topology
+    Link {
+        /// vpn the peers are part of
+        vpn: String,
+        peer1: String,
+        peer2: String,
+    },
+    /// Remove the link between two peers
+    Unlink {
+        /// vpn the peers are part of
+        vpn: String,
+        peer1: String,
+        peer2: String,
+    },
+    /// Manage the tags of a peer, used to select peers in ACLs and bulk operations
+    Tag {
+        #[clap(subcommand)]
+        command: Tag,
+    },
+    /// Show the wg-quick configuration of a peer as a QR code, for the mobile apps
+    Qr {
+        /// vpn the peer is part of
+        vpn: String,
+        /// peer name
+        name: String,
+        /// also write the QR code to a .png or .svg file
+        #[clap(short, long, parse(from_os_str))]
+        output: Option<std::path::PathBuf>,
+    },
 }
 
 impl Peer {
-    fn dispatch(&self, _conn: SqliteConnection) -> Result<bool> {
-        Err(anyhow::anyhow!("not implemented"))
+    fn dispatch(&self, conn: SqliteConnection) -> Result<bool> {
+        match self {
+            Peer::Export {
+                vpn,
+                name,
+                format,
+                template,
+                output_dir,
+                retired_keys,
+            } => {
+                let mut node = export::Node::load(&conn, vpn, name)?;
+                if *retired_keys {
+                    node.add_retired_keys(&conn)?;
+                }
+                let files = format.render(&node, template.as_deref())?;
+                output_files(&files, output_dir.as_deref())?;
+                Ok(true)
+            }
+            Peer::Firewall {
+                vpn,
+                name,
+                format,
+                output_dir,
+                hooks,
+                unset,
+            } => {
+                if *hooks || *unset {
+                    let format = match unset {
+                        true => None,
+                        false => Some(*format),
+                    };
+                    api::set_peer_firewall(&conn, vpn, name, format)?;
+                    return Ok(true);
+                }
+                let files = api::render_peer_firewall(&conn, vpn, name, *format)?;
+                output_files(&files, output_dir.as_deref())?;
+                Ok(true)
+            }
+            Peer::Isolate { vpn, name, unset } => {
+                api::set_peer_isolated(&conn, vpn, name, !unset)?;
+                Ok(true)
+            }
+            Peer::Hub { vpn, name, unset } => {
+                api::set_peer_hub(&conn, vpn, name, !unset)?;
+                Ok(true)
+            }
+            Peer::Server {
+                vpn,
+                name,
+                listen_port,
+                endpoint,
+                keepalive,
+                unset,
+            } => {
+                if *unset {
+                    api::clear_peer_server(&conn, vpn, name)?;
+                    return Ok(true);
+                }
+                if listen_port.is_none() && endpoint.is_none() && keepalive.is_none() {
+                    return Err(anyhow::anyhow!(
+                        "nothing to set, use --listen-port, --endpoint, --keepalive or --unset"
+                    ));
+                }
+                api::update_peer_server(
+                    &conn,
+                    vpn,
+                    name,
+                    *listen_port,
+                    endpoint.as_ref(),
+                    *keepalive,
+                )?;
+                Ok(true)
+            }
+            Peer::Tunnel {
+                vpn,
+                name,
+                mode,
+                exclude_lan,
+            } => {
+                api::set_peer_tunnel(&conn, vpn, name, *mode, *exclude_lan)?;
+                Ok(true)
+            }
+            Peer::Settings {
+                vpn,
+                name,
+                settings,
+            } => {
+                if settings.is_empty() {
+                    // the effective settings, including those inherited from the VPN
+                    let current = api::peer_settings(&conn, vpn, name)?;
+                    print_settings(&[
+                        ("MTU", current.mtu.map(|v| v.to_string())),
+                        ("Table", current.table),
+                        ("FwMark", current.fwmark.map(|v| v.to_string())),
+                        ("PreUp", Some(current.pre_up.join("\n"))),
+                        ("PostUp", Some(current.post_up.join("\n"))),
+                        ("PreDown", Some(current.pre_down.join("\n"))),
+                        ("PostDown", Some(current.post_down.join("\n"))),
+                    ]);
+                    return Ok(true);
+                }
+                api::update_peer_settings(
+                    &conn,
+                    vpn,
+                    name,
+                    &api::PeerSettings::from(settings.changes()),
+                )?;
+                Ok(true)
+            }
+            Peer::List { vpn, tag } => {
+                use schema::peer_tags::dsl as peer_tags;
+                let selected = api::list_peers(&conn, vpn, tag)?;
+                let tags = peer_tags::peer_tags
+                    .filter(peer_tags::vpn.eq(vpn))
+                    .order(peer_tags::tag)
+                    .load::<models::PeerTag>(&conn)?;
+                let now = expiry::now();
+                for peer in selected {
+                    let own: Vec<&str> = tags
+                        .iter()
+                        .filter(|t| t.peer == peer.name)
+                        .map(|t| t.tag.as_str())
+                        .collect();
+                    print!(
+                        "{}: {}, {}, {}",
+                        peer.name, peer.status, peer.address_v4, peer.address_v6
+                    );
+                    match &peer.expires_at {
+                        Some(at) if peer.expired(&now) => print!(", expired on {}", at),
+                        Some(at) => print!(", expires on {}", at),
+                        None => {}
+                    }
+                    if !own.is_empty() {
+                        print!(", tags: {}", own.join(" "));
+                    }
+                    println!();
+                }
+                Ok(true)
+            }
+            Peer::Update {
+                vpn,
+                name: None,
+                tag,
+                new_name,
+                endpoint,
+                dns,
+                status,
+                expires,
+                ttl,
+                no_expiry,
+                pubkey,
+                privatekey,
+                ipv4,
+                ipv6,
+            } => {
+                use schema::peers::dsl;
+                if new_name.is_some()
+                    || endpoint.is_some()
+                    || pubkey.is_some()
+                    || privatekey.is_some()
+                    || ipv4.is_some()
+                    || ipv6.is_some()
+                {
+                    return Err(anyhow::anyhow!(
+                        "only --status, --dns and the expiry can be changed for all the peers \
+                         with a tag"
+                    ));
+                }
+                let changes = models::PeerChanges {
+                    status: *status,
+                    dns: dns.clone(),
+                    expires_at: expiry_change(expires, ttl, *no_expiry),
+                    ..Default::default()
+                };
+                if changes.status.is_none() && changes.dns.is_none() && changes.expires_at.is_none()
+                {
+                    return Err(anyhow::anyhow!(
+                        "nothing to update, use --status, --dns, --expires, --ttl or --no-expiry"
+                    ));
+                }
+                // all the selected peers change, or none does
+                let updated = conn.transaction::<_, anyhow::Error, _>(|| {
+                    let selected = select_peers(&conn, vpn, tag)?;
+                    if selected.is_empty() {
+                        return Err(anyhow::anyhow!(
+                            "no peer of VPN {} has the tags {}",
+                            vpn,
+                            tag.join(", ")
+                        ));
+                    }
+                    Ok(diesel::update(
+                        dsl::peers
+                            .filter(dsl::vpn_name.eq(vpn))
+                            .filter(dsl::name.eq_any(&selected)),
+                    )
+                    .set(&changes)
+                    .execute(&conn)?)
+                })?;
+                println!("Updated {} peers", updated);
+                Ok(true)
+            }
+            Peer::Update {
+                vpn,
+                name: Some(name),
+                tag: _,
+                new_name,
+                endpoint,
+                dns,
+                status,
+                expires,
+                ttl,
+                no_expiry,
+                pubkey,
+                privatekey,
+                ipv4,
+                ipv6,
+            } => {
+                if new_name.is_none()
+                    && endpoint.is_none()
+                    && dns.is_none()
+                    && status.is_none()
+                    && expires.is_none()
+                    && ttl.is_none()
+                    && !no_expiry
+                    && pubkey.is_none()
+                    && privatekey.is_none()
+                    && ipv4.is_none()
+                    && ipv6.is_none()
+                {
+                    return Err(anyhow::anyhow!("nothing to update"));
+                }
+                let mut changes = models::PeerChanges {
+                    name: new_name.clone(),
+                    dns: dns.clone(),
+                    status: *status,
+                    endpoint_host: endpoint.as_ref().map(|e| e.host.clone()),
+                    endpoint_port: endpoint.as_ref().map(|e| i32::from(e.port)),
+                    expires_at: expiry_change(expires, ttl, *no_expiry),
+                    address_v4: ipv4.map(Ipv4Address),
+                    address_v6: ipv6.map(Ipv6Address),
+                    ..Default::default()
+                };
+                api::set_peer_keys(&mut changes, privatekey.as_deref(), pubkey.as_deref())?;
+                api::update_peer(&conn, vpn, name, &changes)?;
+                Ok(true)
+            }
+            Peer::Add {
+                vpn,
+                name,
+                endpoint,
+                dns,
+                status,
+                expires,
+                ttl,
+                pubkey,
+                privatekey,
+                ipv4,
+                ipv6,
+            } => {
+                let peer = api::add_peer(
+                    &conn,
+                    vpn,
+                    name,
+                    &api::PeerOptions {
+                        endpoint: endpoint.clone(),
+                        dns: dns.clone(),
+                        status: *status,
+                        public_key: pubkey.clone(),
+                        private_key: privatekey.clone(),
+                        address_v4: *ipv4,
+                        address_v6: *ipv6,
+                        expires_at: expiry_change(expires, ttl, false).flatten(),
+                    },
+                )?;
+                println!(
+                    "Added peer {} ({}, {})",
+                    peer.name, peer.address_v4, peer.address_v6
+                );
+                Ok(true)
+            }
+            Peer::Remove { vpn, name } => {
+                api::remove_peer(&conn, vpn, name)?;
+                Ok(true)
+            }
+            Peer::Tag { command } => command.dispatch(&conn),
+            Peer::RotateKeys { vpn, name, grace } => {
+                let peer = api::rotate_peer_keys(&conn, vpn, name, grace.unwrap_or(0))?;
+                println!("New public key for peer {}: {}", name, peer.public_key);
+                Ok(true)
+            }
+            Peer::ConfirmKeys { vpn, name } => {
+                if api::confirm_peer_keys(&conn, vpn, name)? == 0 {
+                    return Err(anyhow::anyhow!(
+                        "peer {} has no keys in their grace period",
+                        name
+                    ));
+                }
+                println!(
+                    "Peer {} now runs its new keys, export the other peers again",
+                    name
+                );
+                Ok(true)
+            }
+            Peer::Link { vpn, peer1, peer2 } => {
+                api::link_peers(&conn, vpn, peer1, peer2)?;
+                Ok(true)
+            }
+            Peer::Unlink { vpn, peer1, peer2 } => {
+                api::unlink_peers(&conn, vpn, peer1, peer2)?;
+                Ok(true)
+            }
+            Peer::Qr { vpn, name, output } => {
+                let node = export::Node::load(&conn, vpn, name)?;
+                if node.private_key().is_none() {
+                    return Err(anyhow::anyhow!(
+                        "the private key of peer {} is not known",
+                        name
+                    ));
+                }
+                let config = export::wgquick::config(&node)?.to_string();
+                let (code, warning) = export::qr::encode(&config)?;
+                if let Some(warning) = warning {
+                    println!("Warning: {}", warning);
+                }
+                println!("{}", export::qr::terminal(&code));
+                if let Some(path) = output {
+                    export::qr::write(&code, path)?;
+                    println!("Written {}", path.display());
+                }
+                Ok(true)
+            }
+            Peer::Diff { vpn, name, live } => {
+                let content = std::fs::read_to_string(live)
+                    .with_context(|| format!("cannot read {}", live.display()))?;
+                let live = drift::parse_live(&content)?;
+                let node = export::Node::load(&conn, vpn, name)?;
+                let expected = export::wgquick::config(&node)?;
+                let differences = drift::diff(&expected, &live);
+                if differences.is_empty() {
+                    println!("Peer {} matches the live configuration", name);
+                    return Ok(true);
+                }
+                for difference in &differences {
+                    println!("{}", difference);
+                }
+                Err(anyhow::anyhow!(
+                    "{} differences found for peer {}",
+                    differences.len(),
+                    name
+                ))
+            }
+        }
     }
 }
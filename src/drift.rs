@@ -0,0 +1,146 @@
+//! Compare the configuration rendered from the database with the one running on a node
+use crate::endpoint::Endpoint;
+use crate::wgconf::{self, Config};
+use crate::wgdump::Dump;
+
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+
+/// parse either `wg showconf` or `wg show <iface> dump` output
+pub fn parse_live(content: &str) -> Result<Config> {
+    let is_dump = content
+        .lines()
+        .find(|l| !l.trim().is_empty())
+        .map(|l| l.contains('\t') && !l.trim_start().starts_with('['))
+        .unwrap_or(false);
+    if is_dump {
+        let dump = Dump::parse(content).context("cannot parse dump")?;
+        Ok(dump.interface(None)?.into_config())
+    } else {
+        Config::parse(content).context("cannot parse configuration")
+    }
+}
+
+fn peer_label(peer: &wgconf::Peer) -> String {
+    match &peer.name {
+        Some(name) => format!("{} ({})", name, peer.public_key),
+        None => peer.public_key.clone(),
+    }
+}
+
+fn nets(ips: &[IpNet]) -> BTreeSet<IpNet> {
+    ips.iter().map(|ip| ip.trunc()).collect()
+}
+
+fn format_nets(ips: &BTreeSet<&IpNet>) -> String {
+    ips.iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// true if the `live` endpoint, always an ip:port, is the `expected` one. wg resolves host names
+/// when it loads the configuration, and they may resolve differently now, so only the port of
+/// those is compared
+fn same_endpoint(expected: &str, live: Option<&str>) -> bool {
+    let live = match live.map(str::parse::<SocketAddr>) {
+        Some(Ok(live)) => live,
+        _ => return false,
+    };
+    match expected.parse::<Endpoint>() {
+        Ok(e) => match e.host.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, e.port) == live,
+            Err(_) => e.port == live.port(),
+        },
+        Err(_) => false,
+    }
+}
+
+/// List the differences between the `expected` and `live` configurations.
+/// Values that wireguard picks at runtime (the listen port of clients, the endpoint of roaming
+/// peers) are compared only when the expected configuration sets them.
+pub fn diff(expected: &Config, live: &Config) -> Vec<String> {
+    let mut differences = vec![];
+    if let (Some(e), Some(l)) = (&expected.interface, &live.interface) {
+        if e.listen_port.is_some() && e.listen_port != l.listen_port {
+            differences.push(format!(
+                "listen port: expected {}, found {}",
+                e.listen_port.map(|p| p.to_string()).unwrap_or_default(),
+                l.listen_port
+                    .map(|p| p.to_string())
+                    .unwrap_or_else(|| String::from("none"))
+            ));
+        }
+        if e.fwmark.is_some() && e.fwmark != l.fwmark {
+            differences.push(format!(
+                "fwmark: expected {}, found {}",
+                e.fwmark.map(|m| format!("{:#x}", m)).unwrap_or_default(),
+                l.fwmark
+                    .map(|m| format!("{:#x}", m))
+                    .unwrap_or_else(|| String::from("none"))
+            ));
+        }
+        if let (Some(ek), Some(lk)) = (&e.private_key, &l.private_key) {
+            if ek != lk {
+                differences.push(String::from("private key differs"));
+            }
+        }
+    }
+    for e in &expected.peers {
+        let l = match live.peers.iter().find(|l| l.public_key == e.public_key) {
+            Some(l) => l,
+            None => {
+                differences.push(format!("peer {} is not configured", peer_label(e)));
+                continue;
+            }
+        };
+        let label = peer_label(e);
+        let (expected_ips, live_ips) = (nets(&e.allowed_ips), nets(&l.allowed_ips));
+        let missing: BTreeSet<_> = expected_ips.difference(&live_ips).collect();
+        let extra: BTreeSet<_> = live_ips.difference(&expected_ips).collect();
+        if !missing.is_empty() {
+            differences.push(format!(
+                "peer {}: allowed ips missing: {}",
+                label,
+                format_nets(&missing)
+            ));
+        }
+        if !extra.is_empty() {
+            differences.push(format!(
+                "peer {}: unexpected allowed ips: {}",
+                label,
+                format_nets(&extra)
+            ));
+        }
+        if let Some(endpoint) = &e.endpoint {
+            if !same_endpoint(endpoint, l.endpoint.as_deref()) {
+                differences.push(format!(
+                    "peer {}: endpoint: expected {}, found {}",
+                    label,
+                    endpoint,
+                    l.endpoint.as_deref().unwrap_or("none")
+                ));
+            }
+        }
+        match (&e.preshared_key, &l.preshared_key) {
+            (Some(_), None) => {
+                differences.push(format!("peer {}: preshared key is missing", label))
+            }
+            (None, Some(_)) => {
+                differences.push(format!("peer {}: unexpected preshared key", label))
+            }
+            (Some(ek), Some(lk)) if ek != lk => {
+                differences.push(format!("peer {}: preshared key differs", label))
+            }
+            _ => {}
+        }
+    }
+    for l in &live.peers {
+        if !expected.peers.iter().any(|e| e.public_key == l.public_key) {
+            differences.push(format!("unexpected peer {}", peer_label(l)));
+        }
+    }
+    differences
+}
@@ -0,0 +1,90 @@
+use std::net::{IpAddr, Ipv6Addr};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum EndpointError {
+    #[error("Endpoint `{0}` must be in the host:port form")]
+    MissingPort(String),
+    #[error("Invalid port in endpoint `{0}`")]
+    InvalidPort(String),
+    #[error("Invalid host in endpoint `{0}`, IPv6 addresses must be in brackets")]
+    InvalidHost(String),
+}
+
+/// The public address of a peer, `host:port` or `[ipv6]:port`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+fn valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+impl Endpoint {
+    pub fn new(host: &str, port: u16) -> Result<Self, EndpointError> {
+        let display = format!("{}:{}", host, port);
+        if port == 0 {
+            return Err(EndpointError::InvalidPort(display));
+        }
+        if host.parse::<IpAddr>().is_err() && !valid_hostname(host) {
+            return Err(EndpointError::InvalidHost(display));
+        }
+        Ok(Endpoint {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = EndpointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| EndpointError::MissingPort(s.into()))?;
+        let host = match host.strip_prefix('[') {
+            Some(h) => {
+                let h = h
+                    .strip_suffix(']')
+                    .ok_or_else(|| EndpointError::InvalidHost(s.into()))?;
+                h.parse::<Ipv6Addr>()
+                    .map_err(|_| EndpointError::InvalidHost(s.into()))?;
+                h
+            }
+            // an ipv6 address without brackets: the last group was taken as the port
+            None if host.contains(':') => return Err(EndpointError::InvalidHost(s.into())),
+            None => host,
+        };
+        let port = port
+            .parse()
+            .map_err(|_| EndpointError::InvalidPort(s.into()))?;
+        Endpoint::new(host, port)
+    }
+}
+
+/// serialized as `host:port`, as it is written in configurations
+impl serde::Serialize for Endpoint {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Errors of the commands and of the api
+use crate::keys::KeyError;
+
+use diesel::result::Error as DieselError;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Network {0} does not exist")]
+    NetworkNotFound(String),
+    #[error("Network {0} already exists")]
+    NetworkExists(String),
+    #[error("Network {0} still has VPNs")]
+    NetworkHasVpns(String),
+    #[error("VPN {0} does not exist")]
+    VpnNotFound(String),
+    #[error("VPN {0} already exists")]
+    VpnExists(String),
+    #[error("VPN {0} still has peers")]
+    VpnHasPeers(String),
+    #[error("Peer {name} does not exist in VPN {vpn}")]
+    PeerNotFound { vpn: String, name: String },
+    #[error("Peer {name} already exists in VPN {vpn}")]
+    PeerExists { vpn: String, name: String },
+    #[error("Invalid tag `{0}`")]
+    InvalidTag(String),
+    #[error("ACL {0} does not exist")]
+    AclNotFound(i32),
+    #[error("Ports are only for tcp and udp")]
+    PortWithoutProtocol,
+    #[error("Cannot link peer {0} to itself")]
+    SelfLink(String),
+    #[error("Peers {peer1} and {peer2} are already linked")]
+    AlreadyLinked { peer1: String, peer2: String },
+    #[error("Peers {peer1} and {peer2} are not linked")]
+    NotLinked { peer1: String, peer2: String },
+    #[error("Only full tunnels can exclude the LAN")]
+    SplitTunnelExcludesLan,
+    #[error("The listen port and keepalive must not be 0")]
+    ZeroPortOrKeepalive,
+    #[error("The MTU must be at least 1280 to carry IPv6, not {0}")]
+    MtuTooSmall(i32),
+    #[error("The routing table cannot be empty")]
+    EmptyRouteTable,
+    #[error("{subnet} is outside of network {network}")]
+    SubnetOutOfRange { subnet: IpNet, network: String },
+    #[error("{subnet} overlaps {other}")]
+    SubnetOverlap { subnet: IpNet, other: String },
+    #[error(
+        "No free /{prefix_len} subnet left in {subnet} of network {network}: \
+         its {vpns} VPNs use {used} of its {total} addresses"
+    )]
+    SubnetExhausted {
+        network: String,
+        subnet: IpNet,
+        prefix_len: u8,
+        vpns: usize,
+        used: u128,
+        total: u128,
+    },
+    #[error("/{prefix_len} VPN subnets do not fit in {subnet}")]
+    InvalidPrefixLength { subnet: IpNet, prefix_len: i32 },
+    #[error("{address} is outside of the subnets of VPN {vpn}")]
+    AddressOutOfRange { address: IpAddr, vpn: String },
+    #[error("{address} is already assigned to peer {peer}")]
+    AddressInUse { address: IpAddr, peer: String },
+    #[error("No free address left in {subnet} of VPN {vpn}")]
+    AddressesExhausted { vpn: String, subnet: IpNet },
+    #[error("Public key {key} is already used by peer {peer} of VPN {vpn}")]
+    DuplicatePublicKey {
+        vpn: String,
+        peer: String,
+        key: String,
+    },
+    #[error("The public key does not match the private key")]
+    KeyMismatch,
+    #[error(transparent)]
+    InvalidKey(#[from] KeyError),
+    #[error("Database error")]
+    Database(#[from] DieselError),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+// errors of the other modules can carry a CommandError, like the ones of `export::Node::load`
+impl From<anyhow::Error> for CommandError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<CommandError>() {
+            Ok(err) => err,
+            Err(err) => CommandError::Other(err),
+        }
+    }
+}
+
+/// a constraint of the database that a write failed
+#[derive(Debug, PartialEq)]
+pub(crate) enum Constraint {
+    /// the columns of a UNIQUE constraint, like `vpns.address_v4`
+    Unique(String),
+    /// sqlite doesn't tell which foreign key failed
+    ForeignKey,
+}
+
+pub(crate) fn constraint(err: &DieselError) -> Option<Constraint> {
+    // the kind is not reliable, failed RESTRICT foreign keys have an unknown one
+    let message = match err {
+        DieselError::DatabaseError(_, info) => info.message(),
+        _ => return None,
+    };
+    if let Some(columns) = message.strip_prefix("UNIQUE constraint failed: ") {
+        return Some(Constraint::Unique(columns.into()));
+    }
+    match message == "FOREIGN KEY constraint failed" {
+        true => Some(Constraint::ForeignKey),
+        false => None,
+    }
+}
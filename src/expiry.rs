@@ -0,0 +1,159 @@
+//! Expiry of peers. Times are UTC `YYYY-MM-DD HH:MM:SS` strings, like SQLite's `datetime()`,
+//! so they compare as strings both in SQL and here
+use crate::schema::peers;
+use crate::types::PeerStatus;
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ExpiryError {
+    #[error("Invalid date `{0}`: expected YYYY-MM-DD")]
+    InvalidDate(String),
+    #[error("Invalid duration `{0}`: expected a number followed by s, m, h, d or w")]
+    InvalidDuration(String),
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// proleptic gregorian date of a day counted from 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// `secs` since the epoch
+pub fn format(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let time = secs.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub fn now() -> String {
+    format(now_secs())
+}
+
+/// `--expires`: the peer expires at the start of `date`
+pub fn parse_date(date: &str) -> Result<String, ExpiryError> {
+    let invalid = || ExpiryError::InvalidDate(date.into());
+    let parts: Vec<&str> = date.split('-').collect();
+    if parts.len() != 3 || parts[0].len() != 4 || parts[1].len() != 2 || parts[2].len() != 2 {
+        return Err(invalid());
+    }
+    let mut numbers = [0i64; 3];
+    for (n, part) in numbers.iter_mut().zip(&parts) {
+        if !part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        *n = part.parse().map_err(|_| invalid())?;
+    }
+    let [year, month, day] = numbers;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return Err(invalid());
+    }
+    Ok(format!("{} 00:00:00", date))
+}
+
+/// `secs` from now
+pub fn after(secs: i64) -> String {
+    format(now_secs().saturating_add(secs))
+}
+
+/// seconds in a duration like `90d`
+pub fn parse_duration(duration: &str) -> Result<i64, ExpiryError> {
+    let invalid = || ExpiryError::InvalidDuration(duration.into());
+    let unit = match duration.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86_400,
+        Some('w') => 7 * 86_400,
+        _ => return Err(invalid()),
+    };
+    let count: i64 = duration[..duration.len() - 1]
+        .parse()
+        .map_err(|_| invalid())?;
+    if count <= 0 {
+        return Err(invalid());
+    }
+    count.checked_mul(unit).ok_or_else(invalid)
+}
+
+/// `--ttl`: the peer expires after a duration like `90d` from now
+pub fn parse_ttl(ttl: &str) -> Result<String, ExpiryError> {
+    Ok(after(parse_duration(ttl)?))
+}
+
+/// an active peer past its expiry time
+#[derive(Debug)]
+pub struct ExpiredPeer {
+    pub vpn: String,
+    pub name: String,
+    pub expires_at: String,
+}
+
+/// active peers that expired, in all the VPNs
+pub fn expired_peers(conn: &SqliteConnection) -> QueryResult<Vec<ExpiredPeer>> {
+    Ok(peers::table
+        .filter(peers::status.eq(PeerStatus::Active))
+        .filter(peers::expires_at.le(now()))
+        .select((peers::vpn_name, peers::name, peers::expires_at))
+        .order((peers::vpn_name, peers::name))
+        .load::<(String, String, Option<String>)>(conn)?
+        .into_iter()
+        .map(|(vpn, name, expires_at)| ExpiredPeer {
+            vpn,
+            name,
+            expires_at: expires_at.unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// set the status of the `expired` peers to disabled, all of them or none
+pub fn disable_peers(conn: &SqliteConnection, expired: &[ExpiredPeer]) -> QueryResult<usize> {
+    conn.transaction(|| {
+        let mut updated = 0;
+        for peer in expired {
+            updated += diesel::update(peers::table.find((&peer.vpn, &peer.name)))
+                .set(peers::status.eq(PeerStatus::Disabled))
+                .execute(conn)?;
+        }
+        Ok(updated)
+    })
+}
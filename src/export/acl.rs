@@ -0,0 +1,86 @@
+//! Resolve the peer to peer access rules of a VPN to addresses
+use crate::models;
+use crate::types::{AclAction, AclProtocol};
+
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+/// an ACL entry with its peers and tags replaced by their addresses
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub id: i32,
+    /// `None` matches any peer, an empty list none (a tag nobody has)
+    pub sources: Option<Vec<IpAddr>>,
+    pub destinations: Option<Vec<IpAddr>>,
+    /// `None` for any protocol
+    pub protocol: Option<AclProtocol>,
+    pub port: Option<u16>,
+    pub allow: bool,
+}
+
+pub(super) fn addresses(peer: &models::Peer) -> Vec<IpAddr> {
+    vec![IpAddr::V4(*peer.address_v4), IpAddr::V6(*peer.address_v6)]
+}
+
+fn resolve(
+    acl: &models::Acl,
+    peer: &Option<String>,
+    tag: &Option<String>,
+    peers: &[models::Peer],
+    tags: &[models::PeerTag],
+) -> Result<Option<Vec<IpAddr>>> {
+    let selected: Vec<&models::Peer> = match (peer, tag) {
+        (Some(name), _) => vec![peers.iter().find(|p| &p.name == name).ok_or_else(|| {
+            anyhow!(
+                "ACL {} references peer {}, which is not in VPN {}",
+                acl.id,
+                name,
+                acl.vpn
+            )
+        })?],
+        (None, Some(tag)) => peers
+            .iter()
+            .filter(|p| tags.iter().any(|t| t.peer == p.name && &t.tag == tag))
+            .collect(),
+        (None, None) => return Ok(None),
+    };
+    let mut ips = vec![];
+    for peer in selected {
+        ips.append(&mut addresses(peer));
+    }
+    Ok(Some(ips))
+}
+
+/// resolve the `acls` of `vpn`, given all its `peers` and their `tags`
+pub fn compile(
+    vpn: &str,
+    acls: &[models::Acl],
+    peers: &[models::Peer],
+    tags: &[models::PeerTag],
+) -> Result<Vec<Rule>> {
+    let mut rules = vec![];
+    for acl in acls {
+        if acl.vpn != vpn {
+            return Err(anyhow!(
+                "ACL {} belongs to VPN {}, not {}",
+                acl.id,
+                acl.vpn,
+                vpn
+            ));
+        }
+        rules.push(Rule {
+            id: acl.id,
+            sources: resolve(acl, &acl.src_peer, &acl.src_tag, peers, tags)?,
+            destinations: resolve(acl, &acl.dst_peer, &acl.dst_tag, peers, tags)?,
+            protocol: acl.protocol,
+            port: acl
+                .port
+                .map(u16::try_from)
+                .transpose()
+                .map_err(|_| anyhow!("invalid port for ACL {}", acl.id))?,
+            allow: acl.action == AclAction::Allow,
+        });
+    }
+    Ok(rules)
+}
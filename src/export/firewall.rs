@@ -0,0 +1,379 @@
+//! Forwarding and NAT rules for server peers, as an nftables ruleset or for iptables
+use super::acl::{self, addresses};
+use super::{File, Node};
+use crate::types::{AclProtocol, FirewallFormat, TunnelMode};
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+/// what the rules are made of, independently of the backend
+struct Plan {
+    iface: String,
+    /// name of the iptables chains
+    name: String,
+    /// networks reached through the VPN or behind the server, outside of the VPN subnets
+    routed: Vec<IpNet>,
+    /// addresses of the peers that can't talk to the other peers
+    isolated: Vec<IpAddr>,
+    /// addresses of the full tunnel clients, masqueraded when leaving through another interface
+    clients: Vec<IpAddr>,
+    /// peer to peer rules, checked before anything else
+    acl: Vec<acl::Rule>,
+}
+
+/// the addresses of `family` in `addrs`, or `None` if the rule can't match in this family
+fn of_family(addrs: &Option<Vec<IpAddr>>, v4: bool) -> Option<Option<Vec<String>>> {
+    match addrs {
+        None => Some(None),
+        Some(addrs) => {
+            let addrs: Vec<String> = addrs
+                .iter()
+                .filter(|a| a.is_ipv4() == v4)
+                .map(|a| a.to_string())
+                .collect();
+            match addrs.is_empty() {
+                true => None,
+                false => Some(Some(addrs)),
+            }
+        }
+    }
+}
+
+/// the protocol name for `family`, icmp has a different one for IPv6
+fn protocol(protocol: AclProtocol, v4: bool) -> &'static str {
+    match (protocol, v4) {
+        (AclProtocol::Icmp, false) => "ipv6-icmp",
+        (p, _) => p.as_str(),
+    }
+}
+
+impl Plan {
+    /// nftables identifiers can't contain dashes
+    fn table(&self) -> String {
+        format!("table inet {}", self.name.replace('-', "_"))
+    }
+
+    fn new(node: &Node) -> Result<Self> {
+        if node.listen_port().is_none() && !node.peer.hub {
+            return Err(anyhow!(
+                "peer {} is not a server, set its listen port with `peer server` or make it a hub",
+                node.peer.name
+            ));
+        }
+        let (v4, v6) = node.subnets();
+        let mut routed = vec![];
+        let nets = node
+            .allowed_ips
+            .iter()
+            .chain(node.remotes.iter().flat_map(|r| r.allowed_ips.iter()));
+        for net in nets {
+            let covered = match net {
+                IpNet::V4(n) => v4.contains(n),
+                IpNet::V6(n) => v6.contains(n),
+            };
+            if !covered && !routed.contains(net) {
+                routed.push(*net);
+            }
+        }
+        routed.push(IpNet::V4(v4));
+        routed.push(IpNet::V6(v6));
+        let mut isolated = vec![];
+        let mut clients = vec![];
+        for remote in &node.remotes {
+            if remote.peer.isolated {
+                isolated.append(&mut addresses(&remote.peer));
+            }
+            if remote.peer.tunnel == TunnelMode::Full {
+                clients.append(&mut addresses(&remote.peer));
+            }
+        }
+        let iface = node.interface_name();
+        Ok(Plan {
+            name: format!("vpnutils-{}", iface),
+            iface,
+            routed: IpNet::aggregate(&routed),
+            isolated,
+            clients,
+            acl: node.acl.clone(),
+        })
+    }
+
+    /// nftables ruleset, one statement per line
+    fn nftables(&self) -> Vec<String> {
+        let set = |items: Vec<String>| match items.len() {
+            1 => items[0].clone(),
+            _ => format!("{{ {} }}", items.join(", ")),
+        };
+        let family = |v4: bool| if v4 { "ip" } else { "ip6" };
+        let iif = format!("iifname \"{}\"", self.iface);
+        let oif = format!("oifname \"{}\"", self.iface);
+        let mut forward = vec![
+            String::from("type filter hook forward priority filter; policy accept"),
+            String::from("ct state established,related accept"),
+        ];
+        let mut postrouting = vec![String::from(
+            "type nat hook postrouting priority srcnat; policy accept",
+        )];
+        for rule in &self.acl {
+            for v4 in [true, false] {
+                let (sources, destinations) = match (
+                    of_family(&rule.sources, v4),
+                    of_family(&rule.destinations, v4),
+                ) {
+                    (Some(s), Some(d)) => (s, d),
+                    _ => continue,
+                };
+                let mut statement = format!("{} {}", iif, oif);
+                match (&sources, &destinations) {
+                    (None, None) => statement.push_str(&format!(
+                        " meta nfproto {}",
+                        if v4 { "ipv4" } else { "ipv6" }
+                    )),
+                    _ => {
+                        if let Some(s) = sources {
+                            statement.push_str(&format!(" {} saddr {}", family(v4), set(s)));
+                        }
+                        if let Some(d) = destinations {
+                            statement.push_str(&format!(" {} daddr {}", family(v4), set(d)));
+                        }
+                    }
+                }
+                match (rule.protocol, rule.port) {
+                    (Some(p), Some(port)) => statement.push_str(&format!(" {} dport {}", p, port)),
+                    (Some(p), None) => {
+                        statement.push_str(&format!(" meta l4proto {}", protocol(p, v4)))
+                    }
+                    _ => {}
+                }
+                statement.push_str(if rule.allow { " accept" } else { " drop" });
+                statement.push_str(&format!(" comment \"acl {}\"", rule.id));
+                forward.push(statement);
+            }
+        }
+        for addr in &self.isolated {
+            let family = family(addr.is_ipv4());
+            forward.push(format!("{} {} {} saddr {} drop", iif, oif, family, addr));
+            forward.push(format!("{} {} {} daddr {} drop", iif, oif, family, addr));
+        }
+        forward.push(format!("{} {} accept", iif, oif));
+        for v4 in [true, false] {
+            let routed: Vec<String> = self
+                .routed
+                .iter()
+                .filter(|n| matches!(n, IpNet::V4(_)) == v4)
+                .map(|n| n.to_string())
+                .collect();
+            forward.push(format!(
+                "{} {} daddr {} accept",
+                iif,
+                family(v4),
+                set(routed.clone())
+            ));
+            forward.push(format!(
+                "{} {} saddr {} accept",
+                oif,
+                family(v4),
+                set(routed)
+            ));
+        }
+        for v4 in [true, false] {
+            let clients: Vec<String> = self
+                .clients
+                .iter()
+                .filter(|a| a.is_ipv4() == v4)
+                .map(|a| a.to_string())
+                .collect();
+            if clients.is_empty() {
+                continue;
+            }
+            let clients = set(clients);
+            forward.push(format!("{} {} saddr {} accept", iif, family(v4), clients));
+            postrouting.push(format!(
+                "oifname != \"{}\" {} saddr {} masquerade",
+                self.iface,
+                family(v4),
+                clients
+            ));
+        }
+        forward.push(format!("{} drop", iif));
+        forward.push(format!("{} drop", oif));
+
+        let mut lines = vec![format!("{} {{", self.table())];
+        for (chain, rules) in [("forward", forward), ("postrouting", postrouting)] {
+            lines.push(format!("chain {} {{", chain));
+            lines.extend(rules);
+            lines.push(String::from("}"));
+        }
+        lines.push(String::from("}"));
+        lines
+    }
+
+    /// iptables rules, as (nat table, arguments after the chain name)
+    fn iptables(&self, v4: bool) -> Vec<(bool, String)> {
+        let ours = |a: &IpAddr| a.is_ipv4() == v4;
+        let mut rules = vec![(
+            false,
+            String::from("-m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT"),
+        )];
+        for rule in &self.acl {
+            let (sources, destinations) = match (
+                of_family(&rule.sources, v4),
+                of_family(&rule.destinations, v4),
+            ) {
+                (Some(s), Some(d)) => (s, d),
+                _ => continue,
+            };
+            let mut args = format!("-i {0} -o {0}", self.iface);
+            if let Some(s) = sources {
+                args.push_str(&format!(" -s {}", s.join(",")));
+            }
+            if let Some(d) = destinations {
+                args.push_str(&format!(" -d {}", d.join(",")));
+            }
+            if let Some(p) = rule.protocol {
+                args.push_str(&format!(" -p {}", protocol(p, v4)));
+            }
+            if let Some(port) = rule.port {
+                args.push_str(&format!(" --dport {}", port));
+            }
+            args.push_str(&format!(" -m comment --comment \"acl {}\"", rule.id));
+            args.push_str(if rule.allow { " -j ACCEPT" } else { " -j DROP" });
+            rules.push((false, args));
+        }
+        for addr in self.isolated.iter().filter(|a| ours(a)) {
+            for dir in ["-s", "-d"] {
+                rules.push((
+                    false,
+                    format!("-i {0} -o {0} {1} {2} -j DROP", self.iface, dir, addr),
+                ));
+            }
+        }
+        rules.push((false, format!("-i {0} -o {0} -j ACCEPT", self.iface)));
+        for net in self
+            .routed
+            .iter()
+            .filter(|n| matches!(n, IpNet::V4(_)) == v4)
+        {
+            rules.push((false, format!("-i {} -d {} -j ACCEPT", self.iface, net)));
+            rules.push((false, format!("-o {} -s {} -j ACCEPT", self.iface, net)));
+        }
+        for addr in self.clients.iter().filter(|a| ours(a)) {
+            rules.push((false, format!("-i {} -s {} -j ACCEPT", self.iface, addr)));
+            rules.push((
+                true,
+                format!("! -o {} -s {} -j MASQUERADE", self.iface, addr),
+            ));
+        }
+        rules.push((false, format!("-i {} -j DROP", self.iface)));
+        rules.push((false, format!("-o {} -j DROP", self.iface)));
+        rules
+    }
+}
+
+/// the builtin chain our chain is jumped to from
+fn parent(nat: bool) -> (&'static str, &'static str) {
+    match nat {
+        true => ("nat", "POSTROUTING"),
+        false => ("filter", "FORWARD"),
+    }
+}
+
+/// standalone files: an nftables script, or iptables-restore and ip6tables-restore input
+pub fn files(node: &Node, backend: FirewallFormat) -> Result<Vec<File>> {
+    let plan = Plan::new(node)?;
+    match backend {
+        FirewallFormat::Nftables => {
+            let mut out = String::from("#!/usr/sbin/nft -f\n");
+            let table = plan.table();
+            // creating the table first makes the delete work the first time too
+            writeln!(out, "{}\ndelete {}\n", table, table)?;
+            let mut depth = 0;
+            for line in plan.nftables() {
+                if line == "}" {
+                    depth -= 1;
+                }
+                writeln!(out, "{}{}", "\t".repeat(depth), line)?;
+                if line.ends_with('{') {
+                    depth += 1;
+                }
+            }
+            Ok(vec![File {
+                name: format!("{}.nft", plan.iface),
+                contents: out,
+                secret: false,
+            }])
+        }
+        FirewallFormat::Iptables => {
+            let mut files = vec![];
+            for (v4, name) in [(true, "rules.v4"), (false, "rules.v6")] {
+                let rules = plan.iptables(v4);
+                let mut out = String::from("# load with --noflush\n");
+                for nat in [false, true] {
+                    let (table, chain) = parent(nat);
+                    if !rules.iter().any(|(n, _)| *n == nat) {
+                        continue;
+                    }
+                    writeln!(out, "*{}", table)?;
+                    writeln!(out, ":{} - [0:0]", plan.name)?;
+                    for (_, rule) in rules.iter().filter(|(n, _)| *n == nat) {
+                        writeln!(out, "-A {} {}", plan.name, rule)?;
+                    }
+                    writeln!(out, "-I {} -j {}", chain, plan.name)?;
+                    writeln!(out, "COMMIT")?;
+                }
+                files.push(File {
+                    name: format!("{}.{}", plan.iface, name),
+                    contents: out,
+                    secret: false,
+                });
+            }
+            Ok(files)
+        }
+    }
+}
+
+/// commands for the PostUp and PostDown hooks
+pub fn hooks(node: &Node, backend: FirewallFormat) -> Result<(Vec<String>, Vec<String>)> {
+    let plan = Plan::new(node)?;
+    match backend {
+        FirewallFormat::Nftables => {
+            let mut ruleset = String::new();
+            for line in plan.nftables() {
+                let separator = match line.ends_with('{') {
+                    true => " ",
+                    false => "; ",
+                };
+                ruleset.push_str(&line);
+                ruleset.push_str(separator);
+            }
+            Ok((
+                vec![format!("nft '{}'", ruleset.trim_end())],
+                vec![format!("nft delete {}", plan.table())],
+            ))
+        }
+        FirewallFormat::Iptables => {
+            let (mut up, mut down) = (vec![], vec![]);
+            for (v4, command) in [(true, "iptables"), (false, "ip6tables")] {
+                let rules = plan.iptables(v4);
+                for nat in [false, true] {
+                    let (table, chain) = parent(nat);
+                    if !rules.iter().any(|(n, _)| *n == nat) {
+                        continue;
+                    }
+                    let command = format!("{} -t {}", command, table);
+                    up.push(format!("{} -N {}", command, plan.name));
+                    for (_, rule) in rules.iter().filter(|(n, _)| *n == nat) {
+                        up.push(format!("{} -A {} {}", command, plan.name, rule));
+                    }
+                    up.push(format!("{} -I {} -j {}", command, chain, plan.name));
+                    down.push(format!("{} -D {} -j {}", command, chain, plan.name));
+                    down.push(format!("{} -F {}", command, plan.name));
+                    down.push(format!("{} -X {}", command, plan.name));
+                }
+            }
+            Ok((up, down))
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! MikroTik RouterOS script
+use super::{File, Node};
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use std::fmt::Write;
+
+/// quote a string for RouterOS scripts, escaping the characters the console interprets
+fn quote(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '$' | '?' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_ascii_control() => out.push_str(&format!("\\{:02X}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn join(ips: &[IpNet]) -> String {
+    ips.iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn files(node: &Node) -> Result<Vec<File>> {
+    node.settings.check_no_hooks("RouterOS")?;
+    if node.settings.fwmark.is_some() {
+        return Err(anyhow!(
+            "FwMark is not supported by RouterOS, use mangle rules instead"
+        ));
+    }
+    if node.full_tunnel() {
+        // the default route would also catch the packets to the endpoint
+        return Err(anyhow!(
+            "full tunnels are not supported for RouterOS, route 0.0.0.0/0 by hand"
+        ));
+    }
+    let iface = quote(&node.interface_name());
+    let comment = quote(&format!("{}/{}", node.vpn.name, node.peer.name));
+    let mut out = String::new();
+    write!(out, "/interface wireguard add name={}", iface)?;
+    if let Some(key) = node.private_key() {
+        write!(out, " private-key={}", quote(key))?;
+    }
+    if let Some(port) = node.listen_port() {
+        write!(out, " listen-port={}", port)?;
+    }
+    if let Some(mtu) = node.settings.mtu {
+        write!(out, " mtu={}", mtu)?;
+    }
+    writeln!(out, " comment={}", comment)?;
+    for remote in &node.remotes {
+        write!(
+            out,
+            "/interface wireguard peers add interface={} public-key={}",
+            iface,
+            quote(&remote.peer.public_key)
+        )?;
+        if let Some(key) = &remote.preshared_key {
+            write!(out, " preshared-key={}", quote(key))?;
+        }
+        write!(out, " allowed-address={}", join(&remote.allowed_ips))?;
+        if let Some(endpoint) = &remote.endpoint {
+            write!(
+                out,
+                " endpoint-address={} endpoint-port={}",
+                quote(&endpoint.host),
+                endpoint.port
+            )?;
+        }
+        if let Some(keepalive) = remote.persistent_keepalive() {
+            write!(out, " persistent-keepalive={}s", keepalive)?;
+        }
+        writeln!(out, " comment={}", quote(&remote.peer.name))?;
+    }
+    for address in node.addresses()? {
+        match address {
+            IpNet::V4(_) => writeln!(
+                out,
+                "/ip address add address={} interface={} comment={}",
+                address, iface, comment
+            )?,
+            IpNet::V6(_) => writeln!(
+                out,
+                "/ipv6 address add address={} interface={} advertise=no comment={}",
+                address, iface, comment
+            )?,
+        }
+    }
+    // RouterOS does not route the allowed ips of the peers by itself
+    for route in node.routes() {
+        let prefix = match route {
+            IpNet::V4(_) => "/ip",
+            IpNet::V6(_) => "/ipv6",
+        };
+        write!(
+            out,
+            "{} route add dst-address={} gateway={}",
+            prefix, route, iface
+        )?;
+        if let Some(table) = node.settings.route_table() {
+            write!(out, " routing-table={}", quote(table))?;
+        }
+        writeln!(out, " comment={}", comment)?;
+    }
+    Ok(vec![File {
+        name: format!("{}.rsc", node.interface_name()),
+        contents: out,
+        secret: true,
+    }])
+}
@@ -0,0 +1,405 @@
+//! Render the configuration of a peer for the various wireguard frontends
+use crate::endpoint::Endpoint;
+use crate::error::CommandError;
+use crate::expiry;
+use crate::models;
+use crate::schema::{
+    acls, allowed_ips, edges, key_history, networks, peer_tags, peers, preshared_keys, vpns,
+};
+use crate::types::{PeerStatus, Topology, TunnelMode};
+use crate::wgconf::parse_net;
+
+use anyhow::{anyhow, Context, Result};
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::Path;
+
+pub mod acl;
+pub mod firewall;
+pub mod mikrotik;
+pub mod networkd;
+pub mod networkmanager;
+pub mod openwrt;
+pub mod qr;
+pub mod template;
+pub mod tunnel;
+pub mod wgquick;
+
+/// A file produced by an exporter
+#[derive(Debug)]
+pub struct File {
+    pub name: String,
+    pub contents: String,
+    /// contains keys, must not be world readable
+    pub secret: bool,
+}
+
+/// create or truncate `path`, only readable by the owner when it is `secret`
+pub(crate) fn create_file(path: &Path, secret: bool) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if secret { 0o600 } else { 0o644 });
+    }
+    options.open(path)
+}
+
+/// write the exported files in `dir`, creating it if needed
+pub fn write(files: &[File], dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    for file in files {
+        let path = dir.join(&file.name);
+        create_file(&path, file.secret)
+            .and_then(|mut f| f.write_all(file.contents.as_bytes()))
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        println!("Written {}", path.display());
+    }
+    Ok(())
+}
+
+/// Everything needed to render the configuration of a single peer
+#[derive(Debug)]
+pub struct Node {
+    pub network: models::Network,
+    pub vpn: models::Vpn,
+    pub peer: models::Peer,
+    /// the peers this node has a [Peer] section for
+    pub remotes: Vec<Remote>,
+    /// the allowed ips of the node itself, including the networks behind it
+    pub allowed_ips: Vec<IpNet>,
+    /// the peer to peer access rules of the VPN
+    pub acl: Vec<acl::Rule>,
+    pub settings: Settings,
+}
+
+/// wg-quick interface settings, those of the peer override the ones of the VPN
+#[derive(Debug, Default, Serialize)]
+pub struct Settings {
+    pub mtu: Option<u16>,
+    /// `off`, `auto`, or a routing table number or name
+    pub table: Option<String>,
+    pub fwmark: Option<u32>,
+    pub pre_up: Vec<String>,
+    pub post_up: Vec<String>,
+    pub pre_down: Vec<String>,
+    pub post_down: Vec<String>,
+}
+
+/// hooks are stored one command per line
+fn hooks(peer: &Option<String>, vpn: &Option<String>) -> Vec<String> {
+    peer.as_ref()
+        .or(vpn.as_ref())
+        .iter()
+        .flat_map(|h| h.lines())
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl Settings {
+    fn load(vpn: &models::Vpn, peer: &models::Peer) -> Result<Self> {
+        Ok(Settings {
+            mtu: peer
+                .mtu
+                .or(vpn.mtu)
+                .map(u16::try_from)
+                .transpose()
+                .with_context(|| format!("invalid MTU for peer {}", peer.name))?,
+            table: peer.route_table.clone().or_else(|| vpn.route_table.clone()),
+            fwmark: peer
+                .fwmark
+                .or(vpn.fwmark)
+                .map(u32::try_from)
+                .transpose()
+                .with_context(|| format!("invalid fwmark for peer {}", peer.name))?,
+            pre_up: hooks(&peer.pre_up, &vpn.pre_up),
+            post_up: hooks(&peer.post_up, &vpn.post_up),
+            pre_down: hooks(&peer.pre_down, &vpn.pre_down),
+            post_down: hooks(&peer.post_down, &vpn.post_down),
+        })
+    }
+
+    /// the routing table routes go to, `None` for the main one
+    pub fn route_table(&self) -> Option<&str> {
+        match self.table.as_deref() {
+            None | Some("auto") | Some("off") => None,
+            table => table,
+        }
+    }
+
+    /// fail for the formats that cannot run commands when the interface goes up or down
+    pub fn check_no_hooks(&self, format: &str) -> Result<()> {
+        for (name, hooks) in [
+            ("PreUp", &self.pre_up),
+            ("PostUp", &self.post_up),
+            ("PreDown", &self.pre_down),
+            ("PostDown", &self.post_down),
+        ] {
+            if !hooks.is_empty() {
+                return Err(anyhow!("{} hooks are not supported by {}", name, format));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Remote {
+    pub peer: models::Peer,
+    pub allowed_ips: Vec<IpNet>,
+    pub preshared_key: Option<String>,
+    /// where to reach the remote, `None` if it connects to us
+    pub endpoint: Option<Endpoint>,
+}
+
+impl Remote {
+    /// the keepalive the remote asks its clients to use towards it
+    pub fn persistent_keepalive(&self) -> Option<u16> {
+        self.peer
+            .persistent_keepalive
+            .and_then(|k| u16::try_from(k).ok())
+    }
+}
+
+impl Node {
+    pub fn load(conn: &SqliteConnection, vpn: &str, name: &str) -> Result<Self> {
+        let vpn = vpns::table
+            .find(vpn)
+            .first::<models::Vpn>(conn)
+            .optional()?
+            .ok_or_else(|| CommandError::VpnNotFound(vpn.into()))?;
+        let network = networks::table
+            .find(&vpn.network_name)
+            .first::<models::Network>(conn)?;
+        let mut all = peers::table
+            .filter(peers::vpn_name.eq(&vpn.name))
+            .order(peers::name)
+            .load::<models::Peer>(conn)?;
+        // expired peers are out even if nobody disabled them yet
+        let now = expiry::now();
+        for peer in all.iter_mut().filter(|p| p.expired(&now)) {
+            peer.status = PeerStatus::Disabled;
+        }
+        let acl = acl::compile(
+            &vpn.name,
+            &acls::table
+                .filter(acls::vpn.eq(&vpn.name))
+                .order(acls::id)
+                .load::<models::Acl>(conn)?,
+            &all,
+            &peer_tags::table
+                .filter(peer_tags::vpn.eq(&vpn.name))
+                .load::<models::PeerTag>(conn)?,
+        )?;
+        let (mut this, others): (Vec<_>, Vec<_>) = all.into_iter().partition(|p| p.name == name);
+        let peer = this.pop().ok_or_else(|| CommandError::PeerNotFound {
+            vpn: vpn.name.clone(),
+            name: name.into(),
+        })?;
+        let ips = allowed_ips::table
+            .filter(allowed_ips::peer_vpn.eq(&vpn.name))
+            .load::<models::AllowedIp>(conn)?;
+        let psks = preshared_keys::table
+            .filter(preshared_keys::vpn.eq(&vpn.name))
+            .filter(
+                preshared_keys::peer1
+                    .eq(name)
+                    .or(preshared_keys::peer2.eq(name)),
+            )
+            .load::<models::PresharedKey>(conn)?;
+        let edges = edges::table
+            .filter(edges::vpn.eq(&vpn.name))
+            .filter(edges::peer1.eq(name).or(edges::peer2.eq(name)))
+            .load::<models::Edge>(conn)?;
+        let settings = Settings::load(&vpn, &peer)?;
+        let mut node = Node {
+            network,
+            vpn,
+            peer,
+            remotes: vec![],
+            allowed_ips: vec![],
+            acl,
+            settings,
+        };
+        let active: Vec<_> = others
+            .into_iter()
+            .filter(|p| p.status == PeerStatus::Active)
+            .collect();
+        let linked: Vec<bool> = match node.vpn.topology {
+            Topology::Mesh => active.iter().map(|_| true).collect(),
+            Topology::Custom => active
+                .iter()
+                .map(|p| edges.iter().any(|e| e.peer1 == p.name || e.peer2 == p.name))
+                .collect(),
+            Topology::Hub => active.iter().map(|p| node.peer.hub || p.hub).collect(),
+        };
+        let own_ips = |p: &models::Peer| {
+            ips.iter()
+                .filter(|ip| ip.peer_name == p.name)
+                .map(|ip| {
+                    parse_net(&ip.address).ok_or_else(|| {
+                        anyhow!("invalid allowed ip {} for peer {}", ip.address, p.name)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        node.allowed_ips = own_ips(&node.peer)?;
+        // spokes reach the rest of the VPN, and the networks behind the other spokes, through
+        // the first hub. Other hubs are only used for their own allowed ips
+        let mut through_hub = vec![];
+        if node.vpn.topology == Topology::Hub && !node.peer.hub {
+            if !active.iter().any(|p| p.hub) {
+                return Err(anyhow!(
+                    "VPN {} uses the hub topology but has no active hub peers",
+                    node.vpn.name
+                ));
+            }
+            let (v4, v6) = node.subnets();
+            through_hub.push(IpNet::V4(v4));
+            through_hub.push(IpNet::V6(v6));
+            for spoke in active.iter().filter(|p| !p.hub) {
+                for ip in own_ips(spoke)? {
+                    let covered = match ip {
+                        IpNet::V4(n) => v4.contains(&n),
+                        IpNet::V6(n) => v6.contains(&n),
+                    };
+                    if !covered && !through_hub.contains(&ip) {
+                        through_hub.push(ip);
+                    }
+                }
+            }
+        }
+        for (p, _) in active.into_iter().zip(linked).filter(|(_, l)| *l) {
+            let mut allowed_ips = own_ips(&p)?;
+            if p.hub {
+                allowed_ips.append(&mut through_hub);
+            }
+            let preshared_key = psks
+                .iter()
+                .find(|k| k.peer1 == p.name || k.peer2 == p.name)
+                .map(|k| k.key.clone());
+            node.remotes.push(Remote {
+                endpoint: p.endpoint(),
+                peer: p,
+                allowed_ips,
+                preshared_key,
+            });
+        }
+        if node.full_tunnel() {
+            let exclude_lan = node.peer.exclude_lan;
+            let gateway = node.gateway_mut().ok_or_else(|| {
+                anyhow!(
+                    "peer {} uses a full tunnel but has no hub or peer with an endpoint to \
+                     route through",
+                    name
+                )
+            })?;
+            gateway.allowed_ips = tunnel::allowed_ips(&gateway.allowed_ips, exclude_lan);
+        }
+        if let Some(backend) = node.peer.firewall {
+            let (up, down) = firewall::hooks(&node, backend)?;
+            node.settings.post_up.extend(up);
+            node.settings.post_down.extend(down);
+        }
+        Ok(node)
+    }
+
+    /// the remote a full tunnel goes through: the first hub, or the first remote with an
+    /// endpoint for the other topologies
+    fn gateway_mut(&mut self) -> Option<&mut Remote> {
+        match self.vpn.topology {
+            Topology::Hub => self.remotes.iter_mut().find(|r| r.peer.hub),
+            _ => self.remotes.iter_mut().find(|r| r.endpoint.is_some()),
+        }
+    }
+
+    /// true if the default routes go through the VPN
+    pub fn full_tunnel(&self) -> bool {
+        self.peer.tunnel == TunnelMode::Full
+    }
+
+    /// name of the wireguard interface on the node
+    pub fn interface_name(&self) -> String {
+        format!("wg{}", self.vpn.index_in_network.unwrap_or(0))
+    }
+
+    /// the VPN subnets
+    pub fn subnets(&self) -> (Ipv4Net, Ipv6Net) {
+        (*self.vpn.address_v4, *self.vpn.address_v6)
+    }
+
+    /// allowed ips of the remotes that are not already routed by the VPN subnets, none when
+    /// routing is disabled with `Table = off`
+    pub fn routes(&self) -> Vec<IpNet> {
+        let (v4, v6) = self.subnets();
+        let mut routes: Vec<IpNet> = vec![];
+        if self.settings.table.as_deref() == Some("off") {
+            return routes;
+        }
+        for ip in self.remotes.iter().flat_map(|r| r.allowed_ips.iter()) {
+            let covered = match ip {
+                IpNet::V4(n) => v4.contains(n),
+                IpNet::V6(n) => v6.contains(n),
+            };
+            if !covered && !routes.contains(ip) {
+                routes.push(*ip);
+            }
+        }
+        routes
+    }
+
+    /// addresses of the peer, with the prefix length of the VPN subnets
+    pub fn addresses(&self) -> Result<Vec<IpNet>> {
+        let (v4, v6) = self.subnets();
+        Ok(vec![
+            IpNet::V4(Ipv4Net::new(*self.peer.address_v4, v4.prefix_len())?),
+            IpNet::V6(Ipv6Net::new(*self.peer.address_v6, v6.prefix_len())?),
+        ])
+    }
+
+    /// list the remotes rotated since their last confirmation with the public key they had
+    /// before, until they confirm the new one or their grace period ends. The old key replaces
+    /// the new one instead of going next to it: WireGuard gives overlapping AllowedIPs to a
+    /// single peer, so only one of the two keys could be reached anyway
+    pub fn add_retired_keys(&mut self, conn: &SqliteConnection) -> Result<()> {
+        let retired = key_history::table
+            .filter(key_history::vpn.eq(&self.vpn.name))
+            .filter(key_history::grace_until.gt(expiry::now()))
+            .order(key_history::id)
+            .load::<models::RetiredKey>(conn)?;
+        for remote in &mut self.remotes {
+            // the oldest one, a peer rotated twice without confirming still runs it
+            if let Some(key) = retired.iter().find(|k| k.peer == remote.peer.name) {
+                remote.peer.public_key = key.public_key.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// the port to listen on, only set for peers others connect to
+    pub fn listen_port(&self) -> Option<u16> {
+        self.peer.listen_port.and_then(|p| u16::try_from(p).ok())
+    }
+
+    /// the private key, if known
+    pub fn private_key(&self) -> Option<&str> {
+        self.peer.private_key.as_deref()
+    }
+
+    pub fn dns(&self) -> Vec<String> {
+        self.peer
+            .dns
+            .iter()
+            .flat_map(|d| d.split(','))
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty())
+            .collect()
+    }
+}
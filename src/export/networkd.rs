@@ -0,0 +1,103 @@
+//! systemd-networkd `.netdev` and `.network` files
+use super::{File, Node};
+
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+
+/// where the private key is expected to be installed on the node
+const KEY_DIR: &str = "/etc/systemd/network";
+
+pub fn files(node: &Node) -> Result<Vec<File>> {
+    let iface = node.interface_name();
+    let key_file = format!("{}.key", iface);
+    let private_key = node.private_key().ok_or_else(|| {
+        anyhow!(
+            "the private key of peer {} is not known, cannot export it",
+            node.peer.name
+        )
+    })?;
+    node.settings.check_no_hooks("systemd-networkd")?;
+    if node.full_tunnel() {
+        // the default route would also catch the packets to the endpoint
+        return Err(anyhow!(
+            "full tunnels need policy routing, which systemd-networkd cannot set up by itself"
+        ));
+    }
+
+    let mut netdev = String::new();
+    writeln!(netdev, "# {} in VPN {}", node.peer.name, node.vpn.name)?;
+    writeln!(netdev, "[NetDev]")?;
+    writeln!(netdev, "Name={}", iface)?;
+    writeln!(netdev, "Kind=wireguard")?;
+    writeln!(netdev, "Description={}", node.vpn.name)?;
+    if let Some(mtu) = node.settings.mtu {
+        writeln!(netdev, "MTUBytes={}", mtu)?;
+    }
+    writeln!(netdev)?;
+    writeln!(netdev, "[WireGuard]")?;
+    writeln!(netdev, "PrivateKeyFile={}/{}", KEY_DIR, key_file)?;
+    if let Some(port) = node.listen_port() {
+        writeln!(netdev, "ListenPort={}", port)?;
+    }
+    if let Some(fwmark) = node.settings.fwmark {
+        writeln!(netdev, "FirewallMark={}", fwmark)?;
+    }
+    for remote in &node.remotes {
+        writeln!(netdev)?;
+        writeln!(netdev, "# {}", remote.peer.name)?;
+        writeln!(netdev, "[WireGuardPeer]")?;
+        writeln!(netdev, "PublicKey={}", remote.peer.public_key)?;
+        if let Some(key) = &remote.preshared_key {
+            writeln!(netdev, "PresharedKey={}", key)?;
+        }
+        for ip in &remote.allowed_ips {
+            writeln!(netdev, "AllowedIPs={}", ip)?;
+        }
+        if let Some(endpoint) = &remote.endpoint {
+            writeln!(netdev, "Endpoint={}", endpoint)?;
+        }
+        if let Some(keepalive) = remote.persistent_keepalive() {
+            writeln!(netdev, "PersistentKeepalive={}", keepalive)?;
+        }
+    }
+
+    let mut network = String::new();
+    writeln!(network, "[Match]")?;
+    writeln!(network, "Name={}", iface)?;
+    writeln!(network)?;
+    writeln!(network, "[Network]")?;
+    for address in node.addresses()? {
+        writeln!(network, "Address={}", address)?;
+    }
+    for dns in node.dns() {
+        writeln!(network, "DNS={}", dns)?;
+    }
+    // networkd does not route the allowed ips like wg-quick does
+    for route in node.routes() {
+        writeln!(network)?;
+        writeln!(network, "[Route]")?;
+        writeln!(network, "Destination={}", route)?;
+        if let Some(table) = node.settings.route_table() {
+            writeln!(network, "Table={}", table)?;
+        }
+    }
+
+    Ok(vec![
+        File {
+            name: format!("{}.netdev", iface),
+            contents: netdev,
+            // preshared keys are inline
+            secret: true,
+        },
+        File {
+            name: format!("{}.network", iface),
+            contents: network,
+            secret: false,
+        },
+        File {
+            name: key_file,
+            contents: format!("{}\n", private_key),
+            secret: true,
+        },
+    ])
+}
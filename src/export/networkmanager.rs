@@ -0,0 +1,104 @@
+//! NetworkManager `.nmconnection` keyfiles
+use super::{File, Node};
+
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+use std::net::IpAddr;
+
+pub fn files(node: &Node) -> Result<Vec<File>> {
+    // a profile without it would be imported fine, and then never connect
+    let private_key = node.private_key().ok_or_else(|| {
+        anyhow!(
+            "the private key of peer {} is not known, cannot export it",
+            node.peer.name
+        )
+    })?;
+    node.settings.check_no_hooks("NetworkManager")?;
+    let route_table = node
+        .settings
+        .route_table()
+        .map(|t| {
+            t.parse::<u32>().map_err(|_| {
+                anyhow!(
+                    "NetworkManager only supports numeric routing tables, not {}",
+                    t
+                )
+            })
+        })
+        .transpose()?;
+    let mut out = String::new();
+    writeln!(out, "[connection]")?;
+    writeln!(out, "id={}", node.vpn.name)?;
+    writeln!(out, "type=wireguard")?;
+    writeln!(out, "interface-name={}", node.interface_name())?;
+    writeln!(out)?;
+    writeln!(out, "[wireguard]")?;
+    writeln!(out, "private-key={}", private_key)?;
+    if let Some(port) = node.listen_port() {
+        writeln!(out, "listen-port={}", port)?;
+    }
+    if let Some(fwmark) = node.settings.fwmark {
+        writeln!(out, "fwmark={}", fwmark)?;
+    }
+    if let Some(mtu) = node.settings.mtu {
+        writeln!(out, "mtu={}", mtu)?;
+    }
+    if node.settings.table.as_deref() == Some("off") {
+        writeln!(out, "peer-routes=false")?;
+    }
+    for remote in &node.remotes {
+        writeln!(out)?;
+        writeln!(out, "[wireguard-peer.{}]", remote.peer.public_key)?;
+        if let Some(endpoint) = &remote.endpoint {
+            writeln!(out, "endpoint={}", endpoint)?;
+        }
+        if let Some(keepalive) = remote.persistent_keepalive() {
+            writeln!(out, "persistent-keepalive={}", keepalive)?;
+        }
+        if let Some(key) = &remote.preshared_key {
+            writeln!(out, "preshared-key={}", key)?;
+            // stored in the file, not in a secret agent
+            writeln!(out, "preshared-key-flags=0")?;
+        }
+        write!(out, "allowed-ips=")?;
+        for ip in &remote.allowed_ips {
+            write!(out, "{};", ip)?;
+        }
+        writeln!(out)?;
+    }
+
+    // addresses go in the DNS lists of their family, anything else is a search domain
+    let (mut dns_v4, mut dns_v6, mut search) = (vec![], vec![], vec![]);
+    for dns in node.dns() {
+        match dns.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => dns_v4.push(dns),
+            Ok(IpAddr::V6(_)) => dns_v6.push(dns),
+            Err(_) => search.push(dns),
+        }
+    }
+    for address in node.addresses()? {
+        let (section, dns) = match address {
+            ipnet::IpNet::V4(_) => ("ipv4", &dns_v4),
+            ipnet::IpNet::V6(_) => ("ipv6", &dns_v6),
+        };
+        writeln!(out)?;
+        writeln!(out, "[{}]", section)?;
+        writeln!(out, "method=manual")?;
+        writeln!(out, "address1={}", address)?;
+        if let Some(table) = route_table {
+            writeln!(out, "route-table={}", table)?;
+        }
+        if !dns.is_empty() {
+            writeln!(out, "dns={};", dns.join(";"))?;
+        }
+        if !search.is_empty() {
+            writeln!(out, "dns-search={};", search.join(";"))?;
+        }
+    }
+
+    Ok(vec![File {
+        name: format!("{}.nmconnection", node.vpn.name),
+        contents: out,
+        secret: true,
+    }])
+}
@@ -0,0 +1,149 @@
+//! OpenWrt UCI network configuration, as `uci` commands or as an /etc/config/network fragment
+use super::{File, Node};
+
+use anyhow::Result;
+use std::fmt::Write;
+
+enum Value {
+    Option(String),
+    List(Vec<String>),
+}
+
+struct Section {
+    kind: String,
+    name: String,
+    options: Vec<(&'static str, Value)>,
+}
+
+/// uci section names can only contain alphanumeric characters and underscores
+fn section_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// quote a value for both the shell and /etc/config files. uci reads the latter line by line,
+/// so control characters, which only names and descriptions can have, become spaces
+fn quote(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn sections(node: &Node) -> Result<Vec<Section>> {
+    node.settings.check_no_hooks("OpenWrt")?;
+    let iface = node.interface_name();
+    let mut interface = vec![("proto", Value::Option("wireguard".into()))];
+    if let Some(key) = node.private_key() {
+        interface.push(("private_key", Value::Option(key.into())));
+    }
+    if let Some(port) = node.listen_port() {
+        interface.push(("listen_port", Value::Option(port.to_string())));
+    }
+    if let Some(fwmark) = node.settings.fwmark {
+        interface.push(("fwmark", Value::Option(format!("{:#x}", fwmark))));
+    }
+    if let Some(mtu) = node.settings.mtu {
+        interface.push(("mtu", Value::Option(mtu.to_string())));
+    }
+    if let Some(table) = node.settings.route_table() {
+        interface.push(("ip4table", Value::Option(table.into())));
+        interface.push(("ip6table", Value::Option(table.into())));
+    }
+    interface.push((
+        "addresses",
+        Value::List(node.addresses()?.iter().map(|a| a.to_string()).collect()),
+    ));
+    let dns = node.dns();
+    if !dns.is_empty() {
+        interface.push(("dns", Value::List(dns)));
+    }
+    let mut sections = vec![Section {
+        kind: "interface".into(),
+        name: iface.clone(),
+        options: interface,
+    }];
+    for remote in &node.remotes {
+        let mut options = vec![
+            ("description", Value::Option(remote.peer.name.clone())),
+            ("public_key", Value::Option(remote.peer.public_key.clone())),
+        ];
+        if let Some(key) = &remote.preshared_key {
+            options.push(("preshared_key", Value::Option(key.clone())));
+        }
+        options.push((
+            "allowed_ips",
+            Value::List(remote.allowed_ips.iter().map(|a| a.to_string()).collect()),
+        ));
+        let route = node.settings.table.as_deref() != Some("off");
+        options.push((
+            "route_allowed_ips",
+            Value::Option((route as u8).to_string()),
+        ));
+        if let Some(endpoint) = &remote.endpoint {
+            options.push(("endpoint_host", Value::Option(endpoint.host.clone())));
+            options.push(("endpoint_port", Value::Option(endpoint.port.to_string())));
+        }
+        if let Some(keepalive) = remote.persistent_keepalive() {
+            options.push(("persistent_keepalive", Value::Option(keepalive.to_string())));
+        }
+        sections.push(Section {
+            kind: format!("wireguard_{}", iface),
+            name: format!("{}_{}", iface, section_name(&remote.peer.name)),
+            options,
+        });
+    }
+    Ok(sections)
+}
+
+/// `uci` commands, replacing any existing section with the same name
+pub fn uci_files(node: &Node) -> Result<Vec<File>> {
+    let mut out = String::new();
+    for section in sections(node)? {
+        let path = format!("network.{}", section.name);
+        writeln!(out, "uci -q delete {}", path)?;
+        writeln!(out, "uci set {}={}", path, section.kind)?;
+        for (key, value) in section.options {
+            match value {
+                Value::Option(v) => writeln!(out, "uci set {}.{}={}", path, key, quote(&v))?,
+                Value::List(values) => {
+                    for v in values {
+                        writeln!(out, "uci add_list {}.{}={}", path, key, quote(&v))?
+                    }
+                }
+            }
+        }
+    }
+    writeln!(out, "uci commit network")?;
+    Ok(vec![File {
+        name: format!("{}.uci.sh", node.interface_name()),
+        contents: out,
+        secret: true,
+    }])
+}
+
+/// fragment to append to /etc/config/network
+pub fn config_files(node: &Node) -> Result<Vec<File>> {
+    let mut out = String::new();
+    for section in sections(node)? {
+        writeln!(out, "config {} {}", section.kind, quote(&section.name))?;
+        for (key, value) in section.options {
+            match value {
+                Value::Option(v) => writeln!(out, "\toption {} {}", key, quote(&v))?,
+                Value::List(values) => {
+                    for v in values {
+                        writeln!(out, "\tlist {} {}", key, quote(&v))?
+                    }
+                }
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(vec![File {
+        name: format!("network.{}", node.interface_name()),
+        contents: out,
+        secret: true,
+    }])
+}
@@ -0,0 +1,80 @@
+//! QR codes of wg-quick configurations, for the mobile apps
+use anyhow::{anyhow, Context, Result};
+use image::png::PngEncoder;
+use image::ColorType;
+use qrcode::render::{svg, unicode};
+use qrcode::types::QrError;
+use qrcode::{EcLevel, QrCode};
+use std::io::Write;
+use std::path::Path;
+
+/// pixels per module in png/svg output
+const MODULE_SIZE: u32 = 8;
+
+/// Encode `data`, lowering the error correction when it doesn't fit.
+/// Returns the code and an optional warning for the user
+pub fn encode(data: &str) -> Result<(QrCode, Option<String>)> {
+    match QrCode::with_error_correction_level(data, EcLevel::M) {
+        Ok(code) => Ok((code, None)),
+        Err(QrError::DataTooLong) => match QrCode::with_error_correction_level(data, EcLevel::L) {
+            Ok(code) => Ok((
+                code,
+                Some(String::from(
+                    "configuration is too large for the default error correction, \
+                     the QR code may be hard to scan",
+                )),
+            )),
+            Err(QrError::DataTooLong) => Err(anyhow!(
+                "configuration is {} bytes, more than a QR code can hold",
+                data.len()
+            )),
+            Err(e) => Err(anyhow!("cannot encode QR code: {}", e)),
+        },
+        Err(e) => Err(anyhow!("cannot encode QR code: {}", e)),
+    }
+}
+
+/// render with unicode half blocks, two rows of modules per line. Colors are inverted
+/// as most terminals have a dark background
+pub fn terminal(code: &QrCode) -> String {
+    code.render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .quiet_zone(true)
+        .build()
+}
+
+/// write a png or svg file, depending on the extension of `path`. The code holds a private
+/// key, so the file is only readable by its owner
+pub fn write(code: &QrCode, path: &Path) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    let context = || format!("cannot write {}", path.display());
+    match extension.as_deref() {
+        Some("png") => {
+            let image = code
+                .render::<image::Luma<u8>>()
+                .module_dimensions(MODULE_SIZE, MODULE_SIZE)
+                .build();
+            let file = super::create_file(path, true).with_context(context)?;
+            PngEncoder::new(file)
+                .encode(&image, image.width(), image.height(), ColorType::L8)
+                .with_context(context)
+        }
+        Some("svg") => {
+            let image = code
+                .render::<svg::Color<'_>>()
+                .module_dimensions(MODULE_SIZE, MODULE_SIZE)
+                .build();
+            super::create_file(path, true)
+                .and_then(|mut f| f.write_all(image.as_bytes()))
+                .with_context(context)
+        }
+        _ => Err(anyhow!(
+            "unsupported file type for {}, use .png or .svg",
+            path.display()
+        )),
+    }
+}
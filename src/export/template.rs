@@ -0,0 +1,188 @@
+//! Render peers through user supplied tera templates
+use super::{File, Node, Remote};
+use crate::endpoint::Endpoint;
+use crate::models;
+use crate::types::{Ipv4Address, Ipv6Address, PeerStatus, TunnelMode};
+
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use tera::{Tera, Value};
+
+/// templates shipped with vpnutils, can be overridden by files with the same name in
+/// `$XDG_CONFIG_HOME/vpnutils/templates`
+const BUNDLED: &[(&str, &str)] = &[(
+    "wg-quick.conf",
+    include_str!("templates/wg-quick.conf.tera"),
+)];
+
+/// `{{ "10.0.0.0/24" | cidr_host(n=5) }}` gives `10.0.0.5`, like terraform's cidrhost
+fn cidr_host(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let net: IpNet = tera::from_value::<String>(value.clone())?
+        .parse()
+        .map_err(|e| tera::Error::msg(format!("cidr_host: {}", e)))?;
+    let n = args
+        .get("n")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| tera::Error::msg("cidr_host: missing integer argument `n`"))?;
+    let host = match net {
+        IpNet::V4(net) => u32::try_from(n)
+            .ok()
+            .and_then(|n| u32::from(net.network()).checked_add(n))
+            .map(|a| IpAddr::V4(Ipv4Addr::from(a))),
+        IpNet::V6(net) => u128::from(net.network())
+            .checked_add(n.into())
+            .map(|a| IpAddr::V6(Ipv6Addr::from(a))),
+    }
+    .filter(|a| net.contains(a))
+    .ok_or_else(|| tera::Error::msg(format!("cidr_host: {} is too small for host {}", net, n)))?;
+    Ok(Value::String(host.to_string()))
+}
+
+/// find a template: an existing path, a user override, or a bundled template
+fn load(name: &str) -> Result<(String, String)> {
+    let path = Path::new(name);
+    let user = xdg::BaseDirectories::with_prefix("vpnutils")
+        .ok()
+        .and_then(|dirs| dirs.find_config_file(PathBuf::from("templates").join(name)));
+    let file = match path.is_file() {
+        true => Some(path.to_path_buf()),
+        false => user,
+    };
+    let content = match file {
+        Some(file) => std::fs::read_to_string(&file)
+            .with_context(|| format!("cannot read template {}", file.display()))?,
+        None => BUNDLED
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, t)| t.to_string())
+            .ok_or_else(|| {
+                anyhow!(
+                    "template {} not found, bundled templates are: {}",
+                    name,
+                    BUNDLED
+                        .iter()
+                        .map(|(n, _)| *n)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?,
+    };
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| name.to_string());
+    let file_name = file_name
+        .strip_suffix(".tera")
+        .unwrap_or(&file_name)
+        .to_string();
+    Ok((file_name, content))
+}
+
+/// what templates see of a peer: everything but its private key. Only the one of the node being
+/// rendered is given, as `private_key`, so that an export never has the secrets of other peers
+#[derive(Serialize)]
+pub struct PeerView<'a> {
+    pub vpn_name: &'a str,
+    pub name: &'a str,
+    pub index_in_vpn: Option<i32>,
+    pub public_key: &'a str,
+    pub address_v4: Ipv4Address,
+    pub address_v6: Ipv6Address,
+    pub dns: Option<&'a str>,
+    pub status: PeerStatus,
+    pub hub: bool,
+    pub listen_port: Option<i32>,
+    pub endpoint_host: Option<&'a str>,
+    pub endpoint_port: Option<i32>,
+    pub persistent_keepalive: Option<i32>,
+    pub tunnel: TunnelMode,
+    pub exclude_lan: bool,
+    pub isolated: bool,
+    pub expires_at: Option<&'a str>,
+    pub key_created_at: Option<&'a str>,
+}
+
+impl<'a> From<&'a models::Peer> for PeerView<'a> {
+    fn from(p: &'a models::Peer) -> Self {
+        PeerView {
+            vpn_name: &p.vpn_name,
+            name: &p.name,
+            index_in_vpn: p.index_in_vpn,
+            public_key: &p.public_key,
+            address_v4: p.address_v4,
+            address_v6: p.address_v6,
+            dns: p.dns.as_deref(),
+            status: p.status,
+            hub: p.hub,
+            listen_port: p.listen_port,
+            endpoint_host: p.endpoint_host.as_deref(),
+            endpoint_port: p.endpoint_port,
+            persistent_keepalive: p.persistent_keepalive,
+            tunnel: p.tunnel,
+            exclude_lan: p.exclude_lan,
+            isolated: p.isolated,
+            expires_at: p.expires_at.as_deref(),
+            key_created_at: p.key_created_at.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RemoteView<'a> {
+    peer: PeerView<'a>,
+    allowed_ips: &'a [IpNet],
+    /// shared with the node being rendered
+    preshared_key: Option<&'a str>,
+    endpoint: Option<&'a Endpoint>,
+}
+
+impl<'a> From<&'a Remote> for RemoteView<'a> {
+    fn from(r: &'a Remote) -> Self {
+        RemoteView {
+            peer: PeerView::from(&r.peer),
+            allowed_ips: &r.allowed_ips,
+            preshared_key: r.preshared_key.as_deref(),
+            endpoint: r.endpoint.as_ref(),
+        }
+    }
+}
+
+fn context(node: &Node) -> Result<tera::Context> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("interface", &node.interface_name());
+    ctx.insert("network", &node.network);
+    ctx.insert("vpn", &node.vpn);
+    ctx.insert("peer", &PeerView::from(&node.peer));
+    // no empty strings for unknown private keys in templates
+    ctx.insert("private_key", &node.private_key());
+    ctx.insert("addresses", &node.addresses()?);
+    ctx.insert("dns", &node.dns());
+    ctx.insert("routes", &node.routes());
+    let remotes: Vec<RemoteView> = node.remotes.iter().map(RemoteView::from).collect();
+    ctx.insert("remotes", &remotes);
+    ctx.insert("settings", &node.settings);
+    Ok(ctx)
+}
+
+/// render `node` with the template `name`, the output file is named after the template,
+/// without the `.tera` extension
+pub fn files(node: &Node, name: &str) -> Result<Vec<File>> {
+    let (file_name, content) = load(name)?;
+    let mut tera = Tera::default();
+    tera.register_filter("cidr_host", cidr_host);
+    tera.add_raw_template(&file_name, &content)
+        .with_context(|| format!("invalid template {}", name))?;
+    let contents = tera
+        .render(&file_name, &context(node)?)
+        .with_context(|| format!("cannot render template {}", name))?;
+    Ok(vec![File {
+        name: file_name,
+        contents,
+        secret: true,
+    }])
+}
@@ -0,0 +1,34 @@
+//! AllowedIPs of the gateway for peers sending all their traffic through the VPN
+use ipnet::IpNet;
+
+/// private IPv4 ranges, kept on the LAN when `exclude_lan` is set
+const RFC1918: [&str; 3] = ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"];
+
+/// `net` without the `excluded` networks, split in the fewest subnets
+fn exclude(net: IpNet, excluded: &[IpNet]) -> Vec<IpNet> {
+    if excluded.iter().any(|e| e.contains(&net)) {
+        return vec![];
+    }
+    if !excluded.iter().any(|e| net.contains(e)) {
+        return vec![net];
+    }
+    net.subnets(net.prefix_len() + 1)
+        .map(|halves| halves.flat_map(|half| exclude(half, excluded)).collect())
+        .unwrap_or_default()
+}
+
+/// default routes for the gateway, keeping its current allowed ips `routed` even when they are
+/// private ranges. Those are only its own addresses and networks, plus the VPN subnets and the
+/// LANs behind the spokes when it is a hub: the other peers keep their own [Peer] sections
+pub fn allowed_ips(routed: &[IpNet], exclude_lan: bool) -> Vec<IpNet> {
+    let excluded: Vec<IpNet> = match exclude_lan {
+        true => RFC1918.iter().map(|n| n.parse().unwrap()).collect(),
+        false => vec![],
+    };
+    let mut ips: Vec<IpNet> = ["0.0.0.0/0", "::/0"]
+        .iter()
+        .flat_map(|n| exclude(n.parse().unwrap(), &excluded))
+        .collect();
+    ips.extend_from_slice(routed);
+    IpNet::aggregate(&ips)
+}
@@ -0,0 +1,44 @@
+//! wg-quick configuration files
+use super::{File, Node};
+use crate::wgconf;
+
+use anyhow::Result;
+
+pub fn config(node: &Node) -> Result<wgconf::Config> {
+    Ok(wgconf::Config {
+        interface: Some(wgconf::Interface {
+            name: Some(node.peer.name.clone()),
+            private_key: node.private_key().map(String::from),
+            addresses: node.addresses()?,
+            listen_port: node.listen_port(),
+            fwmark: node.settings.fwmark,
+            dns: node.dns(),
+            mtu: node.settings.mtu,
+            table: node.settings.table.clone(),
+            pre_up: node.settings.pre_up.clone(),
+            post_up: node.settings.post_up.clone(),
+            pre_down: node.settings.pre_down.clone(),
+            post_down: node.settings.post_down.clone(),
+        }),
+        peers: node
+            .remotes
+            .iter()
+            .map(|r| wgconf::Peer {
+                name: Some(r.peer.name.clone()),
+                public_key: r.peer.public_key.clone(),
+                preshared_key: r.preshared_key.clone(),
+                allowed_ips: r.allowed_ips.clone(),
+                endpoint: r.endpoint.as_ref().map(|e| e.to_string()),
+                persistent_keepalive: r.persistent_keepalive(),
+            })
+            .collect(),
+    })
+}
+
+pub fn files(node: &Node) -> Result<Vec<File>> {
+    Ok(vec![File {
+        name: format!("{}.conf", node.interface_name()),
+        contents: config(node)?.to_string(),
+        secret: true,
+    }])
+}
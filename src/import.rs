@@ -0,0 +1,515 @@
+//! Import existing wireguard configurations into a VPN
+use crate::endpoint::Endpoint;
+use crate::error::CommandError;
+use crate::keys;
+use crate::models;
+use crate::schema::{allowed_ips, peers, preshared_keys, vpns};
+use crate::types::{Ipv4Address, Ipv4Network, Ipv6Address, Ipv6Network, PeerStatus};
+use crate::wgconf;
+
+use anyhow::{anyhow, Context, Result};
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A configuration file, already parsed, together with where it comes from
+pub struct Source {
+    /// used as the default name for the [Interface] peer, usually the file stem
+    pub name: String,
+    pub config: wgconf::Config,
+}
+
+#[derive(Default, Debug)]
+pub struct Summary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub allowed_ips: usize,
+    pub preshared_keys: usize,
+    pub warnings: Vec<String>,
+}
+
+/// A peer as seen across all the imported files, deduplicated by public key
+#[derive(Default, Debug)]
+struct Node {
+    name: Option<String>,
+    public_key: String,
+    private_key: Option<String>,
+    address_v4: Option<Ipv4Addr>,
+    address_v6: Option<Ipv6Addr>,
+    endpoint: Option<Endpoint>,
+    listen_port: Option<u16>,
+    persistent_keepalive: Option<u16>,
+    dns: Vec<String>,
+    allowed_ips: Vec<IpNet>,
+}
+
+pub(crate) struct Subnets {
+    pub v4: Ipv4Net,
+    pub v6: Ipv6Net,
+}
+
+impl Subnets {
+    pub fn load(conn: &SqliteConnection, vpn: &str) -> Result<Self> {
+        let (v4, v6) = vpns::table
+            .find(vpn)
+            .select((vpns::address_v4, vpns::address_v6))
+            .first::<(Ipv4Network, Ipv6Network)>(conn)
+            .optional()?
+            .ok_or_else(|| CommandError::VpnNotFound(vpn.into()))?;
+        Ok(Subnets { v4: *v4, v6: *v6 })
+    }
+
+    fn contains(&self, addr: &IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(a) => self.v4.contains(a),
+            IpAddr::V6(a) => self.v6.contains(a),
+        }
+    }
+
+    /// true if `net` covers the whole VPN subnet, as routes to the VPN (or default routes) do
+    fn covered_by(&self, net: &IpNet) -> bool {
+        match net {
+            IpNet::V4(n) => n.contains(&self.v4),
+            IpNet::V6(n) => n.contains(&self.v6),
+        }
+    }
+}
+
+/// first address in the subnet not in `used`, skipping the network address
+pub(crate) fn first_free_v4(subnet: &Ipv4Net, used: &HashSet<IpAddr>) -> Option<Ipv4Addr> {
+    subnet
+        .hosts()
+        .find(|a| *a != subnet.network() && !used.contains(&IpAddr::V4(*a)))
+}
+
+/// first address in the subnet not in `used`, skipping the subnet-router anycast address
+pub(crate) fn first_free_v6(subnet: &Ipv6Net, used: &HashSet<IpAddr>) -> Option<Ipv6Addr> {
+    subnet
+        .hosts()
+        .find(|a| *a != subnet.network() && !used.contains(&IpAddr::V6(*a)))
+}
+
+/// (public key, public key, preshared key)
+type PresharedKey = (String, String, String);
+
+fn is_host(net: &IpNet) -> bool {
+    net.prefix_len() == net.max_prefix_len()
+}
+
+struct Nodes {
+    nodes: Vec<Node>,
+    by_key: HashMap<String, usize>,
+}
+
+impl Nodes {
+    fn get(&mut self, public_key: &str) -> &mut Node {
+        let idx = match self.by_key.get(public_key) {
+            Some(idx) => *idx,
+            None => {
+                self.nodes.push(Node {
+                    public_key: public_key.into(),
+                    ..Default::default()
+                });
+                self.by_key.insert(public_key.into(), self.nodes.len() - 1);
+                self.nodes.len() - 1
+            }
+        };
+        &mut self.nodes[idx]
+    }
+}
+
+impl Node {
+    fn set_address(&mut self, addr: IpAddr, source: &str) -> Result<()> {
+        let conflict = match addr {
+            IpAddr::V4(a) => *self.address_v4.get_or_insert(a) != a,
+            IpAddr::V6(a) => *self.address_v6.get_or_insert(a) != a,
+        };
+        if conflict {
+            return Err(anyhow!(
+                "{}: conflicting addresses for peer {}: {} was already assigned another address",
+                source,
+                self.public_key,
+                addr
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn collect(
+    sources: &[Source],
+    subnets: &Subnets,
+    summary: &mut Summary,
+) -> Result<(Vec<Node>, Vec<PresharedKey>)> {
+    let mut nodes = Nodes {
+        nodes: vec![],
+        by_key: HashMap::new(),
+    };
+    let mut psks = vec![];
+    for source in sources {
+        let interface_key = match &source.config.interface {
+            Some(wgconf::Interface {
+                private_key: Some(private_key),
+                ..
+            }) => Some(
+                keys::public_key(private_key)
+                    .with_context(|| format!("{}: invalid PrivateKey", source.name))?,
+            ),
+            _ => {
+                summary.warnings.push(format!(
+                    "{}: no [Interface] with a PrivateKey, only importing its peers",
+                    source.name
+                ));
+                None
+            }
+        };
+        if let (Some(interface), Some(public_key)) = (&source.config.interface, &interface_key) {
+            let node = nodes.get(public_key);
+            node.private_key = interface.private_key.clone();
+            if node.name.is_none() {
+                node.name = Some(
+                    interface
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| source.name.clone()),
+                );
+            }
+            for address in &interface.addresses {
+                if !subnets.contains(&address.addr()) {
+                    return Err(anyhow!(
+                        "{}: interface address {} is outside of the VPN subnets",
+                        source.name,
+                        address
+                    ));
+                }
+                node.set_address(address.addr(), &source.name)?;
+            }
+            if node.dns.is_empty() {
+                node.dns = interface.dns.clone();
+            }
+            if node.listen_port.is_none() {
+                node.listen_port = interface.listen_port;
+            }
+            let hooks = [
+                &interface.pre_up,
+                &interface.post_up,
+                &interface.pre_down,
+                &interface.post_down,
+            ];
+            if interface.mtu.is_some()
+                || interface.table.is_some()
+                || interface.fwmark.is_some()
+                || hooks.iter().any(|h| !h.is_empty())
+            {
+                summary.warnings.push(format!(
+                    "{}: MTU, Table, FwMark and hooks are not imported, use `peer settings`",
+                    source.name
+                ));
+            }
+        }
+        for peer in &source.config.peers {
+            keys::validate(&peer.public_key)
+                .with_context(|| format!("{}: invalid PublicKey", source.name))?;
+            let node = nodes.get(&peer.public_key);
+            if node.name.is_none() {
+                node.name = peer.name.clone();
+            }
+            for net in &peer.allowed_ips {
+                if is_host(net) && subnets.contains(&net.addr()) {
+                    node.set_address(net.addr(), &source.name)?;
+                } else if subnets.covered_by(net) {
+                    // routes through this peer to the whole VPN depend on the topology,
+                    // they are not something this peer owns
+                    summary.warnings.push(format!(
+                        "{}: skipping AllowedIPs {} for peer {}, it covers the whole VPN",
+                        source.name, net, peer.public_key
+                    ));
+                } else if !node.allowed_ips.contains(net) {
+                    node.allowed_ips.push(*net);
+                }
+            }
+            let endpoint = peer
+                .endpoint
+                .as_deref()
+                .map(str::parse::<Endpoint>)
+                .transpose()
+                .with_context(|| format!("{}: invalid Endpoint", source.name))?;
+            match (&node.endpoint, &endpoint) {
+                (None, Some(endpoint)) => node.endpoint = Some(endpoint.clone()),
+                (Some(existing), Some(endpoint)) if existing != endpoint => {
+                    summary.warnings.push(format!(
+                        "{}: ignoring endpoint {} for peer {}, already set to {}",
+                        source.name, endpoint, peer.public_key, existing
+                    ))
+                }
+                _ => {}
+            }
+            if node.persistent_keepalive.is_none() {
+                node.persistent_keepalive = peer.persistent_keepalive;
+            }
+            if let (Some(psk), Some(interface_key)) = (&peer.preshared_key, &interface_key) {
+                keys::validate(psk)
+                    .with_context(|| format!("{}: invalid PresharedKey", source.name))?;
+                psks.push((interface_key.clone(), peer.public_key.clone(), psk.clone()));
+            }
+        }
+    }
+    Ok((nodes.nodes, psks))
+}
+
+/// name for peers that don't have one in any of the files
+fn default_name(public_key: &str) -> String {
+    let prefix: String = public_key
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(8)
+        .collect();
+    format!("peer-{}", prefix)
+}
+
+/// Import the parsed configuration files into `vpn`, in a single transaction.
+/// Peers are matched by public key with existing ones, and across files.
+pub fn import_configs(conn: &SqliteConnection, vpn: &str, sources: &[Source]) -> Result<Summary> {
+    let subnets = Subnets::load(conn, vpn)?;
+    let mut summary = Summary::default();
+    let (nodes, psks) = collect(sources, &subnets, &mut summary)?;
+
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        let existing = peers::table
+            .filter(peers::vpn_name.eq(vpn))
+            .select((
+                peers::name,
+                peers::pubkey,
+                peers::privkey,
+                peers::address_v4,
+                peers::address_v6,
+                peers::endpoint_host,
+            ))
+            .load::<(
+                String,
+                String,
+                Option<String>,
+                Ipv4Address,
+                Ipv6Address,
+                Option<String>,
+            )>(conn)?;
+        let mut names: HashMap<String, String> = existing
+            .iter()
+            .map(|p| (p.1.clone(), p.0.clone()))
+            .collect();
+        let mut taken: HashSet<String> = existing.iter().map(|p| p.0.clone()).collect();
+        let mut used: HashSet<IpAddr> = existing
+            .iter()
+            .flat_map(|p| vec![IpAddr::V4(*p.3), IpAddr::V6(*p.4)])
+            .chain(nodes.iter().flat_map(|n| {
+                vec![n.address_v4.map(IpAddr::V4), n.address_v6.map(IpAddr::V6)]
+                    .into_iter()
+                    .flatten()
+            }))
+            .collect();
+
+        for node in &nodes {
+            let name = match existing.iter().find(|p| p.1 == node.public_key) {
+                Some((name, _, privkey, address_v4, address_v6, endpoint_host)) => {
+                    let mut changes = models::PeerChanges::default();
+                    if let (None, Some(key)) = (privkey, &node.private_key) {
+                        changes.private_key = Some(Some(key.clone()));
+                    }
+                    if let (None, Some(e)) = (endpoint_host, &node.endpoint) {
+                        changes.endpoint_host = Some(e.host.clone());
+                        changes.endpoint_port = Some(i32::from(e.port));
+                    }
+                    let mut updated =
+                        changes.private_key.is_some() || changes.endpoint_host.is_some();
+                    if updated {
+                        diesel::update(peers::table.find((vpn, name)))
+                            .set(&changes)
+                            .execute(conn)?;
+                    }
+                    // only fill in what is unknown, the database wins over the files
+                    if let Some(port) = node.listen_port {
+                        updated |= diesel::update(peers::table.find((vpn, name)))
+                            .filter(peers::listen_port.is_null())
+                            .set(peers::listen_port.eq(i32::from(port)))
+                            .execute(conn)?
+                            > 0;
+                    }
+                    if let Some(keepalive) = node.persistent_keepalive {
+                        updated |= diesel::update(peers::table.find((vpn, name)))
+                            .filter(peers::persistent_keepalive.is_null())
+                            .set(peers::persistent_keepalive.eq(i32::from(keepalive)))
+                            .execute(conn)?
+                            > 0;
+                    }
+                    let addresses = [
+                        node.address_v4
+                            .map(|a| (IpAddr::V4(a), IpAddr::V4(**address_v4))),
+                        node.address_v6
+                            .map(|a| (IpAddr::V6(a), IpAddr::V6(**address_v6))),
+                    ];
+                    for (new, old) in addresses.iter().flatten() {
+                        if new != old {
+                            summary.warnings.push(format!(
+                                "peer {} has address {} in the database, ignoring {}",
+                                name, old, new
+                            ));
+                        }
+                    }
+                    if updated {
+                        summary.updated.push(name.clone());
+                    }
+                    name.clone()
+                }
+                None => {
+                    let name = node
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| default_name(&node.public_key));
+                    if !taken.insert(name.clone()) {
+                        return Err(anyhow!(
+                            "cannot import peer {}: name {} is already in use, \
+                             add a `# Name = ` comment to its section",
+                            node.public_key,
+                            name
+                        ));
+                    }
+                    let address_v4 = match node.address_v4 {
+                        Some(a) => a,
+                        None => first_free_v4(&subnets.v4, &used).ok_or_else(|| {
+                            anyhow!("no free ipv4 address left in {}", subnets.v4)
+                        })?,
+                    };
+                    let address_v6 = match node.address_v6 {
+                        Some(a) => a,
+                        None => first_free_v6(&subnets.v6, &used).ok_or_else(|| {
+                            anyhow!("no free ipv6 address left in {}", subnets.v6)
+                        })?,
+                    };
+                    used.insert(IpAddr::V4(address_v4));
+                    used.insert(IpAddr::V6(address_v6));
+                    diesel::insert_into(peers::table)
+                        .values(&models::NewPeer {
+                            vpn_name: vpn,
+                            name: &name,
+                            // the private key of peers only known from [Peer] sections is not
+                            // available
+                            private_key: node.private_key.as_deref(),
+                            public_key: &node.public_key,
+                            address_v4: Ipv4Address(address_v4),
+                            address_v6: Ipv6Address(address_v6),
+                            dns: match node.dns.is_empty() {
+                                true => None,
+                                false => Some(node.dns.join(", ")),
+                            },
+                            status: PeerStatus::Active,
+                            listen_port: node.listen_port.map(i32::from),
+                            endpoint_host: node.endpoint.as_ref().map(|e| e.host.as_str()),
+                            endpoint_port: node.endpoint.as_ref().map(|e| i32::from(e.port)),
+                            persistent_keepalive: node.persistent_keepalive.map(i32::from),
+                            // keys made elsewhere are of unknown age
+                            key_created_at: None,
+                            expires_at: None,
+                        })
+                        .execute(conn)?;
+                    names.insert(node.public_key.clone(), name.clone());
+                    summary.added.push(name.clone());
+                    name
+                }
+            };
+            for net in &node.allowed_ips {
+                summary.allowed_ips += diesel::insert_or_ignore_into(allowed_ips::table)
+                    .values((
+                        allowed_ips::peer_vpn.eq(vpn),
+                        allowed_ips::peer_name.eq(&name),
+                        allowed_ips::address.eq(net.to_string()),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+
+        for (key1, key2, psk) in &psks {
+            let (peer1, peer2) = (&names[key1], &names[key2]);
+            let existing = preshared_keys::table
+                .filter(preshared_keys::vpn.eq(vpn))
+                .filter(
+                    (preshared_keys::peer1
+                        .eq(peer1)
+                        .and(preshared_keys::peer2.eq(peer2)))
+                    .or(preshared_keys::peer1
+                        .eq(peer2)
+                        .and(preshared_keys::peer2.eq(peer1))),
+                )
+                .select(preshared_keys::key)
+                .first::<String>(conn)
+                .optional()?;
+            match existing {
+                Some(key) if key == *psk => {}
+                Some(_) => summary.warnings.push(format!(
+                    "peers {} and {} already have a different preshared key, ignoring",
+                    peer1, peer2
+                )),
+                None => {
+                    summary.preshared_keys += diesel::insert_into(preshared_keys::table)
+                        .values((
+                            preshared_keys::vpn.eq(vpn),
+                            preshared_keys::peer1.eq(peer1),
+                            preshared_keys::peer2.eq(peer2),
+                            preshared_keys::key.eq(psk),
+                        ))
+                        .execute(conn)?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(summary)
+}
+
+#[derive(Default, Debug)]
+pub struct Reconciliation {
+    /// public keys configured on the interface that no peer in the VPN has
+    pub unknown: Vec<String>,
+    /// active peers of the VPN that are not configured on the interface
+    pub missing: Vec<String>,
+}
+
+/// Compare the peers configured on a live interface (whose own public key is `interface_key`)
+/// with the peers of `vpn`
+pub fn reconcile(
+    conn: &SqliteConnection,
+    vpn: &str,
+    interface_key: &str,
+    configured: &[wgconf::Peer],
+) -> Result<Reconciliation> {
+    // make sure the vpn exists
+    Subnets::load(conn, vpn)?;
+    let existing = peers::table
+        .filter(peers::vpn_name.eq(vpn))
+        .select((peers::name, peers::pubkey, peers::status))
+        .order(peers::name)
+        .load::<(String, String, PeerStatus)>(conn)?;
+    let known: HashSet<&str> = existing.iter().map(|p| p.1.as_str()).collect();
+    let on_interface: HashSet<&str> = configured.iter().map(|p| p.public_key.as_str()).collect();
+    let mut result = Reconciliation::default();
+    if !known.contains(interface_key) {
+        result.unknown.push(interface_key.into());
+    }
+    result.unknown.extend(
+        configured
+            .iter()
+            .filter(|p| !known.contains(p.public_key.as_str()))
+            .map(|p| p.public_key.clone()),
+    );
+    result.missing = existing
+        .into_iter()
+        .filter(|(_, key, status)| {
+            *status == PeerStatus::Active
+                && key != interface_key
+                && !on_interface.contains(key.as_str())
+        })
+        .map(|(name, _, _)| name)
+        .collect();
+    Ok(result)
+}
@@ -0,0 +1,89 @@
+use rand_core::{OsRng, RngCore};
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// wireguard keys are base64 encoded 32 bytes curve25519 keys
+const KEY_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("Invalid base64 in key `{0}`")]
+    InvalidEncoding(String),
+    #[error("Invalid key length for `{0}`: expected {KEY_LEN} bytes")]
+    InvalidLength(String),
+}
+
+fn decode(key: &str) -> Result<[u8; KEY_LEN], KeyError> {
+    let bytes = base64::decode(key.trim()).map_err(|_| KeyError::InvalidEncoding(key.into()))?;
+    if bytes.len() != KEY_LEN {
+        return Err(KeyError::InvalidLength(key.into()));
+    }
+    let mut out = [0u8; KEY_LEN];
+    out.copy_from_slice(&bytes);
+    Ok(out)
+}
+
+/// check that a public key or a preshared key is well formed
+pub fn validate(key: &str) -> Result<(), KeyError> {
+    decode(key).map(|_| ())
+}
+
+/// derive the public key from a private key, like `wg pubkey` does
+pub fn public_key(private_key: &str) -> Result<String, KeyError> {
+    let secret = StaticSecret::from(decode(private_key)?);
+    Ok(base64::encode(PublicKey::from(&secret).as_bytes()))
+}
+
+/// a new private key, like `wg genkey` does
+pub fn generate_private_key() -> String {
+    base64::encode(StaticSecret::new(OsRng).to_bytes())
+}
+
+/// a new preshared key, like `wg genpsk` does
+pub fn generate_preshared_key() -> String {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    base64::encode(key)
+}
+
+/// curve25519 points of small order, with the high bit cleared. Public keys equal to one of
+/// them give a predictable shared secret
+const LOW_ORDER_POINTS: [[u8; KEY_LEN]; 7] = [
+    [0; KEY_LEN],
+    [
+        1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0,
+    ],
+    [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ],
+    [
+        0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+        0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+        0x11, 0x57,
+    ],
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    [
+        0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    [
+        0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+];
+
+/// true if a public key is one of the weak, low order, curve25519 points
+pub fn is_low_order(key: &str) -> Result<bool, KeyError> {
+    let mut point = decode(key)?;
+    point[KEY_LEN - 1] &= 0x7f;
+    Ok(LOW_ORDER_POINTS.contains(&point))
+}
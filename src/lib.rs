@@ -3,13 +3,34 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
+pub mod api;
 mod args;
+#[allow(non_local_definitions)]
+mod audit;
 mod commands;
 mod database;
-#[allow(clippy::unused_unit)]
+mod drift;
+mod endpoint;
+mod error;
+mod expiry;
+mod export;
+mod import;
+mod keys;
+#[allow(clippy::unused_unit, non_local_definitions)]
 mod models;
+#[allow(non_local_definitions)]
 mod schema;
+#[allow(non_local_definitions)]
+mod types;
+mod wgconf;
+mod wgdump;
 
 pub use args::{Cli, CommandParser};
 pub use commands::Commands;
 pub use database::{Database, DatabaseError};
+pub use error::CommandError;
+pub use expiry::{disable_peers, expired_peers, ExpiredPeer};
+pub use types::{
+    AclAction, AclProtocol, FirewallFormat, Ipv4Address, Ipv4Network, Ipv6Address, Ipv6Network,
+    PeerStatus, Topology, TunnelMode, UnknownValue,
+};
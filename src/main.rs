@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
 use clap::Parser;
-use dialoguer::{theme::ColorfulTheme, Password};
+use dialoguer::{theme::ColorfulTheme, Confirm, Password};
 use rustyline::config::Configurer;
 use rustyline::error::ReadlineError;
 use rustyline::{ColorMode, Editor};
@@ -47,7 +47,8 @@ fn run(db: vpnutils::Database, history_file: String) -> Result<()> {
                         break;
                     }
                     Err(e) => {
-                        println!("Error: {e}");
+                        // the whole chain, e.g. the sqlite error behind a database error
+                        println!("Error: {e:#}");
                         continue;
                     }
                 }
@@ -69,14 +70,44 @@ fn run(db: vpnutils::Database, history_file: String) -> Result<()> {
     Ok(())
 }
 
+/// list the peers past their expiry date, and offer to disable them when interactive
+fn report_expired(db: &vpnutils::Database, interactive: bool) -> Result<()> {
+    let conn = db.connect()?;
+    let expired = vpnutils::expired_peers(&conn)?;
+    if expired.is_empty() {
+        return Ok(());
+    }
+    eprintln!("{} active peers have expired:", expired.len());
+    for peer in &expired {
+        eprintln!("  {}/{} on {}", peer.vpn, peer.name, peer.expires_at);
+    }
+    if interactive
+        && Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Disable them?")
+            .interact()?
+    {
+        let disabled = vpnutils::disable_peers(&conn, &expired)?;
+        println!("Disabled {} peers", disabled);
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     dotenv::dotenv().ok();
     let args = Cli::parse();
-    // TODO implement password from stdin
-    // TODO implement commands on commandline
-    let password = Password::with_theme(&ColorfulTheme::default())
-        .with_prompt("Database Password")
-        .interact()?;
+    // clap can't make a flag require a subcommand
+    if args.save && args.command.is_none() {
+        return Err(anyhow::anyhow!(
+            "--save needs a command, use save in the shell"
+        ));
+    }
+    // never a flag, that would leak it through the process list and the shell history
+    let password = match std::env::var("DATABASE_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Database Password")
+            .interact()?,
+    };
     let xdg_dirs = xdg::BaseDirectories::with_prefix("vpnutils")?;
     let db = match Database::open(args.database_path.clone(), password.clone()) {
         Ok(db) => db,
@@ -89,9 +120,17 @@ fn main() -> Result<()> {
             vpnutils::DatabaseError::DecryptError(_) => {
                 return Err(anyhow::anyhow!("Invalid password, cannot decrypt"));
             }
-            _ => return Err(anyhow::anyhow!("Unknown error")),
+            e => return Err(e).context("cannot open database"),
         },
     };
+    report_expired(&db, args.command.is_none())?;
+    if let Some(command) = args.command {
+        command.dispatch(&db)?;
+        if args.save {
+            db.save()?;
+        }
+        return Ok(());
+    }
     let history_filename = format!("history_{}.txt", str::replace(&db.path(), "/", "__"));
     let history_path = xdg_dirs.place_config_file(history_filename)?;
     let history_file = history_path
@@ -1,51 +1,236 @@
-use crate::schema::{allowed_ips, networks, peer_statuses, peers, preshared_keys, vpns};
-use diesel::{Associations, Identifiable, Queryable};
+use crate::endpoint::Endpoint;
+use crate::schema::{
+    acls, allowed_ips, edges, key_history, networks, peer_tags, peers, preshared_keys, vpns,
+};
+use crate::types::{
+    AclAction, AclProtocol, FirewallFormat, Ipv4Address, Ipv4Network, Ipv6Address, Ipv6Network,
+    PeerStatus, Topology, TunnelMode,
+};
+use diesel::{AsChangeset, Associations, Identifiable, Insertable, Queryable};
+use serde::Serialize;
+use std::convert::TryFrom;
 
-#[derive(Identifiable, Queryable, PartialEq, Debug)]
+#[derive(Identifiable, Queryable, Serialize, PartialEq, Debug)]
 #[table_name = "networks"]
 #[primary_key("name")]
 pub struct Network {
     pub name: String,
-    pub address_v4: String,
-    pub address_v6: String,
+    pub address_v4: Ipv4Network,
+    pub address_v6: Ipv6Network,
+    /// prefix lengths of the subnets assigned to new VPNs
+    pub vpn_prefix_v4: i32,
+    pub vpn_prefix_v6: i32,
 }
 
-#[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
+/// a new network, without prefix lengths the defaults of the database are used
+#[derive(Insertable, Debug)]
+#[table_name = "networks"]
+pub struct NewNetwork<'a> {
+    pub name: &'a str,
+    pub address_v4: Ipv4Network,
+    pub address_v6: Ipv6Network,
+    pub vpn_prefix_v4: Option<i32>,
+    pub vpn_prefix_v6: Option<i32>,
+}
+
+/// changes made by `network update`, `None` fields are left as they are
+#[derive(AsChangeset, Default, Debug)]
+#[table_name = "networks"]
+pub struct NetworkChanges {
+    pub name: Option<String>,
+    pub address_v4: Option<Ipv4Network>,
+    pub address_v6: Option<Ipv6Network>,
+    pub vpn_prefix_v4: Option<i32>,
+    pub vpn_prefix_v6: Option<i32>,
+}
+
+#[derive(Identifiable, Queryable, Associations, Serialize, PartialEq, Debug)]
 #[table_name = "vpns"]
 #[primary_key("name")]
 #[belongs_to(Network, foreign_key = "network_name")]
 pub struct Vpn {
     pub name: String,
     pub network_name: String,
-    pub index_in_network: i32,
-    pub address_v4: String,
-    pub address_v6: String,
+    pub index_in_network: Option<i32>,
+    pub address_v4: Ipv4Network,
+    pub address_v6: Ipv6Network,
+    pub topology: Topology,
+    pub mtu: Option<i32>,
+    pub route_table: Option<String>,
+    pub fwmark: Option<i64>,
+    pub pre_up: Option<String>,
+    pub post_up: Option<String>,
+    pub pre_down: Option<String>,
+    pub post_down: Option<String>,
 }
 
-#[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
+/// a new VPN, the index in the network is assigned by a trigger
+#[derive(Insertable, Debug)]
+#[table_name = "vpns"]
+pub struct NewVpn<'a> {
+    pub name: &'a str,
+    pub network_name: &'a str,
+    pub address_v4: Ipv4Network,
+    pub address_v6: Ipv6Network,
+}
+
+/// changes made by `vpn update`, `None` fields are left as they are
+#[derive(AsChangeset, Default, Debug)]
+#[table_name = "vpns"]
+pub struct VpnChanges {
+    pub name: Option<String>,
+    pub address_v4: Option<Ipv4Network>,
+    pub address_v6: Option<Ipv6Network>,
+}
+
+// not Serialize: it carries the private key, templates get `export::template::PeerView`
+#[derive(Identifiable, Queryable, Associations, Clone, PartialEq, Debug)]
 #[table_name = "peers"]
-#[primary_key("name")]
+#[primary_key("vpn_name", "name")]
 #[belongs_to(Vpn, foreign_key = "vpn_name")]
 pub struct Peer {
     pub vpn_name: String,
     pub name: String,
-    pub index_in_vpn: i32,
+    pub index_in_vpn: Option<i32>,
+    /// unknown for peers imported from the [Peer] sections of other configurations
     #[column_name = "privkey"]
-    pub private_key: String,
+    pub private_key: Option<String>,
     #[column_name = "pubkey"]
     pub public_key: String,
-    pub address_v4: String,
-    pub address_v6: String,
-    pub endpoint: String,
-    pub dns: String,
-    pub status: String,
+    pub address_v4: Ipv4Address,
+    pub address_v6: Ipv6Address,
+    pub dns: Option<String>,
+    pub status: PeerStatus,
+    pub hub: bool,
+    pub listen_port: Option<i32>,
+    pub endpoint_host: Option<String>,
+    pub endpoint_port: Option<i32>,
+    pub persistent_keepalive: Option<i32>,
+    pub mtu: Option<i32>,
+    pub route_table: Option<String>,
+    pub fwmark: Option<i64>,
+    pub pre_up: Option<String>,
+    pub post_up: Option<String>,
+    pub pre_down: Option<String>,
+    pub post_down: Option<String>,
+    pub tunnel: TunnelMode,
+    pub exclude_lan: bool,
+    pub isolated: bool,
+    pub firewall: Option<FirewallFormat>,
+    pub expires_at: Option<String>,
+    pub key_created_at: Option<String>,
 }
 
-#[derive(Identifiable, Queryable, PartialEq, Debug)]
-#[table_name = "peer_statuses"]
-#[primary_key("status")]
-pub struct PeerStatus {
-    pub status: String,
+impl Peer {
+    /// the public endpoint, if both host and port are set
+    pub fn endpoint(&self) -> Option<Endpoint> {
+        match (&self.endpoint_host, self.endpoint_port) {
+            (Some(host), Some(port)) => Some(Endpoint {
+                host: host.clone(),
+                port: u16::try_from(port).ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// true if the peer expired at `now`, see `expiry::now`
+    pub fn expired(&self, now: &str) -> bool {
+        self.expires_at.as_deref().is_some_and(|e| e <= now)
+    }
+}
+
+/// a new peer, the index in the VPN is assigned by a trigger and the other columns take their
+/// defaults
+#[derive(Insertable, Debug)]
+#[table_name = "peers"]
+pub struct NewPeer<'a> {
+    pub vpn_name: &'a str,
+    pub name: &'a str,
+    #[column_name = "privkey"]
+    pub private_key: Option<&'a str>,
+    #[column_name = "pubkey"]
+    pub public_key: &'a str,
+    pub address_v4: Ipv4Address,
+    pub address_v6: Ipv6Address,
+    pub dns: Option<String>,
+    pub status: PeerStatus,
+    pub listen_port: Option<i32>,
+    pub endpoint_host: Option<&'a str>,
+    pub endpoint_port: Option<i32>,
+    pub persistent_keepalive: Option<i32>,
+    pub key_created_at: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+/// changes to the interface settings of a VPN: `None` leaves a setting alone, `Some(None)`
+/// clears it
+#[derive(AsChangeset, Default, Debug)]
+#[table_name = "vpns"]
+pub struct VpnSettings {
+    pub mtu: Option<Option<i32>>,
+    pub route_table: Option<Option<String>>,
+    pub fwmark: Option<Option<i64>>,
+    pub pre_up: Option<Option<String>>,
+    pub post_up: Option<Option<String>>,
+    pub pre_down: Option<Option<String>>,
+    pub post_down: Option<Option<String>>,
+}
+
+/// changes to a peer, `None` fields are left as they are
+#[derive(AsChangeset, Default, Debug)]
+#[table_name = "peers"]
+pub struct PeerChanges {
+    pub name: Option<String>,
+    #[column_name = "privkey"]
+    pub private_key: Option<Option<String>>,
+    #[column_name = "pubkey"]
+    pub public_key: Option<String>,
+    pub address_v4: Option<Ipv4Address>,
+    pub address_v6: Option<Ipv6Address>,
+    pub dns: Option<String>,
+    pub status: Option<PeerStatus>,
+    pub endpoint_host: Option<String>,
+    pub endpoint_port: Option<i32>,
+    pub expires_at: Option<Option<String>>,
+    pub key_created_at: Option<Option<String>>,
+}
+
+/// changes to the interface settings of a peer, cleared settings fall back to the VPN ones
+#[derive(AsChangeset, Default, Debug)]
+#[table_name = "peers"]
+pub struct PeerSettings {
+    pub mtu: Option<Option<i32>>,
+    pub route_table: Option<Option<String>>,
+    pub fwmark: Option<Option<i64>>,
+    pub pre_up: Option<Option<String>>,
+    pub post_up: Option<Option<String>>,
+    pub pre_down: Option<Option<String>>,
+    pub post_down: Option<Option<String>>,
+}
+
+/// changes to how the other peers reach a peer acting as a server: `None` leaves a column
+/// alone, `Some(None)` clears it
+#[derive(AsChangeset, Default, Debug)]
+#[table_name = "peers"]
+pub struct PeerServer {
+    pub listen_port: Option<Option<i32>>,
+    pub endpoint_host: Option<Option<String>>,
+    pub endpoint_port: Option<Option<i32>>,
+    pub persistent_keepalive: Option<Option<i32>>,
+}
+
+impl From<VpnSettings> for PeerSettings {
+    fn from(s: VpnSettings) -> Self {
+        PeerSettings {
+            mtu: s.mtu,
+            route_table: s.route_table,
+            fwmark: s.fwmark,
+            pre_up: s.pre_up,
+            post_up: s.post_up,
+            pre_down: s.pre_down,
+            post_down: s.post_down,
+        }
+    }
 }
 
 // cannot use Associations here - it doesn't support composite fkeys
@@ -67,4 +252,77 @@ pub struct PresharedKey {
     pub peer1: String,
     pub peer2: String,
     pub key: String,
+    pub created_at: Option<String>,
+}
+
+// cannot use Associations here - it doesn't support composite fkeys
+#[derive(Identifiable, Queryable, PartialEq, Debug)]
+#[table_name = "edges"]
+#[primary_key("vpn", "peer1", "peer2")]
+pub struct Edge {
+    pub vpn: String,
+    pub peer1: String,
+    pub peer2: String,
+}
+
+// cannot use Associations here - it doesn't support composite fkeys
+#[derive(Identifiable, Queryable, PartialEq, Debug)]
+#[table_name = "peer_tags"]
+#[primary_key("vpn", "peer", "tag")]
+pub struct PeerTag {
+    pub vpn: String,
+    pub peer: String,
+    pub tag: String,
+}
+
+/// a public key replaced by a rotation
+#[derive(Identifiable, Queryable, PartialEq, Debug)]
+#[table_name = "key_history"]
+pub struct RetiredKey {
+    pub id: i32,
+    pub vpn: String,
+    pub peer: String,
+    #[column_name = "pubkey"]
+    pub public_key: String,
+    pub retired_at: String,
+    pub grace_until: String,
+}
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Debug)]
+#[table_name = "acls"]
+#[belongs_to(Vpn, foreign_key = "vpn")]
+pub struct Acl {
+    pub id: i32,
+    pub vpn: String,
+    pub src_peer: Option<String>,
+    pub src_tag: Option<String>,
+    pub dst_peer: Option<String>,
+    pub dst_tag: Option<String>,
+    pub protocol: Option<AclProtocol>,
+    pub port: Option<i32>,
+    pub action: AclAction,
+}
+
+/// a new access rule, the id is assigned by the database and orders the rules of a VPN
+#[derive(Insertable, Debug)]
+#[table_name = "acls"]
+pub struct NewAcl {
+    pub vpn: String,
+    pub src_peer: Option<String>,
+    pub src_tag: Option<String>,
+    pub dst_peer: Option<String>,
+    pub dst_tag: Option<String>,
+    pub protocol: Option<AclProtocol>,
+    pub port: Option<i32>,
+    pub action: AclAction,
+}
+
+/// changes to an access rule: `None` leaves a column alone, `Some(None)` matches any protocol
+/// or port
+#[derive(AsChangeset, Default, Debug)]
+#[table_name = "acls"]
+pub struct AclChanges {
+    pub action: Option<AclAction>,
+    pub protocol: Option<Option<AclProtocol>>,
+    pub port: Option<Option<i32>>,
 }
@@ -1,3 +1,17 @@
+table! {
+    acls (id) {
+        id -> Integer,
+        vpn -> Text,
+        src_peer -> Nullable<Text>,
+        src_tag -> Nullable<Text>,
+        dst_peer -> Nullable<Text>,
+        dst_tag -> Nullable<Text>,
+        protocol -> Nullable<Text>,
+        port -> Nullable<Integer>,
+        action -> Text,
+    }
+}
+
 table! {
     allowed_ips (peer_vpn, peer_name, address) {
         peer_vpn -> Text,
@@ -6,11 +20,21 @@ table! {
     }
 }
 
+table! {
+    edges (vpn, peer1, peer2) {
+        vpn -> Text,
+        peer1 -> Text,
+        peer2 -> Text,
+    }
+}
+
 table! {
     networks (name) {
         name -> Text,
         address_v4 -> Text,
         address_v6 -> Text,
+        vpn_prefix_v4 -> Integer,
+        vpn_prefix_v6 -> Integer,
     }
 }
 
@@ -20,18 +44,54 @@ table! {
     }
 }
 
+table! {
+    key_history (id) {
+        id -> Integer,
+        vpn -> Text,
+        peer -> Text,
+        pubkey -> Text,
+        retired_at -> Text,
+        grace_until -> Text,
+    }
+}
+
+table! {
+    peer_tags (vpn, peer, tag) {
+        vpn -> Text,
+        peer -> Text,
+        tag -> Text,
+    }
+}
+
 table! {
     peers (vpn_name, name) {
         vpn_name -> Text,
         name -> Text,
         index_in_vpn -> Nullable<Integer>,
-        privkey -> Text,
+        privkey -> Nullable<Text>,
         pubkey -> Text,
         address_v4 -> Text,
         address_v6 -> Text,
-        endpoint -> Nullable<Text>,
         dns -> Nullable<Text>,
         status -> Text,
+        hub -> Bool,
+        listen_port -> Nullable<Integer>,
+        endpoint_host -> Nullable<Text>,
+        endpoint_port -> Nullable<Integer>,
+        persistent_keepalive -> Nullable<Integer>,
+        mtu -> Nullable<Integer>,
+        route_table -> Nullable<Text>,
+        fwmark -> Nullable<BigInt>,
+        pre_up -> Nullable<Text>,
+        post_up -> Nullable<Text>,
+        pre_down -> Nullable<Text>,
+        post_down -> Nullable<Text>,
+        tunnel -> Text,
+        exclude_lan -> Bool,
+        isolated -> Bool,
+        firewall -> Nullable<Text>,
+        expires_at -> Nullable<Text>,
+        key_created_at -> Nullable<Text>,
     }
 }
 
@@ -41,6 +101,7 @@ table! {
         peer1 -> Text,
         peer2 -> Text,
         key -> Text,
+        created_at -> Nullable<Text>,
     }
 }
 
@@ -51,17 +112,30 @@ table! {
         index_in_network -> Nullable<Integer>,
         address_v4 -> Text,
         address_v6 -> Text,
+        topology -> Text,
+        mtu -> Nullable<Integer>,
+        route_table -> Nullable<Text>,
+        fwmark -> Nullable<BigInt>,
+        pre_up -> Nullable<Text>,
+        post_up -> Nullable<Text>,
+        pre_down -> Nullable<Text>,
+        post_down -> Nullable<Text>,
     }
 }
 
+joinable!(acls -> vpns (vpn));
 joinable!(peers -> peer_statuses (status));
 joinable!(peers -> vpns (vpn_name));
 joinable!(vpns -> networks (network_name));
 
 allow_tables_to_appear_in_same_query!(
+    acls,
     allowed_ips,
+    key_history,
+    edges,
     networks,
     peer_statuses,
+    peer_tags,
     peers,
     preshared_keys,
     vpns,
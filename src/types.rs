@@ -0,0 +1,221 @@
+//! Typed address and enum columns, stored as TEXT but parsed when loaded so that invalid
+//! values fail at the database boundary
+use clap::ArgEnum;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use diesel::sqlite::Sqlite;
+use ipnet::{Ipv4Net, Ipv6Net};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::Deref;
+use std::str::FromStr;
+use thiserror::Error;
+
+macro_rules! address_type {
+    ($(#[$doc:meta])* $name:ident, $inner:ty) => {
+        $(#[$doc])*
+        #[derive(AsExpression, FromSqlRow, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        #[sql_type = "Text"]
+        #[serde(transparent)]
+        pub struct $name(pub $inner);
+
+        impl Deref for $name {
+            type Target = $inner;
+
+            fn deref(&self) -> &$inner {
+                &self.0
+            }
+        }
+
+        impl From<$inner> for $name {
+            fn from(value: $inner) -> Self {
+                $name(value)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = <$inner as FromStr>::Err;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse().map($name)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl ToSql<Text, Sqlite> for $name {
+            fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+                ToSql::<Text, Sqlite>::to_sql(&self.0.to_string(), out)
+            }
+        }
+
+        impl FromSql<Text, Sqlite> for $name {
+            fn from_sql(bytes: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+                let text = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+                text.parse().map_err(|e| {
+                    format!("invalid {} `{}`: {}", stringify!($name), text, e).into()
+                })
+            }
+        }
+    };
+}
+
+address_type!(
+    /// the IPv4 network of a network or VPN, like `10.0.0.0/8`
+    Ipv4Network,
+    Ipv4Net
+);
+address_type!(
+    /// the IPv6 network of a network or VPN, like `fd00::/8`
+    Ipv6Network,
+    Ipv6Net
+);
+address_type!(
+    /// the IPv4 address of a peer, without prefix length
+    Ipv4Address,
+    Ipv4Addr
+);
+address_type!(
+    /// the IPv6 address of a peer, without prefix length
+    Ipv6Address,
+    Ipv6Addr
+);
+
+/// a TEXT value that is not one of those of an enum column
+#[derive(Error, Debug, PartialEq)]
+#[error("invalid {kind} `{value}`")]
+pub struct UnknownValue {
+    pub kind: &'static str,
+    pub value: String,
+}
+
+macro_rules! text_enum {
+    ($(#[$doc:meta])* $name:ident, $kind:literal {
+        $($(#[$variant_doc:meta])* $variant:ident => $text:literal,)+
+    }) => {
+        $(#[$doc])*
+        #[derive(AsExpression, FromSqlRow, ArgEnum, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        #[sql_type = "Text"]
+        pub enum $name {
+            $($(#[$variant_doc])* $variant,)+
+        }
+
+        impl $name {
+            /// the value stored in the database, also the one of the command line
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $text,)+
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = UnknownValue;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($text => Ok($name::$variant),)+
+                    _ => Err(UnknownValue {
+                        kind: $kind,
+                        value: s.into(),
+                    }),
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl ToSql<Text, Sqlite> for $name {
+            fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+                ToSql::<Text, Sqlite>::to_sql(self.as_str(), out)
+            }
+        }
+
+        impl FromSql<Text, Sqlite> for $name {
+            fn from_sql(bytes: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+                let text = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+                Ok(text.parse()?)
+            }
+        }
+    };
+}
+
+text_enum!(
+    /// which peers have a [Peer] section for which
+    Topology, "topology" {
+        Mesh => "mesh",
+        Hub => "hub",
+        Custom => "custom",
+    }
+);
+text_enum!(
+    /// which traffic of a peer goes through the VPN
+    TunnelMode, "tunnel mode" {
+        Split => "split",
+        Full => "full",
+    }
+);
+text_enum!(
+    /// the firewall rules generated for a server peer
+    FirewallFormat, "firewall format" {
+        /// nftables ruleset, in its own table
+        Nftables => "nftables",
+        /// iptables-restore and ip6tables-restore input
+        Iptables => "iptables",
+    }
+);
+text_enum!(
+    /// disabled peers are kept in the database but left out of the exports
+    PeerStatus, "peer status" {
+        Active => "active",
+        Disabled => "disabled",
+    }
+);
+text_enum!(
+    /// what an access rule does with the traffic it matches
+    AclAction, "ACL action" {
+        Allow => "allow",
+        Deny => "deny",
+    }
+);
+text_enum!(
+    /// the protocols an access rule can match
+    AclProtocol, "protocol" {
+        Tcp => "tcp",
+        Udp => "udp",
+        Icmp => "icmp",
+    }
+);
+
+// text_enum! can't put #[default] on a variant
+#[allow(clippy::derivable_impls)]
+impl Default for PeerStatus {
+    fn default() -> Self {
+        PeerStatus::Active
+    }
+}
+
+impl AclProtocol {
+    /// true for the protocols with ports
+    pub fn has_ports(&self) -> bool {
+        matches!(self, AclProtocol::Tcp | AclProtocol::Udp)
+    }
+}
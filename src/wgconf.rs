@@ -0,0 +1,327 @@
+//! Parser for wg-quick / `wg showconf` configuration files
+use ipnet::IpNet;
+use std::net::IpAddr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("line {line}: `{content}` is not in a section")]
+    OutsideSection { line: usize, content: String },
+    #[error("line {line}: unknown section `{section}`")]
+    UnknownSection { line: usize, section: String },
+    #[error("line {line}: expected `Key = Value`, got `{content}`")]
+    InvalidLine { line: usize, content: String },
+    #[error("line {line}: invalid value for {key}: `{value}`")]
+    InvalidValue {
+        line: usize,
+        key: String,
+        value: String,
+    },
+    #[error("line {line}: more than one [Interface] section")]
+    DuplicateInterface { line: usize },
+    #[error("[Peer] section at line {line} has no PublicKey")]
+    MissingPublicKey { line: usize },
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Interface {
+    /// from a `# Name = ` comment, or a comment right before the section
+    pub name: Option<String>,
+    pub private_key: Option<String>,
+    pub addresses: Vec<IpNet>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub dns: Vec<String>,
+    pub mtu: Option<u16>,
+    /// `off`, `auto`, or a routing table number or name
+    pub table: Option<String>,
+    pub pre_up: Vec<String>,
+    pub post_up: Vec<String>,
+    pub pre_down: Vec<String>,
+    pub post_down: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Peer {
+    /// from a `# Name = ` comment, or a comment right before the section
+    pub name: Option<String>,
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    pub allowed_ips: Vec<IpNet>,
+    pub endpoint: Option<String>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    pub interface: Option<Interface>,
+    pub peers: Vec<Peer>,
+}
+
+enum Section {
+    Interface(Interface),
+    Peer(Peer, usize),
+}
+
+/// parse an address that may or may not have a prefix length, like wg does for AllowedIPs
+pub fn parse_net(value: &str) -> Option<IpNet> {
+    let value = value.trim();
+    value
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+fn parse_list<T>(
+    line: usize,
+    key: &str,
+    value: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<Vec<T>, ParseError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            parse(v).ok_or_else(|| ParseError::InvalidValue {
+                line,
+                key: key.into(),
+                value: v.into(),
+            })
+        })
+        .collect()
+}
+
+fn parse_value<T: std::str::FromStr>(line: usize, key: &str, value: &str) -> Result<T, ParseError> {
+    value.parse().map_err(|_| ParseError::InvalidValue {
+        line,
+        key: key.into(),
+        value: value.into(),
+    })
+}
+
+/// fwmarks are written in hexadecimal by `wg showconf`, wg-quick also accepts decimal
+pub fn parse_fwmark(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// `# Name = foo` comments are used by several tools to name peers
+fn name_from_comment(comment: &str) -> Option<String> {
+    let (key, value) = comment.split_once('=')?;
+    if key.trim().eq_ignore_ascii_case("name") && !value.trim().is_empty() {
+        return Some(value.trim().to_string());
+    }
+    None
+}
+
+impl Config {
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let mut config = Config::default();
+        let mut section: Option<Section> = None;
+        // comment immediately preceding a section header, used as its name
+        let mut last_comment: Option<String> = None;
+
+        for (idx, raw) in input.lines().enumerate() {
+            let line = idx + 1;
+            let (content, comment) = match raw.split_once('#') {
+                Some((c, comment)) => (c.trim(), Some(comment.trim())),
+                None => (raw.trim(), None),
+            };
+            if content.is_empty() {
+                if let Some(comment) = comment {
+                    if let Some(name) = name_from_comment(comment) {
+                        match section.as_mut() {
+                            Some(Section::Interface(i)) => i.name = Some(name),
+                            Some(Section::Peer(p, _)) => p.name = Some(name),
+                            None => {}
+                        }
+                    } else if !comment.is_empty() && !comment.contains(char::is_whitespace) {
+                        last_comment = Some(comment.to_string());
+                        continue;
+                    }
+                }
+                last_comment = None;
+                continue;
+            }
+            if content.starts_with('[') && content.ends_with(']') {
+                config.push(section.take())?;
+                let name = last_comment.take();
+                section = match content[1..content.len() - 1].trim() {
+                    s if s.eq_ignore_ascii_case("interface") => {
+                        if config.interface.is_some() {
+                            return Err(ParseError::DuplicateInterface { line });
+                        }
+                        Some(Section::Interface(Interface {
+                            name,
+                            ..Default::default()
+                        }))
+                    }
+                    s if s.eq_ignore_ascii_case("peer") => Some(Section::Peer(
+                        Peer {
+                            name,
+                            ..Default::default()
+                        },
+                        line,
+                    )),
+                    s => {
+                        return Err(ParseError::UnknownSection {
+                            line,
+                            section: s.into(),
+                        })
+                    }
+                };
+                continue;
+            }
+            last_comment = None;
+            let (key, value) = content
+                .split_once('=')
+                .map(|(k, v)| (k.trim().to_lowercase(), v.trim()))
+                .ok_or_else(|| ParseError::InvalidLine {
+                    line,
+                    content: content.into(),
+                })?;
+            match section.as_mut() {
+                None => {
+                    return Err(ParseError::OutsideSection {
+                        line,
+                        content: content.into(),
+                    })
+                }
+                Some(Section::Interface(i)) => match key.as_str() {
+                    "privatekey" => i.private_key = Some(value.into()),
+                    "address" => i
+                        .addresses
+                        .extend(parse_list(line, &key, value, parse_net)?),
+                    "listenport" => i.listen_port = Some(parse_value(line, &key, value)?),
+                    "dns" => i
+                        .dns
+                        .extend(parse_list(line, &key, value, |v| Some(v.to_string()))?),
+                    "fwmark" => {
+                        i.fwmark = match value {
+                            "off" => None,
+                            v => Some(parse_fwmark(v).ok_or_else(|| ParseError::InvalidValue {
+                                line,
+                                key: key.clone(),
+                                value: v.into(),
+                            })?),
+                        }
+                    }
+                    "mtu" => i.mtu = Some(parse_value(line, &key, value)?),
+                    "table" => i.table = Some(value.into()),
+                    // hooks can be repeated, and are run in order
+                    "preup" => i.pre_up.push(value.into()),
+                    "postup" => i.post_up.push(value.into()),
+                    "predown" => i.pre_down.push(value.into()),
+                    "postdown" => i.post_down.push(value.into()),
+                    _ => {}
+                },
+                Some(Section::Peer(p, _)) => match key.as_str() {
+                    "publickey" => p.public_key = value.into(),
+                    "presharedkey" => p.preshared_key = Some(value.into()),
+                    "allowedips" => p
+                        .allowed_ips
+                        .extend(parse_list(line, &key, value, parse_net)?),
+                    "endpoint" => p.endpoint = Some(value.into()),
+                    "persistentkeepalive" => {
+                        p.persistent_keepalive = match value {
+                            "off" => None,
+                            v => Some(parse_value(line, &key, v)?),
+                        }
+                    }
+                    _ => {}
+                },
+            }
+        }
+        config.push(section)?;
+        Ok(config)
+    }
+
+    fn push(&mut self, section: Option<Section>) -> Result<(), ParseError> {
+        match section {
+            Some(Section::Interface(i)) => self.interface = Some(i),
+            Some(Section::Peer(p, line)) => {
+                if p.public_key.is_empty() {
+                    return Err(ParseError::MissingPublicKey { line });
+                }
+                self.peers.push(p)
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+fn join<T: std::fmt::Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// render in the wg-quick format, names are written as `# Name = ` comments
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(i) = &self.interface {
+            writeln!(f, "[Interface]")?;
+            if let Some(name) = &i.name {
+                writeln!(f, "# Name = {}", name)?;
+            }
+            if let Some(key) = &i.private_key {
+                writeln!(f, "PrivateKey = {}", key)?;
+            }
+            if !i.addresses.is_empty() {
+                writeln!(f, "Address = {}", join(&i.addresses))?;
+            }
+            if let Some(port) = i.listen_port {
+                writeln!(f, "ListenPort = {}", port)?;
+            }
+            if let Some(fwmark) = i.fwmark {
+                writeln!(f, "FwMark = {}", fwmark)?;
+            }
+            if !i.dns.is_empty() {
+                writeln!(f, "DNS = {}", join(&i.dns))?;
+            }
+            if let Some(mtu) = i.mtu {
+                writeln!(f, "MTU = {}", mtu)?;
+            }
+            if let Some(table) = &i.table {
+                writeln!(f, "Table = {}", table)?;
+            }
+            for (key, hooks) in [
+                ("PreUp", &i.pre_up),
+                ("PostUp", &i.post_up),
+                ("PreDown", &i.pre_down),
+                ("PostDown", &i.post_down),
+            ] {
+                for hook in hooks {
+                    writeln!(f, "{} = {}", key, hook)?;
+                }
+            }
+        }
+        for p in &self.peers {
+            writeln!(f)?;
+            writeln!(f, "[Peer]")?;
+            if let Some(name) = &p.name {
+                writeln!(f, "# Name = {}", name)?;
+            }
+            writeln!(f, "PublicKey = {}", p.public_key)?;
+            if let Some(key) = &p.preshared_key {
+                writeln!(f, "PresharedKey = {}", key)?;
+            }
+            if !p.allowed_ips.is_empty() {
+                writeln!(f, "AllowedIPs = {}", join(&p.allowed_ips))?;
+            }
+            if let Some(endpoint) = &p.endpoint {
+                writeln!(f, "Endpoint = {}", endpoint)?;
+            }
+            if let Some(keepalive) = p.persistent_keepalive {
+                writeln!(f, "PersistentKeepalive = {}", keepalive)?;
+            }
+        }
+        Ok(())
+    }
+}
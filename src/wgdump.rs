@@ -0,0 +1,174 @@
+//! Parser for the tab separated output of `wg show <iface> dump` and `wg show all dump`
+use crate::wgconf::{self, parse_net};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DumpError {
+    #[error("line {line}: unexpected number of fields ({fields})")]
+    InvalidLine { line: usize, fields: usize },
+    #[error("line {line}: invalid value for {field}: `{value}`")]
+    InvalidValue {
+        line: usize,
+        field: &'static str,
+        value: String,
+    },
+    #[error("line {line}: peer found before its interface")]
+    PeerWithoutInterface { line: usize },
+    #[error("Dump contains no interface")]
+    Empty,
+    #[error("Interface `{0}` not found in dump")]
+    InterfaceNotFound(String),
+    #[error("Dump contains several interfaces ({0}), choose one")]
+    AmbiguousInterface(String),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Interface {
+    /// only known for `wg show all dump`
+    pub name: Option<String>,
+    pub private_key: Option<String>,
+    pub public_key: String,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub peers: Vec<wgconf::Peer>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Dump {
+    pub interfaces: Vec<Interface>,
+}
+
+/// wg uses `(none)` and `off` for unset values
+fn optional(value: &str) -> Option<&str> {
+    match value {
+        "(none)" | "off" | "0" | "" => None,
+        v => Some(v),
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(
+    line: usize,
+    field: &'static str,
+    value: &str,
+) -> Result<Option<T>, DumpError> {
+    optional(value)
+        .map(|v| {
+            v.parse().map_err(|_| DumpError::InvalidValue {
+                line,
+                field,
+                value: v.into(),
+            })
+        })
+        .transpose()
+}
+
+impl Dump {
+    pub fn parse(input: &str) -> Result<Self, DumpError> {
+        let mut dump = Dump::default();
+        // `wg show all dump` prefixes every line with the interface name
+        let mut all: Option<bool> = None;
+        for (idx, raw) in input.lines().enumerate() {
+            let line = idx + 1;
+            if raw.trim().is_empty() {
+                continue;
+            }
+            let mut fields: Vec<&str> = raw.split('\t').collect();
+            let prefixed = *all.get_or_insert(fields.len() == 5 || fields.len() == 9);
+            let name = match prefixed {
+                true => Some(fields.remove(0).to_string()),
+                false => None,
+            };
+            let new_interface = match fields.len() {
+                4 => true,
+                8 => false,
+                fields => return Err(DumpError::InvalidLine { line, fields }),
+            };
+            if new_interface {
+                dump.interfaces.push(Interface {
+                    name,
+                    private_key: optional(fields[0]).map(String::from),
+                    public_key: fields[1].into(),
+                    listen_port: parse_value(line, "listen-port", fields[2])?,
+                    fwmark: match optional(fields[3]) {
+                        Some(v) => {
+                            Some(u32::from_str_radix(v.trim_start_matches("0x"), 16).map_err(
+                                |_| DumpError::InvalidValue {
+                                    line,
+                                    field: "fwmark",
+                                    value: v.into(),
+                                },
+                            )?)
+                        }
+                        None => None,
+                    },
+                    peers: vec![],
+                });
+                continue;
+            }
+            let interface = dump
+                .interfaces
+                .last_mut()
+                .ok_or(DumpError::PeerWithoutInterface { line })?;
+            let allowed_ips = match optional(fields[3]) {
+                Some(ips) => ips
+                    .split(',')
+                    .map(|ip| {
+                        parse_net(ip).ok_or_else(|| DumpError::InvalidValue {
+                            line,
+                            field: "allowed-ips",
+                            value: ip.into(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => vec![],
+            };
+            interface.peers.push(wgconf::Peer {
+                name: None,
+                public_key: fields[0].into(),
+                preshared_key: optional(fields[1]).map(String::from),
+                endpoint: optional(fields[2]).map(String::from),
+                allowed_ips,
+                persistent_keepalive: parse_value(line, "persistent-keepalive", fields[7])?,
+            });
+        }
+        Ok(dump)
+    }
+
+    /// pick an interface by name, or the only one in the dump
+    pub fn interface(self, name: Option<&str>) -> Result<Interface, DumpError> {
+        let mut interfaces = self.interfaces;
+        match name {
+            Some(name) => interfaces
+                .into_iter()
+                .find(|i| i.name.as_deref() == Some(name))
+                .ok_or_else(|| DumpError::InterfaceNotFound(name.into())),
+            None => match interfaces.len() {
+                0 => Err(DumpError::Empty),
+                1 => Ok(interfaces.remove(0)),
+                _ => Err(DumpError::AmbiguousInterface(
+                    interfaces
+                        .iter()
+                        .filter_map(|i| i.name.clone())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )),
+            },
+        }
+    }
+}
+
+impl Interface {
+    /// convert to the same structure used for configuration files. The dump has no addresses
+    pub fn into_config(self) -> wgconf::Config {
+        wgconf::Config {
+            interface: Some(wgconf::Interface {
+                name: self.name,
+                private_key: self.private_key,
+                listen_port: self.listen_port,
+                fwmark: self.fwmark,
+                ..Default::default()
+            }),
+            peers: self.peers,
+        }
+    }
+}
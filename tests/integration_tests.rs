@@ -1,7 +1,28 @@
+// diesel 1 derives trigger this lint on recent compilers
+#![allow(non_local_definitions)]
+#[macro_use]
+extern crate diesel;
+
 use anyhow::Result;
 
 static PASSWORD: &str = "supersafe";
 
+#[test]
+fn test_cli() -> Result<()> {
+    use clap::Parser;
+    let args = vpnutils::Cli::try_parse_from(["vpnutils", "-d", "vpn.db"])?;
+    assert!(args.command.is_none());
+    let args =
+        vpnutils::Cli::try_parse_from(["vpnutils", "-d", "vpn.db", "--save", "network", "list"])?;
+    assert!(args.save);
+    assert!(args.command.is_some());
+    // never a flag
+    assert!(
+        vpnutils::Cli::try_parse_from(["vpnutils", "-d", "vpn.db", "--password", "x"]).is_err()
+    );
+    Ok(())
+}
+
 #[test]
 fn test_database() -> Result<()> {
     let dir = tempfile::tempdir()?;
@@ -27,3 +48,1382 @@ fn test_database() -> Result<()> {
     assert_eq!(db.path(), new.path());
     Ok(())
 }
+
+fn database_with_vpn(dir: &tempfile::TempDir) -> Result<vpnutils::Database> {
+    use diesel::RunQueryDsl;
+    let db_path = dir.path().join("database.db");
+    let db = vpnutils::Database::create(db_path, PASSWORD.to_string())?;
+    let conn = db.connect()?;
+    diesel::sql_query("INSERT INTO networks (name, address_v4, address_v6) VALUES ('home', '10.0.0.0/8', 'fd00::/8')")
+        .execute(&conn)?;
+    diesel::sql_query(
+        "INSERT INTO vpns(name, network_name, address_v4, address_v6) \
+         VALUES ('office', 'home', '10.1.0.0/24', 'fd00:1::/64')",
+    )
+    .execute(&conn)?;
+    Ok(db)
+}
+
+fn run(db: &vpnutils::Database, line: &str) -> Result<bool> {
+    use clap::Parser;
+    let mut args = shellwords::split(line)?;
+    args.insert(0, String::from("vpnutils"));
+    vpnutils::CommandParser::try_parse_from(args)?
+        .command
+        .dispatch(db)
+}
+
+fn private_key(seed: u8) -> String {
+    base64::encode([seed; 32])
+}
+
+fn public_key(seed: u8) -> String {
+    let secret = x25519_dalek::StaticSecret::from([seed; 32]);
+    base64::encode(x25519_dalek::PublicKey::from(&secret).as_bytes())
+}
+
+fn write_configs(dir: &std::path::Path) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
+    let server = dir.join("server.conf");
+    std::fs::write(
+        &server,
+        format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 10.1.0.1/24, fd00:1::1/64\nListenPort = 51820\n\n\
+             # laptop\n[Peer]\nPublicKey = {}\nPresharedKey = {}\nAllowedIPs = 10.1.0.2/32, 192.168.1.0/24\n",
+            private_key(1),
+            public_key(2),
+            private_key(9)
+        ),
+    )?;
+    let laptop = dir.join("laptop.conf");
+    std::fs::write(
+        &laptop,
+        format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 10.1.0.2/24\n\n\
+             [Peer]\n# Name = server\nPublicKey = {}\nPresharedKey = {}\n\
+             Endpoint = vpn.example.com:51820\nAllowedIPs = 10.1.0.0/24\n",
+            private_key(2),
+            public_key(1),
+            private_key(9)
+        ),
+    )?;
+    Ok((server, laptop))
+}
+
+/// the office VPN of `database_with_vpn` with the server and the laptop of `write_configs`
+fn imported_vpn(dir: &tempfile::TempDir) -> Result<vpnutils::Database> {
+    let db = database_with_vpn(dir)?;
+    let (server, laptop) = write_configs(dir.path())?;
+    run(
+        &db,
+        &format!(
+            "vpn import-conf office {} {}",
+            server.display(),
+            laptop.display()
+        ),
+    )?;
+    Ok(db)
+}
+
+#[test]
+fn test_import_conf() -> Result<()> {
+    use diesel::prelude::*;
+    let dir = tempfile::tempdir()?;
+    let db = database_with_vpn(&dir)?;
+    let (server, laptop) = write_configs(dir.path())?;
+    let conn = db.connect()?;
+    // the laptop is known only from the [Peer] section of the server
+    assert!(run(
+        &db,
+        &format!("vpn import-conf office {}", server.display())
+    )?);
+    let laptop_peer = vpnutils::api::get_peer(&conn, "office", "laptop")?;
+    assert_eq!(laptop_peer.private_key, None);
+    assert!(run(
+        &db,
+        &format!(
+            "vpn import-conf office {} {}",
+            server.display(),
+            laptop.display()
+        )
+    )?);
+    let laptop_peer = vpnutils::api::get_peer(&conn, "office", "laptop")?;
+    assert_eq!(laptop_peer.private_key, Some(private_key(2)));
+
+    let peers =
+        diesel::sql_query("SELECT name FROM peers ORDER BY name").load::<PeerName>(&conn)?;
+    let names: Vec<_> = peers.into_iter().map(|p| p.name).collect();
+    assert_eq!(names, vec!["laptop", "server"]);
+    let allowed = diesel::sql_query(
+        "SELECT peer_name AS name FROM allowed_ips WHERE address = '192.168.1.0/24'",
+    )
+    .load::<PeerName>(&conn)?;
+    assert_eq!(allowed.len(), 1);
+    assert_eq!(allowed[0].name, "laptop");
+    let psks =
+        diesel::sql_query("SELECT peer1 AS name FROM preshared_keys").load::<PeerName>(&conn)?;
+    assert_eq!(psks.len(), 1);
+
+    // importing again doesn't duplicate anything
+    assert!(run(
+        &db,
+        &format!("vpn import-conf office {}", laptop.display())
+    )?);
+    let count = diesel::sql_query("SELECT name FROM peers").load::<PeerName>(&conn)?;
+    assert_eq!(count.len(), 2);
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct PeerName {
+    #[sql_type = "diesel::sql_types::Text"]
+    name: String,
+}
+
+#[test]
+fn test_import_dump() -> Result<()> {
+    use diesel::prelude::*;
+    let dir = tempfile::tempdir()?;
+    let db = database_with_vpn(&dir)?;
+    let dump = dir.path().join("dump.txt");
+    std::fs::write(
+        &dump,
+        format!(
+            "wg0\t(none)\t{}\t51820\toff\n\
+             wg0\t{}\t(none)\t192.0.2.1:51820\t10.1.0.2/32\t0\t0\t0\toff\n\
+             wg0\t{}\t(none)\t(none)\t10.1.0.3/32,fd00:1::3/128\t0\t0\t0\t25\n",
+            public_key(1),
+            public_key(2),
+            public_key(3)
+        ),
+    )?;
+    assert!(run(
+        &db,
+        &format!(
+            "vpn import-dump office {} --interface wg0 --yes",
+            dump.display()
+        )
+    )?);
+    let conn = db.connect()?;
+    // the interface is added even if the dump doesn't have its private key
+    let mut keys: Vec<_> = vpnutils::api::list_peers(&conn, "office", &[])?
+        .into_iter()
+        .map(|p| (p.public_key, p.private_key))
+        .collect();
+    keys.sort();
+    let mut expected = vec![
+        (public_key(1), None),
+        (public_key(2), None),
+        (public_key(3), None),
+    ];
+    expected.sort();
+    assert_eq!(keys, expected);
+    // the endpoints of a dump are where the peers were last seen from, not configuration
+    let with_endpoint = diesel::sql_query("SELECT name FROM peers WHERE endpoint_host IS NOT NULL")
+        .load::<PeerName>(&conn)?;
+    assert!(with_endpoint.is_empty());
+    // the only interface is picked automatically, and there is nothing left to add
+    assert!(run(
+        &db,
+        &format!("vpn import-dump office {} --yes", dump.display())
+    )
+    .is_ok());
+    Ok(())
+}
+
+#[test]
+fn test_peer_diff() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    let live = dir.path().join("live.conf");
+    let showconf = format!(
+        "[Interface]\nListenPort = 51820\nPrivateKey = {}\n\n[Peer]\nPublicKey = {}\n\
+         PresharedKey = {}\nAllowedIPs = 10.1.0.2/32, fd00:1::2/128, 192.168.1.0/24\n",
+        private_key(1),
+        public_key(2),
+        private_key(9)
+    );
+    std::fs::write(&live, &showconf)?;
+    let diff = format!("peer diff office server --live {}", live.display());
+    assert!(run(&db, &diff)?);
+
+    // the peer has no preshared key and routes fewer addresses
+    std::fs::write(
+        &live,
+        showconf
+            .replace(&format!("PresharedKey = {}\n", private_key(9)), "")
+            .replace(", 192.168.1.0/24", ""),
+    )?;
+    assert!(run(&db, &diff).is_err());
+
+    // wg shows the address the endpoint of the server resolved to
+    let laptop_live = format!(
+        "[Interface]\nPrivateKey = {}\n\n[Peer]\nPublicKey = {}\nPresharedKey = {}\n\
+         Endpoint = 192.0.2.10:51820\nAllowedIPs = 10.1.0.1/32, fd00:1::1/128\n",
+        private_key(2),
+        public_key(1),
+        private_key(9)
+    );
+    std::fs::write(&live, &laptop_live)?;
+    let diff = format!("peer diff office laptop --live {}", live.display());
+    assert!(run(&db, &diff)?);
+    std::fs::write(&live, laptop_live.replace(":51820", ":51821"))?;
+    assert!(run(&db, &diff).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_export_networkd() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    let out = dir.path().join("export");
+    assert!(run(
+        &db,
+        &format!("vpn export office --format networkd -o {}", out.display())
+    )?);
+    let netdev = std::fs::read_to_string(out.join("server").join("wg0.netdev"))?;
+    assert!(netdev.contains("PrivateKeyFile=/etc/systemd/network/wg0.key"));
+    assert!(netdev.contains(&format!("PublicKey={}", public_key(2))));
+    assert!(netdev.contains("AllowedIPs=192.168.1.0/24"));
+    let network = std::fs::read_to_string(out.join("server").join("wg0.network"))?;
+    assert!(network.contains("Address=10.1.0.1/24"));
+    assert!(network.contains("Destination=192.168.1.0/24"));
+    let key = std::fs::read_to_string(out.join("server").join("wg0.key"))?;
+    assert_eq!(key.trim(), private_key(1));
+    Ok(())
+}
+
+#[test]
+fn test_export_networkmanager() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    assert!(run(
+        &db,
+        "peer update office laptop --dns '10.1.0.1, fd00:1::1, office.lan'"
+    )?);
+    let out = dir.path().join("export");
+    assert!(run(
+        &db,
+        &format!(
+            "peer export office laptop --format networkmanager -o {}",
+            out.display()
+        )
+    )?);
+    let keyfile = std::fs::read_to_string(out.join("office.nmconnection"))?;
+    assert!(keyfile.contains("type=wireguard\ninterface-name=wg0\n"));
+    assert!(keyfile.contains(&format!("private-key={}\n", private_key(2))));
+    assert!(keyfile.contains(&format!("[wireguard-peer.{}]", public_key(1))));
+    assert!(keyfile.contains("endpoint=vpn.example.com:51820\n"));
+    assert!(keyfile.contains(&format!("preshared-key={}\n", private_key(9))));
+    assert!(keyfile.contains("[ipv4]\nmethod=manual\naddress1=10.1.0.2/24\n"));
+    assert!(keyfile.contains("dns=10.1.0.1;\ndns-search=office.lan;\n"));
+    assert!(keyfile.contains("dns=fd00:1::1;\n"));
+
+    // a profile without the private key could never connect
+    run(&db, "peer add office phone")?;
+    assert!(run(
+        &db,
+        &format!("peer update office phone --pubkey {}", public_key(5))
+    )
+    .is_ok());
+    assert!(run(
+        &db,
+        &format!(
+            "peer export office phone --format networkmanager -o {}",
+            out.display()
+        )
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_export_mikrotik() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    // names end up in the comments of the script
+    let conn = db.connect()?;
+    vpnutils::api::add_peer(&conn, "office", "evil\"$x?\u{7}\nname", &Default::default())?;
+    let out = dir.path().join("export");
+    assert!(run(
+        &db,
+        &format!(
+            "peer export office server --format mikrotik -o {}",
+            out.display()
+        )
+    )?);
+    let script = std::fs::read_to_string(out.join("wg0.rsc"))?;
+    assert!(script.contains(&format!(
+        "/interface wireguard add name=\"wg0\" private-key=\"{}\" listen-port=51820 \
+         comment=\"office/server\"\n",
+        private_key(1)
+    )));
+    assert!(script.contains(r#"comment="evil\"\$x\?\07\nname""#));
+    // every line is a command, nothing leaked out of the quotes
+    assert!(script.lines().all(|l| l.starts_with('/')));
+    assert!(script.contains(&format!(
+        "preshared-key=\"{}\" allowed-address=10.1.0.2/32,192.168.1.0/24,fd00:1::2/128 \
+         comment=\"laptop\"\n",
+        private_key(9)
+    )));
+    assert!(script.contains("/ip route add dst-address=192.168.1.0/24 gateway=\"wg0\""));
+
+    assert!(run(
+        &db,
+        &format!(
+            "peer export office laptop --format mikrotik -o {}",
+            out.display()
+        )
+    )?);
+    let script = std::fs::read_to_string(out.join("wg0.rsc"))?;
+    assert!(script.contains("endpoint-address=\"vpn.example.com\" endpoint-port=51820"));
+    Ok(())
+}
+
+#[test]
+fn test_export_openwrt() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    let conn = db.connect()?;
+    for name in ["o'neil", "two\nlines"] {
+        vpnutils::api::add_peer(&conn, "office", name, &Default::default())?;
+    }
+    let out = dir.path().join("export");
+    assert!(run(
+        &db,
+        &format!(
+            "peer export office server --format openwrt-uci -o {}",
+            out.display()
+        )
+    )?);
+    let uci = std::fs::read_to_string(out.join("wg0.uci.sh"))?;
+    assert!(uci.starts_with(
+        "uci -q delete network.wg0\nuci set network.wg0=interface\n\
+         uci set network.wg0.proto='wireguard'\n"
+    ));
+    assert!(uci.contains(&format!(
+        "uci set network.wg0.private_key='{}'\n",
+        private_key(1)
+    )));
+    assert!(uci.contains("uci set network.wg0_laptop=wireguard_wg0\n"));
+    assert!(uci.contains(&format!(
+        "uci set network.wg0_laptop.preshared_key='{}'\n",
+        private_key(9)
+    )));
+    assert!(uci.contains("uci add_list network.wg0_laptop.allowed_ips='192.168.1.0/24'\n"));
+    assert!(uci.contains("uci set network.wg0_laptop.route_allowed_ips='1'\n"));
+    assert!(uci.contains("uci set network.wg0_o_neil.description='o'\\''neil'\n"));
+    assert!(uci.contains("uci set network.wg0_two_lines.description='two lines'\n"));
+    assert!(uci.ends_with("uci commit network\n"));
+
+    assert!(run(
+        &db,
+        &format!(
+            "peer export office laptop --format openwrt-config -o {}",
+            out.display()
+        )
+    )?);
+    let config = std::fs::read_to_string(out.join("network.wg0"))?;
+    assert!(config.contains("config wireguard_wg0 'wg0_server'\n"));
+    assert!(config
+        .contains("\toption endpoint_host 'vpn.example.com'\n\toption endpoint_port '51820'\n"));
+    assert!(config.contains("\toption description 'two lines'\n"));
+    Ok(())
+}
+
+#[test]
+fn test_peer_server() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    let out = dir.path().join("export");
+    run(&db, &format!("vpn export office -o {}", out.display()))?;
+    let server_conf = std::fs::read_to_string(out.join("server").join("wg0.conf"))?;
+    assert!(server_conf.contains("ListenPort = 51820"));
+    assert!(!server_conf.contains("Endpoint"));
+    let laptop_conf = std::fs::read_to_string(out.join("laptop").join("wg0.conf"))?;
+    assert!(!laptop_conf.contains("ListenPort"));
+    assert!(laptop_conf.contains("Endpoint = vpn.example.com:51820"));
+
+    // ipv6 endpoints need brackets
+    assert!(run(&db, "peer server office server -e 2001:db8::1:51820").is_err());
+    assert!(run(&db, "peer server office server -e vpn.example.com").is_err());
+    assert!(run(&db, "peer server office server --unset -l 51820").is_err());
+    assert!(run(
+        &db,
+        "peer server office server -e [2001:db8::1]:443 -k 25"
+    )?);
+    run(&db, &format!("vpn export office -o {}", out.display()))?;
+    let laptop_conf = std::fs::read_to_string(out.join("laptop").join("wg0.conf"))?;
+    assert!(laptop_conf.contains("Endpoint = [2001:db8::1]:443"));
+    assert!(laptop_conf.contains("PersistentKeepalive = 25"));
+    run(
+        &db,
+        &format!(
+            "peer export office laptop -f template -t wg-quick.conf -o {}",
+            out.display()
+        ),
+    )?;
+    assert_eq!(
+        std::fs::read_to_string(out.join("wg-quick.conf"))?,
+        laptop_conf
+    );
+
+    assert!(run(&db, "peer server office server --unset")?);
+    run(&db, &format!("vpn export office -o {}", out.display()))?;
+    let server_conf = std::fs::read_to_string(out.join("server").join("wg0.conf"))?;
+    assert!(!server_conf.contains("ListenPort"));
+    Ok(())
+}
+
+#[test]
+fn test_interface_settings() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    assert!(run(&db, "vpn settings office --mtu 1000").is_err());
+    assert!(run(&db, "vpn settings office --fwmark 0").is_err());
+    assert!(run(
+        &db,
+        "vpn settings office --mtu 1420 --fwmark 0x10 --post-up 'ip rule add fwmark 16 table 100' \
+         --post-up 'echo up'"
+    )?);
+    // the peer overrides the VPN
+    assert!(run(
+        &db,
+        "peer settings office laptop --mtu 1380 --table 100"
+    )?);
+    let out = dir.path().join("export");
+    run(&db, &format!("vpn export office -o {}", out.display()))?;
+    let laptop_conf = std::fs::read_to_string(out.join("laptop").join("wg0.conf"))?;
+    assert!(laptop_conf.contains(
+        "FwMark = 16\nMTU = 1380\nTable = 100\n\
+         PostUp = ip rule add fwmark 16 table 100\nPostUp = echo up\n"
+    ));
+    run(
+        &db,
+        &format!(
+            "peer export office laptop -f template -t wg-quick.conf -o {}",
+            out.display()
+        ),
+    )?;
+    assert_eq!(
+        std::fs::read_to_string(out.join("wg-quick.conf"))?,
+        laptop_conf
+    );
+    // formats without hooks refuse to drop them
+    assert!(run(&db, "peer export office laptop -f networkd").is_err());
+    assert!(run(&db, "vpn settings office --unset post-up")?);
+    run(
+        &db,
+        &format!("peer export office laptop -f networkd -o {}", out.display()),
+    )?;
+    let netdev = std::fs::read_to_string(out.join("wg0.netdev"))?;
+    assert!(netdev.contains("MTUBytes=1380"));
+    assert!(netdev.contains("FirewallMark=16"));
+    assert!(run(&db, "peer settings office server --table 200")?);
+    run(
+        &db,
+        &format!("peer export office server -f networkd -o {}", out.display()),
+    )?;
+    let network = std::fs::read_to_string(out.join("wg0.network"))?;
+    assert!(network.contains("Destination=192.168.1.0/24\nTable=200"));
+
+    assert!(run(
+        &db,
+        "peer settings office laptop --unset mtu --unset table"
+    )?);
+    run(&db, &format!("vpn export office -o {}", out.display()))?;
+    let laptop_conf = std::fs::read_to_string(out.join("laptop").join("wg0.conf"))?;
+    assert!(laptop_conf.contains("MTU = 1420"));
+    assert!(!laptop_conf.contains("Table"));
+    Ok(())
+}
+
+#[test]
+fn test_full_tunnel() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    let export = |db: &vpnutils::Database| -> Result<String> {
+        let out = dir.path().join("export");
+        run(db, &format!("vpn export office -o {}", out.display()))?;
+        Ok(std::fs::read_to_string(
+            out.join("laptop").join("wg0.conf"),
+        )?)
+    };
+    assert!(export(&db)?.contains("AllowedIPs = 10.1.0.1/32, fd00:1::1/128\n"));
+    assert!(run(&db, "peer tunnel office laptop split --exclude-lan").is_err());
+    assert!(run(&db, "peer tunnel office laptop full")?);
+    assert!(export(&db)?.contains("AllowedIPs = 0.0.0.0/0, ::/0\n"));
+    // the VPN addresses are kept, the rest of 10.0.0.0/8 is not
+    assert!(run(&db, "peer tunnel office laptop full --exclude-lan")?);
+    assert!(export(&db)?.contains(
+        "AllowedIPs = 0.0.0.0/5, 8.0.0.0/7, 10.1.0.1/32, 11.0.0.0/8, 12.0.0.0/6, 16.0.0.0/4, \
+         32.0.0.0/3, 64.0.0.0/2, 128.0.0.0/3, 160.0.0.0/5, 168.0.0.0/6, 172.0.0.0/12, \
+         172.32.0.0/11, 172.64.0.0/10, 172.128.0.0/9, 173.0.0.0/8, 174.0.0.0/7, 176.0.0.0/4, \
+         192.0.0.0/9, 192.128.0.0/11, 192.160.0.0/13, 192.169.0.0/16, 192.170.0.0/15, \
+         192.172.0.0/14, 192.176.0.0/12, 192.192.0.0/10, 193.0.0.0/8, 194.0.0.0/7, \
+         196.0.0.0/6, 200.0.0.0/5, 208.0.0.0/4, 224.0.0.0/3, ::/0\n"
+    ));
+    let out = dir.path().join("networkd");
+    assert!(run(
+        &db,
+        &format!("peer export office laptop -f networkd -o {}", out.display())
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_firewall() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    let out = dir.path().join("firewall");
+    // the laptop has no listen port
+    assert!(run(&db, "peer firewall office laptop").is_err());
+    run(&db, "peer tunnel office laptop full")?;
+    run(&db, "peer isolate office laptop")?;
+    assert!(run(
+        &db,
+        &format!("peer firewall office server -o {}", out.display())
+    )?);
+    let nft = std::fs::read_to_string(out.join("wg0.nft"))?;
+    assert!(nft.contains("\t\tiifname \"wg0\" oifname \"wg0\" ip saddr 10.1.0.2 drop\n"));
+    assert!(nft.contains("\t\tiifname \"wg0\" ip daddr { 10.1.0.0/24, 192.168.1.0/24 } accept\n"));
+    assert!(nft.contains("\t\toifname != \"wg0\" ip6 saddr fd00:1::2 masquerade\n"));
+    assert!(run(
+        &db,
+        &format!(
+            "peer firewall office server -f iptables -o {}",
+            out.display()
+        )
+    )?);
+    let rules = std::fs::read_to_string(out.join("wg0.rules.v4"))?;
+    assert!(rules.contains(
+        "*nat\n:vpnutils-wg0 - [0:0]\n\
+         -A vpnutils-wg0 ! -o wg0 -s 10.1.0.2 -j MASQUERADE\n\
+         -I POSTROUTING -j vpnutils-wg0\nCOMMIT\n"
+    ));
+
+    assert!(run(&db, "peer firewall office server -f iptables --hooks")?);
+    let export = dir.path().join("export");
+    run(&db, &format!("vpn export office -o {}", export.display()))?;
+    let conf = std::fs::read_to_string(export.join("server").join("wg0.conf"))?;
+    assert!(conf.contains("PostUp = iptables -t filter -N vpnutils-wg0\n"));
+    assert!(conf.contains("PostDown = ip6tables -t nat -X vpnutils-wg0\n"));
+    assert!(run(&db, "peer firewall office server --unset")?);
+    run(&db, &format!("vpn export office -o {}", export.display()))?;
+    let conf = std::fs::read_to_string(export.join("server").join("wg0.conf"))?;
+    assert!(!conf.contains("PostUp"));
+    Ok(())
+}
+
+#[test]
+fn test_acl() -> Result<()> {
+    use diesel::RunQueryDsl;
+    let dir = tempfile::tempdir()?;
+    let db = database_with_vpn(&dir)?;
+    let (server, laptop) = write_configs(dir.path())?;
+    let nas = dir.path().join("nas.conf");
+    std::fs::write(
+        &nas,
+        format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 10.1.0.3/24\n",
+            private_key(3)
+        ),
+    )?;
+    run(
+        &db,
+        &format!(
+            "vpn import-conf office {} {} {}",
+            server.display(),
+            laptop.display(),
+            nas.display()
+        ),
+    )?;
+    let conn = db.connect()?;
+    diesel::sql_query(
+        "INSERT INTO vpns(name, network_name, address_v4, address_v6) \
+         VALUES ('lab', 'home', '10.2.0.0/24', 'fd00:2::/64')",
+    )
+    .execute(&conn)?;
+    diesel::sql_query("INSERT INTO peer_tags VALUES ('office', 'laptop', 'admins')")
+        .execute(&conn)?;
+
+    assert!(run(
+        &db,
+        "acl add office allow --from laptop --to nas -p tcp --port 445"
+    )?);
+    assert!(run(&db, "acl add office deny --from laptop")?);
+    assert!(run(&db, "acl add office allow --from-tag admins -p icmp")?);
+    assert!(run(&db, "acl add office allow --from laptop --to ghost").is_err());
+    // peers of another VPN
+    assert!(run(&db, "acl add lab allow --from laptop").is_err());
+    assert!(run(&db, "acl add office allow -p icmp --port 7").is_err());
+    assert!(run(&db, "acl update 3 --port 22").is_err());
+
+    let out = dir.path().join("firewall");
+    run(
+        &db,
+        &format!("peer firewall office server -o {}", out.display()),
+    )?;
+    let nft = std::fs::read_to_string(out.join("wg0.nft"))?;
+    assert!(nft.contains(
+        "\t\tiifname \"wg0\" oifname \"wg0\" ip saddr 10.1.0.2 ip daddr 10.1.0.3 \
+         tcp dport 445 accept comment \"acl 1\"\n\
+         \t\tiifname \"wg0\" oifname \"wg0\" ip6 saddr fd00:1::2 ip6 daddr fd00:1::3 \
+         tcp dport 445 accept comment \"acl 1\"\n\
+         \t\tiifname \"wg0\" oifname \"wg0\" ip saddr 10.1.0.2 drop comment \"acl 2\"\n"
+    ));
+    assert!(nft.contains("ip6 saddr fd00:1::2 meta l4proto ipv6-icmp accept comment \"acl 3\""));
+
+    assert!(run(&db, "acl update 1 --port 22 -a deny")?);
+    assert!(run(&db, "acl remove 2")?);
+    assert!(run(&db, "acl remove 2").is_err());
+    run(
+        &db,
+        &format!(
+            "peer firewall office server -f iptables -o {}",
+            out.display()
+        ),
+    )?;
+    let rules = std::fs::read_to_string(out.join("wg0.rules.v4"))?;
+    assert!(rules.contains(
+        "-A vpnutils-wg0 -i wg0 -o wg0 -s 10.1.0.2 -d 10.1.0.3 -p tcp --dport 22 \
+         -m comment --comment \"acl 1\" -j DROP\n\
+         -A vpnutils-wg0 -i wg0 -o wg0 -s 10.1.0.2 -p icmp -m comment --comment \"acl 3\" -j ACCEPT\n"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_peer_qr() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    for name in ["qr.png", "qr.svg"] {
+        let path = dir.path().join(name);
+        assert!(run(
+            &db,
+            &format!("peer qr office laptop -o {}", path.display())
+        )?);
+        let metadata = std::fs::metadata(&path)?;
+        assert!(metadata.len() > 0);
+        // it holds the private key of the peer
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+    }
+    assert!(image::open(dir.path().join("qr.png")).is_ok());
+    assert!(run(&db, "peer qr office laptop -o qr.gif").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_export_template() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    let out = dir.path().join("out");
+    // the bundled template renders the same as the builtin exporter
+    for args in ["-f wg-quick", "-f template -t wg-quick.conf"] {
+        run(
+            &db,
+            &format!("peer export office laptop {} -o {}", args, out.display()),
+        )?;
+    }
+    assert_eq!(
+        std::fs::read_to_string(out.join("wg0.conf"))?,
+        std::fs::read_to_string(out.join("wg-quick.conf"))?
+    );
+
+    let template = dir.path().join("gateway.txt.tera");
+    std::fs::write(
+        &template,
+        "{{ network.name }}/{{ vpn.name }}: gateway {{ vpn.address_v4 | cidr_host(n=1) }}\n\
+         {% for r in remotes %}{{ r.peer.name }} {{ r.allowed_ips | join(sep=\",\") }}\n{% endfor %}",
+    )?;
+    run(
+        &db,
+        &format!(
+            "peer export office laptop -f template -t {} -o {}",
+            template.display(),
+            out.display()
+        ),
+    )?;
+    assert_eq!(
+        std::fs::read_to_string(out.join("gateway.txt"))?,
+        "home/office: gateway 10.1.0.1\nserver 10.1.0.1/32,fd00:1::1/128\n"
+    );
+
+    // templates only see the private key of the peer they render
+    std::fs::write(&template, "{{ remotes[0].peer.public_key }}")?;
+    assert!(run(
+        &db,
+        &format!(
+            "peer export office laptop -f template -t {}",
+            template.display()
+        ),
+    )?);
+    for secret in ["remotes[0].peer.private_key", "peer.private_key"] {
+        std::fs::write(&template, format!("{{{{ {} }}}}", secret))?;
+        assert!(run(
+            &db,
+            &format!(
+                "peer export office laptop -f template -t {}",
+                template.display()
+            ),
+        )
+        .is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_topology() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = database_with_vpn(&dir)?;
+    let (server, laptop) = write_configs(dir.path())?;
+    let phone = dir.path().join("phone.conf");
+    std::fs::write(
+        &phone,
+        format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 10.1.0.3/24\n",
+            private_key(3)
+        ),
+    )?;
+    run(
+        &db,
+        &format!(
+            "vpn import-conf office {} {} {}",
+            server.display(),
+            laptop.display(),
+            phone.display()
+        ),
+    )?;
+    let out = dir.path().join("out");
+    let export = |peer: &str| -> Result<String> {
+        run(
+            &db,
+            &format!("peer export office {} -o {}", peer, out.display()),
+        )?;
+        Ok(std::fs::read_to_string(out.join("wg0.conf"))?)
+    };
+    // mesh by default
+    assert_eq!(export("phone")?.matches("[Peer]").count(), 2);
+
+    run(&db, "vpn topology office hub")?;
+    // no hubs yet
+    assert!(export("phone").is_err());
+    run(&db, "peer hub office server")?;
+    let phone_conf = export("phone")?;
+    assert_eq!(phone_conf.matches("[Peer]").count(), 1);
+    assert!(phone_conf.contains(
+        "AllowedIPs = 10.1.0.1/32, fd00:1::1/128, 10.1.0.0/24, fd00:1::/64, 192.168.1.0/24"
+    ));
+    assert_eq!(export("server")?.matches("[Peer]").count(), 2);
+
+    run(&db, "vpn topology office custom")?;
+    run(&db, "peer link office phone laptop")?;
+    // links are not directed
+    assert!(run(&db, "peer link office laptop phone").is_err());
+    let laptop_conf = export("laptop")?;
+    assert_eq!(laptop_conf.matches("[Peer]").count(), 1);
+    assert!(laptop_conf.contains("# Name = phone"));
+    run(&db, "peer unlink office laptop phone")?;
+    assert_eq!(export("laptop")?.matches("[Peer]").count(), 0);
+
+    assert_eq!("hub".parse(), Ok(vpnutils::Topology::Hub));
+    let err = "star".parse::<vpnutils::Topology>().unwrap_err();
+    assert_eq!(err.to_string(), "invalid topology `star`");
+    Ok(())
+}
+
+#[test]
+fn test_peer_tags() -> Result<()> {
+    use diesel::RunQueryDsl;
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    assert!(run(&db, "peer tag add office laptop contractors remote")?);
+    assert!(run(&db, "peer tag add office server remote")?);
+    assert!(run(&db, "peer tag add office ghost remote").is_err());
+    assert!(run(&db, "peer tag remove office server contractors").is_err());
+    assert!(run(&db, "peer list office --tag remote --tag contractors")?);
+    // the tag selector only changes the status and dns
+    assert!(run(&db, "peer update office --tag contractors -n other").is_err());
+    assert!(run(&db, "peer update office --tag nobody --status disabled").is_err());
+    assert!(run(
+        &db,
+        "peer update office --tag contractors --status disabled"
+    )?);
+
+    let conn = db.connect()?;
+    let disabled = diesel::sql_query("SELECT name FROM peers WHERE status = 'disabled'")
+        .load::<PeerName>(&conn)?;
+    assert_eq!(disabled.len(), 1);
+    assert_eq!(disabled[0].name, "laptop");
+
+    let out = dir.path().join("export");
+    run(
+        &db,
+        &format!("vpn export office --tag remote -o {}", out.display()),
+    )?;
+    assert!(out.join("server").exists());
+    assert!(!out.join("laptop").exists());
+
+    // renaming a peer keeps its tags
+    assert!(run(&db, "peer update office laptop -n tablet -4 10.1.0.9")?);
+    assert!(run(&db, "peer update office server -4 10.1.0.9").is_err());
+    assert!(run(
+        &db,
+        "peer update office --tag contractors --status active"
+    )?);
+    assert!(run(&db, "peer tag remove office tablet contractors")?);
+    Ok(())
+}
+
+#[test]
+fn test_peer_expiry() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    assert!(run(&db, "peer update office laptop --expires 2026-02-30").is_err());
+    assert!(run(&db, "peer update office laptop --ttl 90").is_err());
+    assert!(run(&db, "peer update office laptop --ttl 90d --no-expiry").is_err());
+    assert!(run(&db, "peer update office server --ttl 90d")?);
+    assert!(run(&db, "peer update office laptop --expires 2020-01-01")?);
+    assert!(run(&db, "peer add office guest --expires 2020-01-02 --ttl 1d").is_err());
+    assert!(run(&db, "peer add office guest --expires 2020-01-02")?);
+    assert!(run(&db, "peer add office visitor --ttl 30d")?);
+
+    let conn = db.connect()?;
+    let expired = vpnutils::expired_peers(&conn)?;
+    assert_eq!(expired.len(), 2);
+    assert_eq!(expired[0].name, "guest");
+    assert_eq!(expired[0].expires_at, "2020-01-02 00:00:00");
+    assert_eq!(expired[1].name, "laptop");
+    assert_eq!(expired[1].expires_at, "2020-01-01 00:00:00");
+    let visitor = vpnutils::api::get_peer(&conn, "office", "visitor")?;
+    assert!(visitor.expires_at.is_some());
+
+    // still active, but left out of the export
+    let out = dir.path().join("export");
+    run(&db, &format!("vpn export office -o {}", out.display()))?;
+    assert!(!out.join("laptop").exists());
+    let server_conf = std::fs::read_to_string(out.join("server").join("wg0.conf"))?;
+    assert!(!server_conf.contains(&public_key(2)));
+
+    assert_eq!(vpnutils::disable_peers(&conn, &expired)?, 2);
+    assert!(vpnutils::expired_peers(&conn)?.is_empty());
+    assert!(run(
+        &db,
+        "peer update office laptop --no-expiry --status active"
+    )?);
+    run(
+        &db,
+        &format!("peer export office server -o {}", out.display()),
+    )?;
+    let server_conf = std::fs::read_to_string(out.join("wg0.conf"))?;
+    assert!(server_conf.contains(&public_key(2)));
+    Ok(())
+}
+
+#[test]
+fn test_rotate_keys() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    assert!(run(&db, "peer rotate-keys office ghost").is_err());
+    assert!(run(&db, "peer rotate-keys office laptop --grace 7d")?);
+
+    let out = dir.path().join("export");
+    run(
+        &db,
+        &format!("peer export office server -o {}", out.display()),
+    )?;
+    let server_conf = std::fs::read_to_string(out.join("wg0.conf"))?;
+    assert!(!server_conf.contains(&public_key(2)));
+    assert_eq!(server_conf.matches("[Peer]").count(), 1);
+    let conn = db.connect()?;
+    let new_key = vpnutils::api::get_peer(&conn, "office", "laptop")?.public_key;
+    // the laptop keeps its AllowedIPs with the old key until it confirms the new one
+    let export_server = || -> Result<String> {
+        run(
+            &db,
+            &format!(
+                "peer export office server --retired-keys -o {}",
+                out.display()
+            ),
+        )?;
+        Ok(std::fs::read_to_string(out.join("wg0.conf"))?)
+    };
+    let server_conf = export_server()?;
+    assert_eq!(server_conf.matches("[Peer]").count(), 1);
+    assert!(server_conf.contains(&format!("PublicKey = {}", public_key(2))));
+    assert!(!server_conf.contains(&new_key));
+    assert!(server_conf.contains("AllowedIPs = 10.1.0.2/32"));
+    // still the first key after a second rotation
+    assert!(run(&db, "peer rotate-keys office laptop --grace 7d")?);
+    let server_conf = export_server()?;
+    assert!(server_conf.contains(&format!("PublicKey = {}", public_key(2))));
+    let new_key = vpnutils::api::get_peer(&conn, "office", "laptop")?.public_key;
+    assert!(run(&db, "peer confirm-keys office laptop")?);
+    assert!(run(&db, "peer confirm-keys office laptop").is_err());
+    let server_conf = export_server()?;
+    assert_eq!(server_conf.matches("[Peer]").count(), 1);
+    assert!(server_conf.contains(&format!("PublicKey = {}", new_key)));
+    assert!(!server_conf.contains(&public_key(2)));
+
+    // without a grace period the old keys are not exported
+    assert!(run(&db, "vpn rotate-keys office")?);
+    run(
+        &db,
+        &format!("vpn export office --retired-keys -o {}", out.display()),
+    )?;
+    let laptop_conf = std::fs::read_to_string(out.join("laptop").join("wg0.conf"))?;
+    assert_eq!(laptop_conf.matches("[Peer]").count(), 1);
+    assert!(!laptop_conf.contains(&public_key(1)));
+    assert!(!laptop_conf.contains(&private_key(2)));
+    assert!(!laptop_conf.contains(&private_key(9)));
+    let server_conf = std::fs::read_to_string(out.join("server").join("wg0.conf"))?;
+    // the confirmation ended the grace period of the first laptop key
+    assert_eq!(server_conf.matches("[Peer]").count(), 1);
+    let new_key = vpnutils::api::get_peer(&conn, "office", "laptop")?.public_key;
+    assert!(server_conf.contains(&format!("PublicKey = {}", new_key)));
+    Ok(())
+}
+
+#[test]
+fn test_audit_keys() -> Result<()> {
+    use diesel::RunQueryDsl;
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    // imported keys are of unknown age, only a warning
+    assert!(run(&db, "audit keys")?);
+    assert!(run(&db, "audit keys office --strict").is_err());
+    assert!(run(&db, "audit keys --max-age 180").is_err());
+    assert!(run(&db, "vpn rotate-keys office")?);
+    assert!(run(&db, "audit keys office --strict --max-age 1d")?);
+
+    let conn = db.connect()?;
+    diesel::sql_query(
+        "UPDATE peers SET key_created_at = '2020-01-01 00:00:00' WHERE name = 'server'",
+    )
+    .execute(&conn)?;
+    assert!(run(&db, "audit keys office").is_err());
+    assert!(run(&db, "audit keys office --max-age 5000w")?);
+
+    // the laptop keeps its private key, but announces the server public key
+    diesel::sql_query(
+        "UPDATE peers SET pubkey = (SELECT pubkey FROM peers WHERE name = 'server') \
+         WHERE name = 'laptop'",
+    )
+    .execute(&conn)?;
+    assert!(run(&db, "audit keys office --max-age 5000w").is_err());
+    assert!(run(&db, "peer rotate-keys office laptop")?);
+    assert!(run(&db, "audit keys office --max-age 5000w")?);
+
+    diesel::sql_query(format!(
+        "UPDATE peers SET pubkey = '{}', privkey = NULL WHERE name = 'laptop'",
+        base64::encode([0u8; 32])
+    ))
+    .execute(&conn)?;
+    assert!(run(&db, "audit keys office --max-age 5000w").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_check() -> Result<()> {
+    use diesel::RunQueryDsl;
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    assert!(run(&db, "check")?);
+    // a preshared key of a disabled peer is only a warning
+    assert!(run(&db, "peer update office laptop --status disabled")?);
+    assert!(run(&db, "check")?);
+    assert!(run(&db, "peer update office laptop --status active")?);
+
+    let conn = db.connect()?;
+    // each change breaks one rule, and its pair undoes it
+    let changes = [
+        (
+            "INSERT INTO vpns(name, network_name, address_v4, address_v6) \
+             VALUES ('lab', 'home', '10.1.0.0/16', 'fd00:2::/64')",
+            "DELETE FROM vpns WHERE name = 'lab'",
+        ),
+        (
+            "INSERT INTO vpns(name, network_name, address_v4, address_v6) \
+             VALUES ('lab', 'home', '192.168.0.0/24', 'fd00:2::/64')",
+            "DELETE FROM vpns WHERE name = 'lab'",
+        ),
+        (
+            "UPDATE peers SET address_v4 = '10.1.0.300' WHERE name = 'server'",
+            "UPDATE peers SET address_v4 = '10.1.0.1' WHERE name = 'server'",
+        ),
+        (
+            "UPDATE peers SET endpoint_port = NULL WHERE name = 'server'",
+            "UPDATE peers SET endpoint_port = 51820 WHERE name = 'server'",
+        ),
+        (
+            "UPDATE peers SET address_v6 = 'fd00:1::1' WHERE name = 'laptop'",
+            "UPDATE peers SET address_v6 = 'fd00:1::2' WHERE name = 'laptop'",
+        ),
+    ];
+    for (change, undo) in changes.iter() {
+        diesel::sql_query(*change).execute(&conn)?;
+        assert!(run(&db, "check").is_err(), "{}", change);
+        diesel::sql_query(*undo).execute(&conn)?;
+        assert!(run(&db, "check")?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_invalid_address_rows() -> Result<()> {
+    use diesel::RunQueryDsl;
+    let dir = tempfile::tempdir()?;
+    let db = imported_vpn(&dir)?;
+    let conn = db.connect()?;
+    diesel::sql_query("UPDATE peers SET address_v4 = '10.1.0.300' WHERE name = 'laptop'")
+        .execute(&conn)?;
+    // the typed columns fail when loaded, check still reports the row
+    let err = run(&db, "peer list office").unwrap_err();
+    assert!(format!("{:#}", err).contains("10.1.0.300"), "{:#}", err);
+    let out = dir.path().join("server.conf");
+    assert!(run(&db, &format!("vpn export office -o {}", out.display())).is_err());
+    assert!(run(&db, "check").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_api() -> Result<()> {
+    use vpnutils::api;
+    let dir = tempfile::tempdir()?;
+    let db = vpnutils::Database::create(dir.path().join("database.db"), PASSWORD.to_string())?;
+    let conn = db.connect()?;
+    api::create_network(
+        &conn,
+        "home",
+        "10.0.0.0/8".parse()?,
+        "fd00::/8".parse()?,
+        None,
+        None,
+    )?;
+    assert!(matches!(
+        api::create_network(
+            &conn,
+            "lab",
+            "10.5.0.0/16".parse()?,
+            "fd01::/16".parse()?,
+            None,
+            None
+        ),
+        Err(api::CommandError::SubnetOverlap { .. })
+    ));
+
+    // subnets are assigned first fit
+    let office = api::add_vpn(&conn, "home", "office", None, None)?;
+    assert_eq!(office.address_v4.to_string(), "10.0.0.0/24");
+    assert_eq!(office.address_v6.to_string(), "fd00::/64");
+    api::add_vpn(&conn, "home", "lab", Some("10.0.2.0/24".parse()?), None)?;
+    let shop = api::add_vpn(&conn, "home", "shop", None, None)?;
+    assert_eq!(shop.address_v4.to_string(), "10.0.1.0/24");
+    assert!(matches!(
+        api::add_vpn(&conn, "home", "other", Some("10.0.1.128/25".parse()?), None),
+        Err(api::CommandError::SubnetOverlap { .. })
+    ));
+    assert!(matches!(
+        api::add_vpn(&conn, "nowhere", "other", None, None),
+        Err(api::CommandError::NetworkNotFound(_))
+    ));
+
+    let server = api::add_peer(&conn, "office", "server", &api::PeerOptions::default())?;
+    assert_eq!(server.address_v4.to_string(), "10.0.0.1");
+    assert!(server.key_created_at.is_some());
+    let laptop = api::add_peer(
+        &conn,
+        "office",
+        "laptop",
+        &api::PeerOptions {
+            private_key: Some(private_key(2)),
+            address_v4: Some("10.0.0.10".parse()?),
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(laptop.public_key, public_key(2));
+    assert_eq!(laptop.address_v6.to_string(), "fd00::2");
+    assert!(matches!(
+        api::add_peer(
+            &conn,
+            "office",
+            "phone",
+            &api::PeerOptions {
+                address_v4: Some("10.0.0.10".parse()?),
+                ..Default::default()
+            }
+        ),
+        Err(api::CommandError::AddressInUse { .. })
+    ));
+    assert!(matches!(
+        api::add_peer(
+            &conn,
+            "office",
+            "phone",
+            &api::PeerOptions {
+                address_v4: Some("10.0.1.10".parse()?),
+                ..Default::default()
+            }
+        ),
+        Err(api::CommandError::AddressOutOfRange { .. })
+    ));
+
+    let names: Vec<_> = api::list_peers(&conn, "office", &[])?
+        .into_iter()
+        .map(|p| p.name)
+        .collect();
+    assert_eq!(names, vec!["laptop", "server"]);
+    let config = api::render_peer_config(&conn, "office", "laptop")?;
+    assert!(
+        config.contains("Address = 10.0.0.10/24, fd00::2/64"),
+        "{}",
+        config
+    );
+    assert!(config.contains(&server.public_key), "{}", config);
+
+    // the subnets must still contain the peers and the VPNs
+    assert!(matches!(
+        api::update_vpn(
+            &conn,
+            "office",
+            api::VpnChanges {
+                address_v4: Some("10.0.3.0/24".parse()?),
+                ..Default::default()
+            }
+        ),
+        Err(api::CommandError::AddressOutOfRange { .. })
+    ));
+    assert!(matches!(
+        api::update_network(
+            &conn,
+            "home",
+            api::NetworkChanges {
+                address_v4: Some("10.1.0.0/16".parse()?),
+                ..Default::default()
+            }
+        ),
+        Err(api::CommandError::SubnetOutOfRange { .. })
+    ));
+    assert!(matches!(
+        api::remove_vpn(&conn, "office"),
+        Err(api::CommandError::VpnHasPeers(_))
+    ));
+    assert!(matches!(
+        api::remove_network(&conn, "home"),
+        Err(api::CommandError::NetworkHasVpns(_))
+    ));
+    assert!(matches!(
+        api::update_vpn(
+            &conn,
+            "lab",
+            api::VpnChanges {
+                name: Some(String::from("shop")),
+                ..Default::default()
+            }
+        ),
+        Err(api::CommandError::VpnExists(_))
+    ));
+    assert!(matches!(
+        api::add_peer(
+            &conn,
+            "office",
+            "phone",
+            &api::PeerOptions {
+                public_key: Some(public_key(2)),
+                ..Default::default()
+            }
+        ),
+        Err(api::CommandError::DuplicatePublicKey { .. })
+    ));
+
+    // tags and key rotations
+    let tags = vec![String::from("dept=eng"), String::from("laptops")];
+    api::add_peer_tags(&conn, "office", "laptop", &tags)?;
+    assert_eq!(api::list_peers(&conn, "office", &tags)?.len(), 1);
+    assert!(matches!(
+        api::add_peer_tags(&conn, "office", "laptop", &[String::from("two words")]),
+        Err(api::CommandError::InvalidTag(_))
+    ));
+    assert_eq!(api::remove_peer_tags(&conn, "office", "laptop", &tags)?, 2);
+    let rotated = api::rotate_peer_keys(&conn, "office", "laptop", 3600)?;
+    assert_ne!(rotated.public_key, public_key(2));
+    assert!(rotated.private_key.is_some());
+    assert_eq!(api::confirm_peer_keys(&conn, "office", "laptop")?, 1);
+    assert_eq!(api::confirm_peer_keys(&conn, "office", "laptop")?, 0);
+    assert!(matches!(
+        api::rotate_peer_keys(&conn, "office", "ghost", 0),
+        Err(api::CommandError::PeerNotFound { .. })
+    ));
+
+    // topologies, tunnels and access rules are typed
+    let office = api::set_vpn_topology(&conn, "office", vpnutils::Topology::Custom)?;
+    assert_eq!(office.topology, vpnutils::Topology::Custom);
+    api::link_peers(&conn, "office", "server", "laptop")?;
+    assert!(matches!(
+        api::link_peers(&conn, "office", "laptop", "server"),
+        Err(api::CommandError::AlreadyLinked { .. })
+    ));
+    api::unlink_peers(&conn, "office", "laptop", "server")?;
+    assert!(matches!(
+        api::set_peer_tunnel(&conn, "office", "laptop", vpnutils::TunnelMode::Split, true),
+        Err(api::CommandError::SplitTunnelExcludesLan)
+    ));
+    let laptop = api::set_peer_tunnel(&conn, "office", "laptop", vpnutils::TunnelMode::Full, true)?;
+    assert_eq!(laptop.tunnel, vpnutils::TunnelMode::Full);
+    let server = api::update_peer_server(&conn, "office", "server", Some(51820), None, None)?;
+    assert_eq!(server.listen_port, Some(51820));
+    let acl = api::add_acl(
+        &conn,
+        &api::NewAcl {
+            vpn: String::from("office"),
+            src_peer: Some(String::from("laptop")),
+            src_tag: None,
+            dst_peer: None,
+            dst_tag: None,
+            protocol: Some(vpnutils::AclProtocol::Tcp),
+            port: Some(22),
+            action: vpnutils::AclAction::Deny,
+        },
+    )?;
+    assert!(matches!(
+        api::update_acl(
+            &conn,
+            acl.id,
+            &api::AclChanges {
+                protocol: Some(Some(vpnutils::AclProtocol::Icmp)),
+                ..Default::default()
+            }
+        ),
+        Err(api::CommandError::PortWithoutProtocol)
+    ));
+    assert_eq!(api::list_acls(&conn, "office")?, vec![acl]);
+    assert!(api::exported_peers(&conn, "office", &[])?
+        .iter()
+        .all(|p| p.status == vpnutils::PeerStatus::Active));
+    let rotation = api::rotate_vpn_keys(&conn, "office", 0)?;
+    assert_eq!(rotation.peers.len(), 2);
+
+    // the commands wrap the same functions
+    assert!(run(&db, "peer remove office laptop")?);
+    assert!(run(&db, "peer add office laptop -4 10.0.0.20")?);
+    assert!(run(&db, "peer update office laptop -4 10.0.0.30")?);
+    let err = run(&db, "peer update office laptop -4 10.0.0.1").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<vpnutils::CommandError>(),
+        Some(vpnutils::CommandError::AddressInUse { .. })
+    ));
+    // errors of the other modules are CommandErrors too
+    let err = run(&db, "peer export nowhere laptop").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<vpnutils::CommandError>(),
+        Some(vpnutils::CommandError::VpnNotFound(_))
+    ));
+    assert!(run(&db, "vpn remove lab")?);
+    assert!(run(&db, "vpn update shop -n store -4 10.0.4.0/24")?);
+    assert_eq!(
+        api::get_vpn(&conn, "store")?.address_v4.to_string(),
+        "10.0.4.0/24"
+    );
+    assert!(run(
+        &db,
+        "network add lab -4 192.168.0.0/16 -6 2001:db8::/32"
+    )?);
+    assert!(run(&db, "network remove lab")?);
+    assert!(run(&db, "network list")?);
+    assert!(run(&db, "vpn list home")?);
+    assert_eq!(
+        api::get_peer(&conn, "office", "laptop")?
+            .address_v4
+            .to_string(),
+        "10.0.0.30"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_vpn_subnet_allocation() -> Result<()> {
+    use vpnutils::api;
+    let dir = tempfile::tempdir()?;
+    let db = vpnutils::Database::create(dir.path().join("database.db"), PASSWORD.to_string())?;
+    let conn = db.connect()?;
+    assert!(run(&db, "network add small -4 10.9.0.0/23 -6 fd09::/48")?);
+    let a = api::add_vpn(&conn, "small", "a", None, None)?;
+    let b = api::add_vpn(&conn, "small", "b", None, None)?;
+    assert_eq!(a.index_in_network, Some(0));
+    assert_eq!(b.index_in_network, Some(1));
+    assert_eq!(b.address_v4.to_string(), "10.9.1.0/24");
+    assert_eq!(b.address_v6.to_string(), "fd09:0:0:1::/64");
+    let err = api::add_vpn(&conn, "small", "c", None, None).unwrap_err();
+    assert!(matches!(
+        err,
+        api::CommandError::SubnetExhausted {
+            vpns: 2,
+            used: 512,
+            total: 512,
+            ..
+        }
+    ));
+    assert_eq!(
+        err.to_string(),
+        "No free /24 subnet left in 10.9.0.0/23 of network small: \
+         its 2 VPNs use 512 of its 512 addresses"
+    );
+
+    // the subnet and the index of a removed VPN are reused
+    api::remove_vpn(&conn, "a")?;
+    let c = api::add_vpn(&conn, "small", "c", None, None)?;
+    assert_eq!(c.index_in_network, Some(0));
+    assert_eq!(c.address_v4.to_string(), "10.9.0.0/24");
+    assert_eq!(c.address_v6.to_string(), "fd09::/64");
+
+    assert!(run(
+        &db,
+        "network update small -4 10.9.0.0/22 --vpn-prefix-v4 26"
+    )?);
+    let d = api::add_vpn(&conn, "small", "d", None, None)?;
+    assert_eq!(d.index_in_network, Some(2));
+    assert_eq!(d.address_v4.to_string(), "10.9.2.0/26");
+    assert!(matches!(
+        api::update_network(
+            &conn,
+            "small",
+            api::NetworkChanges {
+                vpn_prefix_v4: Some(21),
+                ..Default::default()
+            }
+        ),
+        Err(api::CommandError::InvalidPrefixLength { prefix_len: 21, .. })
+    ));
+    // the default /24 doesn't fit
+    assert!(matches!(
+        api::create_network(
+            &conn,
+            "tiny",
+            "10.8.0.0/25".parse()?,
+            "fd08::/48".parse()?,
+            None,
+            None
+        ),
+        Err(api::CommandError::InvalidPrefixLength { prefix_len: 24, .. })
+    ));
+    assert!(api::get_network(&conn, "tiny").is_err());
+
+    // used subnets are skipped as a whole, not one candidate at a time
+    api::create_network(
+        &conn,
+        "big",
+        "172.16.0.0/12".parse()?,
+        "fd80::/9".parse()?,
+        None,
+        None,
+    )?;
+    api::add_vpn(&conn, "big", "x", None, Some("fd80::/10".parse()?))?;
+    let y = api::add_vpn(&conn, "big", "y", None, None)?;
+    assert_eq!(y.address_v4.to_string(), "172.16.1.0/24");
+    assert_eq!(y.address_v6.to_string(), "fdc0::/64");
+    Ok(())
+}